@@ -1,12 +1,27 @@
 use std::{collections::HashSet, sync::Arc};
 
+use anyhow::Result;
 use rocket::{
-    http::Status,
     request::{FromRequest, Outcome},
     Request, State,
 };
 use rspotify::{clients::BaseClient, AuthCodeSpotify, Credentials, OAuth, Token};
 use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    error::{refuse, ApiError},
+    persistence::Store,
+};
+
+///Header the frontend uses to identify the guest making a request
+pub const GUEST_HEADER: &str = "X-DDJ-Guest";
+
+///Cookie set when a guest id is issued, for clients which don't send the header
+pub const GUEST_COOKIE: &str = "ddj_guest";
+
+///Header the session's host sends their host token in
+pub const HOST_HEADER: &str = "X-DDJ-Host";
 
 pub struct AuthenticationState {
     oauth: OAuth,
@@ -17,58 +32,320 @@ pub struct AuthenticationState {
 pub type ManagedAuthState = Arc<Mutex<Option<AuthenticationState>>>;
 
 impl AuthenticationState {
-    pub async fn new(client: AuthCodeSpotify) -> AuthenticationState {
-        Self {
-            oauth: client.oauth,
-            token: client.token.lock().await.unwrap().clone(),
-            creds: client.creds,
-        }
+    ///Rebuild the authentication state for a previously stored token
+    pub fn from_token(token: Token) -> Result<AuthenticationState> {
+        let creds = Credentials::from_env().ok_or_else(|| {
+            anyhow::Error::msg("no spotify app credentials found in the environment")
+        })?;
+        let oauth = OAuth::from_env(scopes()).ok_or_else(|| {
+            anyhow::Error::msg("no spotify oauth settings found in the environment")
+        })?;
+
+        Ok(Self {
+            oauth: oauth,
+            token: Some(token),
+            creds: creds,
+        })
     }
 
-    pub async fn client(&mut self) -> AuthCodeSpotify {
+    ///Client for the stored token, refreshing it first if it has expired
+    pub async fn client(&mut self) -> Result<AuthCodeSpotify> {
         let client = AuthCodeSpotify::new(self.creds.clone(), self.oauth.clone());
-        *client.token.lock().await.unwrap() = self.token.clone();
+        *client.token.lock().await.map_err(|_| token_lock_error())? = self.token.clone();
 
-        client.auto_reauth().await.unwrap();
+        client.auto_reauth().await?;
 
-        self.token = client.token.lock().await.unwrap().clone();
+        self.token = client
+            .token
+            .lock()
+            .await
+            .map_err(|_| token_lock_error())?
+            .clone();
 
-        client
+        Ok(client)
     }
 }
 
-pub struct SpotifyClient {
-    pub spotify: AuthCodeSpotify,
+fn token_lock_error() -> anyhow::Error {
+    anyhow::Error::msg("failed to lock the spotify token")
+}
+
+///The data store, for guards which look up who is making the request
+async fn store<'r>(request: &'r Request<'_>) -> Result<&'r State<Store>, ApiError> {
+    match request.guard::<&State<Store>>().await {
+        Outcome::Success(store) => Ok(store),
+        _ => Err(ApiError::Internal(anyhow::Error::msg(
+            "failed to find the data store",
+        ))),
+    }
+}
+
+///The session a request is for, read from the matched route's `<session_id>`
+/// parameter. None if the route doesn't take one.
+fn session_param(request: &Request<'_>) -> Option<Result<Uuid, uuid::Error>> {
+    let route = request.route()?;
+    let index = route
+        .uri
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .position(|segment| matches!(segment, "<session_id>" | "<_session_id>"))?;
+    let segment = request.uri().path().segments().get(index)?;
+    Some(Uuid::parse_str(segment))
+}
+
+///Anonymous identity of whoever is making a request, used to attribute queued tracks
+/// and votes. Guests belong to a single session and are refused by routes for any
+/// other session.
+pub struct Guest {
+    pub id: Uuid,
+    pub session_id: Uuid,
 }
 
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for SpotifyClient {
-    type Error = anyhow::Error;
+impl<'r> FromRequest<'r> for Guest {
+    type Error = ApiError;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let auth_state = request.guard::<&State<ManagedAuthState>>().await;
-        if !auth_state.is_success() {
-            return Outcome::Failure((
-                Status::InternalServerError,
-                anyhow::Error::msg("failed to find static spotify authentication state"),
-            ));
+        let guest_id = match request.headers().get_one(GUEST_HEADER) {
+            Some(header) => Some(header.to_owned()),
+            None => request
+                .cookies()
+                .get(GUEST_COOKIE)
+                .map(|cookie| cookie.value().to_owned()),
+        };
+        let guest_id = match guest_id.map(|id| Uuid::parse_str(&id)) {
+            Some(Ok(id)) => id,
+            Some(Err(_)) => {
+                return refuse(
+                    request,
+                    ApiError::Validation("guest id is not a valid uuid".to_owned()),
+                )
+            }
+            None => {
+                return refuse(
+                    request,
+                    ApiError::NotAuthenticated(
+                        "request did not identify a guest".to_owned(),
+                    ),
+                )
+            }
+        };
+
+        let store = match store(request).await {
+            Ok(store) => store,
+            Err(e) => return refuse(request, e),
+        };
+        let guest = match store.get_guest(guest_id).await {
+            Ok(Some(guest)) => guest,
+            Ok(None) => {
+                return refuse(
+                    request,
+                    ApiError::NotAuthenticated(format!("unknown guest {}", guest_id)),
+                )
+            }
+            Err(e) => return refuse(request, ApiError::Store(e)),
+        };
+
+        match session_param(request) {
+            Some(Ok(session_id)) if session_id != guest.session_id => {
+                return refuse(
+                    request,
+                    ApiError::Forbidden(format!(
+                        "guest {} does not belong to session {}",
+                        guest.id, session_id
+                    )),
+                )
+            }
+            Some(Err(_)) => {
+                return refuse(
+                    request,
+                    ApiError::Validation("session id is not a valid uuid".to_owned()),
+                )
+            }
+            _ => {}
         }
 
-        let mut maybe_spotify_auth = auth_state.unwrap().lock().await;
-        if maybe_spotify_auth.is_none() {
-            return Outcome::Failure((
-                Status::Unauthorized,
-                anyhow::Error::msg(
-                    "the admin for this session does not have valid credentials",
-                ),
-            ));
+        Outcome::Success(Guest {
+            id: guest.id,
+            session_id: guest.session_id,
+        })
+    }
+}
+
+///Whoever created the session, proven by the host token handed out with it. Only
+/// valid on routes under /session/<session_id>.
+pub struct Host {
+    pub session_id: Uuid,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Host {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let host_token = match request.headers().get_one(HOST_HEADER) {
+            Some(token) => token,
+            None => {
+                return refuse(
+                    request,
+                    ApiError::NotAuthenticated(
+                        "request did not include a host token".to_owned(),
+                    ),
+                )
+            }
+        };
+        let session_id = match session_param(request) {
+            Some(Ok(session_id)) => session_id,
+            _ => {
+                return refuse(
+                    request,
+                    ApiError::Validation("route is not for a single session".to_owned()),
+                )
+            }
+        };
+
+        let store = match store(request).await {
+            Ok(store) => store,
+            Err(e) => return refuse(request, e),
+        };
+        let session = match store.get_session(session_id).await {
+            Ok(Some(session)) => session,
+            Ok(None) => {
+                return refuse(
+                    request,
+                    ApiError::NotFound(format!("no session with id {}", session_id)),
+                )
+            }
+            Err(e) => return refuse(request, ApiError::Store(e)),
+        };
+
+        match session.host_token {
+            Some(expected) if expected == host_token => Outcome::Success(Host {
+                session_id: session_id,
+            }),
+            _ => refuse(
+                request,
+                ApiError::Forbidden(format!(
+                    "wrong host token for session {}",
+                    session_id
+                )),
+            ),
         }
+    }
+}
 
-        let spotify_auth = maybe_spotify_auth.as_mut().unwrap();
+///Something a member of a session may be allowed to do
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    ///Take back tracks the member queued themselves
+    RemoveOwnEntries,
+    ///Remove anyone's tracks from the queue, or clear it entirely
+    RemoveAnyEntry,
+    ///Move entries around the queue, overriding the votes
+    ArrangeQueue,
+    ///Start, pause, skip and otherwise control playback
+    ControlPlayback,
+    ///Change session settings such as the playback device
+    ChangeSettings,
+    ///Replace the session's Spotify credentials
+    Reauthenticate,
+}
 
-        let client = spotify_auth.client().await;
+impl Permission {
+    ///What the permission lets a member do, for error messages
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Permission::RemoveOwnEntries => "remove your tracks from the queue",
+            Permission::RemoveAnyEntry => "remove other guests' tracks from the queue",
+            Permission::ArrangeQueue => "rearrange the queue",
+            Permission::ControlPlayback => "control playback",
+            Permission::ChangeSettings => "change the session's settings",
+            Permission::Reauthenticate => "log the session in to Spotify",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Host,
+    Guest,
+}
+
+impl Role {
+    pub fn allows(&self, permission: Permission) -> bool {
+        match (self, permission) {
+            (Role::Host, _) => true,
+            (Role::Guest, Permission::RemoveOwnEntries) => true,
+            (Role::Guest, Permission::RemoveAnyEntry) => false,
+            (Role::Guest, Permission::ArrangeQueue) => false,
+            (Role::Guest, Permission::ControlPlayback) => false,
+            (Role::Guest, Permission::ChangeSettings) => false,
+            (Role::Guest, Permission::Reauthenticate) => false,
+        }
+    }
+}
 
-        Outcome::Success(SpotifyClient { spotify: client })
+///Anyone taking part in a session. Requests with a host token are from the host,
+/// anything else has to identify a guest.
+pub enum Member {
+    Host(Host),
+    Guest(Guest),
+}
+
+impl Member {
+    pub fn role(&self) -> Role {
+        match self {
+            Member::Host(_) => Role::Host,
+            Member::Guest(_) => Role::Guest,
+        }
+    }
+
+    pub fn session_id(&self) -> Uuid {
+        match self {
+            Member::Host(host) => host.session_id,
+            Member::Guest(guest) => guest.session_id,
+        }
+    }
+
+    ///The guest making the request, None for the host
+    pub fn guest_id(&self) -> Option<Uuid> {
+        match self {
+            Member::Host(_) => None,
+            Member::Guest(guest) => Some(guest.id),
+        }
+    }
+
+    ///Refuse with 403 Forbidden unless the member's role allows `permission`
+    pub fn require(&self, permission: Permission) -> Result<(), ApiError> {
+        if self.role().allows(permission) {
+            return Ok(());
+        }
+        Err(ApiError::Forbidden(format!(
+            "you are not allowed to {}",
+            permission.describe()
+        )))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Member {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if request.headers().get_one(HOST_HEADER).is_some() {
+            return match request.guard::<Host>().await {
+                Outcome::Success(host) => Outcome::Success(Member::Host(host)),
+                Outcome::Failure(failure) => Outcome::Failure(failure),
+                Outcome::Forward(forward) => Outcome::Forward(forward),
+            };
+        }
+
+        match request.guard::<Guest>().await {
+            Outcome::Success(guest) => Outcome::Success(Member::Guest(guest)),
+            Outcome::Failure(failure) => Outcome::Failure(failure),
+            Outcome::Forward(forward) => Outcome::Forward(forward),
+        }
     }
 }
 
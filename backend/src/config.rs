@@ -0,0 +1,568 @@
+//! Server configuration, read from a TOML file and overridden by environment variables.
+//! Loaded and checked once at startup, then handed to the store, the players and the
+//! routes.
+
+use std::{
+    env, fs, io,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::limits::QueueLimits;
+
+///File read when `DDJ_CONFIG` doesn't name one. It is fine for it not to exist.
+const DEFAULT_CONFIG_FILE: &str = "ddj.toml";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("could not read config file {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+
+    #[error("could not parse config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("{name} must be {expected}, got {value}")]
+    Env {
+        name: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+
+    #[error("invalid configuration:\n  {}", .0.join("\n  "))]
+    Invalid(Vec<String>),
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub spotify: SpotifyConfig,
+    pub player: PlayerConfig,
+    pub limits: LimitsConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: IpAddr,
+    pub port: u16,
+    ///Url clients reach the API at, handed to the frontend through /config.json. The
+    /// frontend uses the origin it loaded config.json from when this isn't set
+    pub public_url: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8090,
+            public_url: None,
+        }
+    }
+}
+
+///Which kind of store to use when no database url is given
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreKind {
+    Postgres,
+    Memory,
+}
+
+impl FromStr for StoreKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "postgres" => Ok(StoreKind::Postgres),
+            "memory" => Ok(StoreKind::Memory),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    ///Full database url, `postgres://`, `sqlite:` or `memory`. Everything below but the
+    /// pool size is ignored when it is set
+    pub url: Option<String>,
+    pub store: StoreKind,
+    pub host: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub name: String,
+    pub pool_size: u32,
+    ///Whether pending migrations are applied at startup, rather than refusing to start.
+    /// Off unless asked for, `backend migrate` applies them on demand
+    pub auto_migrate: bool,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: None,
+            store: StoreKind::Postgres,
+            host: None,
+            user: None,
+            password: None,
+            name: "ddj".to_owned(),
+            pool_size: 5,
+            auto_migrate: false,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    ///Url of the database to connect to, built from the host, user, password and name
+    /// unless one is set outright
+    pub fn connection_url(&self) -> String {
+        if let Some(url) = &self.url {
+            return url.clone();
+        }
+        match self.store {
+            StoreKind::Memory => "memory".to_owned(),
+            StoreKind::Postgres => format!(
+                "postgres://{}:{}@{}/{}",
+                self.user.as_deref().unwrap_or_default(),
+                self.password.as_deref().unwrap_or_default(),
+                self.host.as_deref().unwrap_or_default(),
+                self.name
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpotifyConfig {
+    ///Where Spotify sends hosts back to once they have logged in, the frontend's login
+    /// page
+    pub redirect_uri: String,
+}
+
+impl Default for SpotifyConfig {
+    fn default() -> Self {
+        SpotifyConfig {
+            redirect_uri: "http://localhost:8080#login".to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlayerConfig {
+    ///How long before the end of the current track the next one is handed to Spotify
+    pub queue_lead_time_secs: u64,
+    ///How often the cached playback state is refreshed from Spotify
+    pub playback_refresh_secs: u64,
+    ///Whether the player fills in with tracks of its own when the queue runs dry. Off
+    /// unless the host's deployment opts in
+    pub autofill: bool,
+}
+
+impl Default for PlayerConfig {
+    fn default() -> Self {
+        PlayerConfig {
+            queue_lead_time_secs: 10,
+            playback_refresh_secs: 5,
+            autofill: false,
+        }
+    }
+}
+
+impl PlayerConfig {
+    pub fn queue_lead_time(&self) -> Duration {
+        Duration::from_secs(self.queue_lead_time_secs)
+    }
+
+    pub fn playback_refresh(&self) -> Duration {
+        Duration::from_secs(self.playback_refresh_secs)
+    }
+}
+
+///Rules on what guests can add to a queue, see `QueueLimits`. 0 turns a limit off
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_queued_per_guest: u32,
+    pub max_adds_per_window: u32,
+    pub add_window_secs: u64,
+    pub reject_duplicates: bool,
+    pub replay_cooldown_mins: u64,
+    pub max_artist_streak: u32,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_queued_per_guest: 5,
+            max_adds_per_window: 10,
+            add_window_secs: 10 * 60,
+            reject_duplicates: true,
+            replay_cooldown_mins: 60,
+            max_artist_streak: 0,
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn queue_limits(&self) -> QueueLimits {
+        QueueLimits {
+            max_queued_per_guest: Some(self.max_queued_per_guest).filter(|max| *max > 0),
+            max_adds_per_window: Some(self.max_adds_per_window).filter(|max| *max > 0),
+            add_window: Duration::from_secs(self.add_window_secs),
+            reject_duplicates: self.reject_duplicates,
+            replay_cooldown: Some(Duration::from_secs(
+                self.replay_cooldown_mins.saturating_mul(60),
+            ))
+            .filter(|cooldown| !cooldown.is_zero()),
+            max_artist_streak: Some(self.max_artist_streak).filter(|max| *max > 0),
+        }
+    }
+}
+
+impl Config {
+    ///Read the file named by `DDJ_CONFIG`, or `ddj.toml` if there is one, apply the
+    /// environment's overrides and check the result
+    pub fn load() -> Result<Config, ConfigError> {
+        let mut config = match env::var_os("DDJ_CONFIG") {
+            Some(path) => Config::from_file(PathBuf::from(path))?,
+            None if PathBuf::from(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(PathBuf::from(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: PathBuf) -> Result<Config, ConfigError> {
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                return Err(ConfigError::Read {
+                    path: path,
+                    source: e,
+                })
+            }
+        };
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse {
+            path: path,
+            source: e,
+        })
+    }
+
+    ///Override settings with whichever of these variables are set:
+    ///
+    /// | variable                    | setting                        |
+    /// |-----------------------------|--------------------------------|
+    /// | `DDJ_ADDRESS`               | `server.address`               |
+    /// | `DDJ_PORT`                  | `server.port`                  |
+    /// | `DDJ_PUBLIC_URL`            | `server.public_url`            |
+    /// | `DDJ_DATABASE_URL`          | `database.url`                 |
+    /// | `DDJ_STORE`                 | `database.store`               |
+    /// | `POSTGRES_HOST`             | `database.host`                |
+    /// | `POSTGRES_USER`             | `database.user`                |
+    /// | `POSTGRES_PASSWORD`         | `database.password`            |
+    /// | `DDJ_DATABASE_NAME`         | `database.name`                |
+    /// | `DDJ_POOL_SIZE`             | `database.pool_size`           |
+    /// | `DDJ_AUTO_MIGRATE`          | `database.auto_migrate`        |
+    /// | `DDJ_REDIRECT_URI`          | `spotify.redirect_uri`         |
+    /// | `DDJ_QUEUE_LEAD_TIME_SECS`  | `player.queue_lead_time_secs`  |
+    /// | `DDJ_PLAYBACK_REFRESH_SECS` | `player.playback_refresh_secs` |
+    /// | `DDJ_AUTOFILL`              | `player.autofill`              |
+    /// | `DDJ_MAX_QUEUED_PER_GUEST`  | `limits.max_queued_per_guest`  |
+    /// | `DDJ_MAX_ADDS_PER_WINDOW`   | `limits.max_adds_per_window`   |
+    /// | `DDJ_ADD_WINDOW_SECS`       | `limits.add_window_secs`       |
+    /// | `DDJ_REJECT_DUPLICATES`     | `limits.reject_duplicates`     |
+    /// | `DDJ_REPLAY_COOLDOWN_MINS`  | `limits.replay_cooldown_mins`  |
+    /// | `DDJ_MAX_ARTIST_STREAK`     | `limits.max_artist_streak`     |
+    pub fn apply_env(&mut self) -> Result<(), ConfigError> {
+        read_env("DDJ_ADDRESS", "an ip address", &mut self.server.address)?;
+        read_env("DDJ_PORT", "a port number", &mut self.server.port)?;
+        read_optional_env("DDJ_PUBLIC_URL", &mut self.server.public_url);
+
+        let database = &mut self.database;
+        read_optional_env("DDJ_DATABASE_URL", &mut database.url);
+        read_env("DDJ_STORE", "postgres or memory", &mut database.store)?;
+        read_optional_env("POSTGRES_HOST", &mut database.host);
+        read_optional_env("POSTGRES_USER", &mut database.user);
+        read_optional_env("POSTGRES_PASSWORD", &mut database.password);
+        read_env("DDJ_DATABASE_NAME", "a database name", &mut database.name)?;
+        read_env("DDJ_POOL_SIZE", "a whole number", &mut database.pool_size)?;
+        read_flag("DDJ_AUTO_MIGRATE", &mut database.auto_migrate)?;
+
+        read_env("DDJ_REDIRECT_URI", "a url", &mut self.spotify.redirect_uri)?;
+
+        let player = &mut self.player;
+        read_env(
+            "DDJ_QUEUE_LEAD_TIME_SECS",
+            "a whole number",
+            &mut player.queue_lead_time_secs,
+        )?;
+        read_env(
+            "DDJ_PLAYBACK_REFRESH_SECS",
+            "a whole number",
+            &mut player.playback_refresh_secs,
+        )?;
+        read_flag("DDJ_AUTOFILL", &mut player.autofill)?;
+
+        let limits = &mut self.limits;
+        read_env(
+            "DDJ_MAX_QUEUED_PER_GUEST",
+            "a whole number",
+            &mut limits.max_queued_per_guest,
+        )?;
+        read_env(
+            "DDJ_MAX_ADDS_PER_WINDOW",
+            "a whole number",
+            &mut limits.max_adds_per_window,
+        )?;
+        read_env(
+            "DDJ_ADD_WINDOW_SECS",
+            "a whole number",
+            &mut limits.add_window_secs,
+        )?;
+        read_flag("DDJ_REJECT_DUPLICATES", &mut limits.reject_duplicates)?;
+        read_env(
+            "DDJ_REPLAY_COOLDOWN_MINS",
+            "a whole number",
+            &mut limits.replay_cooldown_mins,
+        )?;
+        read_env(
+            "DDJ_MAX_ARTIST_STREAK",
+            "a whole number",
+            &mut limits.max_artist_streak,
+        )?;
+        Ok(())
+    }
+
+    ///Check every setting, listing all the problems at once rather than only the first
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.server.port == 0 {
+            problems.push("server.port must not be 0".to_owned());
+        }
+
+        let database = &self.database;
+        if database.pool_size == 0 {
+            problems.push("database.pool_size must be at least 1".to_owned());
+        }
+        if database.url.is_none() && database.store == StoreKind::Postgres {
+            let required = [
+                ("database.host", "POSTGRES_HOST", &database.host),
+                ("database.user", "POSTGRES_USER", &database.user),
+                ("database.password", "POSTGRES_PASSWORD", &database.password),
+            ];
+            for (setting, variable, value) in required {
+                if value.is_none() {
+                    problems.push(format!(
+                        "{} (or {}) must be set to connect to postgres",
+                        setting, variable
+                    ));
+                }
+            }
+            if database.name.is_empty() {
+                problems.push("database.name must not be empty".to_owned());
+            }
+        }
+
+        if let Some(public_url) = &self.server.public_url {
+            if !is_http_url(public_url) {
+                problems.push(format!(
+                    "server.public_url must be an http(s) url, got {:?}",
+                    public_url
+                ));
+            }
+        }
+
+        let redirect_uri = &self.spotify.redirect_uri;
+        if !is_http_url(redirect_uri) {
+            problems.push(format!(
+                "spotify.redirect_uri must be an http(s) url, got {:?}",
+                redirect_uri
+            ));
+        }
+
+        if self.player.queue_lead_time_secs == 0 {
+            problems.push("player.queue_lead_time_secs must be at least 1".to_owned());
+        }
+        if self.player.playback_refresh_secs == 0 {
+            problems.push("player.playback_refresh_secs must be at least 1".to_owned());
+        }
+
+        let limits = &self.limits;
+        if limits.max_adds_per_window > 0 && limits.add_window_secs == 0 {
+            problems.push(
+                "limits.add_window_secs must be at least 1 while max_adds_per_window is set"
+                    .to_owned(),
+            );
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+}
+
+fn is_http_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+fn read_env<T: FromStr>(
+    name: &'static str,
+    expected: &'static str,
+    setting: &mut T,
+) -> Result<(), ConfigError> {
+    if let Ok(value) = env::var(name) {
+        match value.parse() {
+            Ok(parsed) => *setting = parsed,
+            Err(_) => return Err(env_error(name, value, expected)),
+        }
+    }
+    Ok(())
+}
+
+fn read_optional_env(name: &'static str, setting: &mut Option<String>) {
+    if let Ok(value) = env::var(name) {
+        *setting = Some(value);
+    }
+}
+
+fn read_flag(name: &'static str, setting: &mut bool) -> Result<(), ConfigError> {
+    if let Ok(value) = env::var(name) {
+        *setting = match value.as_str() {
+            "true" | "1" => true,
+            "false" | "0" => false,
+            _ => return Err(env_error(name, value, "true or false")),
+        };
+    }
+    Ok(())
+}
+
+fn env_error(name: &'static str, value: String, expected: &'static str) -> ConfigError {
+    ConfigError::Env {
+        name: name,
+        value: value,
+        expected: expected,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Config {
+        toml::from_str(contents).unwrap()
+    }
+
+    fn problems(config: &Config) -> Vec<String> {
+        match config.validate() {
+            Err(ConfigError::Invalid(problems)) => problems,
+            other => panic!("expected the config to be invalid, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_settings_keep_their_defaults() {
+        let config = parse(
+            r#"
+            [server]
+            port = 9000
+
+            [database]
+            url = "memory"
+            "#,
+        );
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.server.address, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(config.database.pool_size, 5);
+        assert_eq!(config.database.connection_url(), "memory");
+        assert_eq!(config.player.queue_lead_time(), Duration::from_secs(10));
+        //upgrading doesn't migrate the schema or start filling in unasked
+        assert!(!config.database.auto_migrate);
+        assert!(!config.player.autofill);
+        assert_eq!(config.spotify.redirect_uri, "http://localhost:8080#login");
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn postgres_url_is_built_from_its_parts() {
+        let config = parse(
+            r#"
+            [database]
+            host = "db"
+            user = "postgres"
+            password = "secret"
+            name = "party"
+            pool_size = 12
+            "#,
+        );
+        assert_eq!(
+            config.database.connection_url(),
+            "postgres://postgres:secret@db/party"
+        );
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn zero_turns_a_limit_off() {
+        let config = parse(
+            r#"
+            [database]
+            url = "memory"
+
+            [limits]
+            max_queued_per_guest = 0
+            replay_cooldown_mins = 0
+            max_artist_streak = 2
+            "#,
+        );
+        let limits = config.limits.queue_limits();
+        assert_eq!(limits.max_queued_per_guest, None);
+        assert_eq!(limits.max_adds_per_window, Some(10));
+        assert_eq!(limits.replay_cooldown, None);
+        assert_eq!(limits.max_artist_streak, Some(2));
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn unknown_settings_are_refused() {
+        let result: Result<Config, _> = toml::from_str("[server]\nprot = 9000\n");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let config = parse(
+            r#"
+            [database]
+            pool_size = 0
+
+            [spotify]
+            redirect_uri = "localhost:8080"
+
+            [player]
+            queue_lead_time_secs = 0
+            "#,
+        );
+        let problems = problems(&config);
+        assert_eq!(problems.len(), 6, "{:?}", problems);
+        assert!(problems.iter().any(|p| p.contains("POSTGRES_HOST")));
+        assert!(problems.iter().any(|p| p.contains("spotify.redirect_uri")));
+    }
+}
@@ -0,0 +1,191 @@
+use ddj_core::types::{ErrorBody, ErrorKind, QueueRejection};
+use rocket::{
+    http::Status,
+    request::Outcome,
+    response::{self, Responder},
+    serde::json::Json,
+    Request,
+};
+use rspotify::ClientError;
+use thiserror::Error;
+
+use crate::{player::AddTrackError, sessions::SessionError};
+
+///Everything a route can fail with. Sent to clients as an ErrorBody with a status to
+/// match its kind.
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    NotAuthenticated(String),
+
+    #[error("{0}")]
+    Forbidden(String),
+
+    #[error("{0}")]
+    NotFound(String),
+
+    #[error("{0}")]
+    Validation(String),
+
+    #[error("{0}")]
+    Rejected(QueueRejection),
+
+    #[error("{0}")]
+    RateLimited(QueueRejection),
+
+    #[error("spotify request failed: {0}")]
+    Provider(anyhow::Error),
+
+    #[error("storage failed: {0}")]
+    Store(anyhow::Error),
+
+    #[error(transparent)]
+    Internal(anyhow::Error),
+}
+
+impl ApiError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ApiError::NotAuthenticated(_) => ErrorKind::NotAuthenticated,
+            ApiError::Forbidden(_) => ErrorKind::Forbidden,
+            ApiError::NotFound(_) => ErrorKind::NotFound,
+            ApiError::Validation(_) => ErrorKind::Validation,
+            ApiError::Rejected(_) => ErrorKind::Rejected,
+            ApiError::RateLimited(_) => ErrorKind::RateLimited,
+            ApiError::Provider(_) => ErrorKind::Provider,
+            ApiError::Store(_) => ErrorKind::Store,
+            ApiError::Internal(_) => ErrorKind::Internal,
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        status_of(self.kind())
+    }
+
+    ///What the client is sent. Storage and internal failures are only described in the
+    /// server's log, their details mean nothing to guests.
+    pub fn body(&self) -> ErrorBody {
+        let message = match self {
+            ApiError::Store(_) => {
+                "the session's data could not be read or saved".to_owned()
+            }
+            ApiError::Internal(_) => "something went wrong on the server".to_owned(),
+            other => other.to_string(),
+        };
+        let rejection = match self {
+            ApiError::Rejected(rejection) | ApiError::RateLimited(rejection) => {
+                Some(rejection.clone())
+            }
+            _ => None,
+        };
+        ErrorBody {
+            kind: self.kind(),
+            message: message,
+            rejection: rejection,
+        }
+    }
+
+    ///Rejected for a rate limit or for the session's rules, whichever `rejection` is
+    pub fn rejection(rejection: QueueRejection) -> ApiError {
+        if rejection.is_rate_limit() {
+            ApiError::RateLimited(rejection)
+        } else {
+            ApiError::Rejected(rejection)
+        }
+    }
+}
+
+///Player and session errors arrive as anyhow errors from deep inside, tell the storage
+/// and Spotify ones apart by what they wrap
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        if error.chain().any(|cause| cause.is::<sqlx::Error>()) {
+            return ApiError::Store(error);
+        }
+        if error.chain().any(|cause| cause.is::<ClientError>()) {
+            return ApiError::Provider(error);
+        }
+        ApiError::Internal(error)
+    }
+}
+
+impl From<AddTrackError> for ApiError {
+    fn from(error: AddTrackError) -> Self {
+        match error {
+            AddTrackError::Rejected(rejection) => ApiError::rejection(rejection),
+            AddTrackError::UnknownTrack(_) => ApiError::Validation(error.to_string()),
+            AddTrackError::Internal(e) => e.into(),
+        }
+    }
+}
+
+impl From<SessionError> for ApiError {
+    fn from(error: SessionError) -> Self {
+        match error {
+            SessionError::NotFound(_) => ApiError::NotFound(error.to_string()),
+            SessionError::NotAuthenticated(_) => {
+                ApiError::NotAuthenticated(error.to_string())
+            }
+            SessionError::Internal(e) => e.into(),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        println!("{} {} failed: {}", request.method(), request.uri(), self);
+        (self.status(), Json(self.body())).respond_to(request)
+    }
+}
+
+fn status_of(kind: ErrorKind) -> Status {
+    match kind {
+        ErrorKind::NotAuthenticated => Status::Unauthorized,
+        ErrorKind::Forbidden => Status::Forbidden,
+        ErrorKind::NotFound => Status::NotFound,
+        ErrorKind::Validation => Status::BadRequest,
+        ErrorKind::Rejected => Status::Conflict,
+        ErrorKind::RateLimited => Status::TooManyRequests,
+        ErrorKind::Provider => Status::BadGateway,
+        ErrorKind::Store | ErrorKind::Internal => Status::InternalServerError,
+    }
+}
+
+fn kind_of(status: Status) -> ErrorKind {
+    match status.code {
+        401 => ErrorKind::NotAuthenticated,
+        403 => ErrorKind::Forbidden,
+        404 => ErrorKind::NotFound,
+        409 => ErrorKind::Rejected,
+        429 => ErrorKind::RateLimited,
+        502 => ErrorKind::Provider,
+        400..=499 => ErrorKind::Validation,
+        _ => ErrorKind::Internal,
+    }
+}
+
+///Body a request guard refused the request with, Rocket only passes the status on to
+/// the catcher
+struct GuardFailure(Option<ErrorBody>);
+
+///Refuse a request from a request guard, keeping the error for `json_catcher` to send
+pub fn refuse<T>(request: &Request<'_>, error: ApiError) -> Outcome<T, ApiError> {
+    println!("{} {} refused: {}", request.method(), request.uri(), error);
+    let status = error.status();
+    request.local_cache(|| GuardFailure(Some(error.body())));
+    Outcome::Failure((status, error))
+}
+
+///Answers every request no route handled, or a guard refused, with an ErrorBody
+#[catch(default)]
+pub fn json_catcher(status: Status, request: &Request) -> (Status, Json<ErrorBody>) {
+    let body = match request.local_cache(|| GuardFailure(None)) {
+        GuardFailure(Some(body)) => body.clone(),
+        GuardFailure(None) => ErrorBody {
+            kind: kind_of(status),
+            message: status.reason().unwrap_or("request failed").to_lowercase(),
+            rejection: None,
+        },
+    };
+    (status, Json(body))
+}
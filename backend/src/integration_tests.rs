@@ -0,0 +1,985 @@
+//! End-to-end tests driving the mounted routes with Rocket's local client. Sessions run
+//! on an in-memory store and a FakeProvider, whose clock the tests move by hand.
+
+use std::{sync::Arc, time::Duration};
+
+use ddj_core::types::{
+    ClientConfig, Device, ErrorBody, ErrorKind, GuestIdentity, PlayHistory, PlayerHealth,
+    PlayerState, PlayerStatus, QueueRejection, Readiness, Requester, Track,
+};
+use rocket::{
+    http::{Header, Status},
+    local::asynchronous::Client,
+};
+use rspotify::Token;
+use uuid::Uuid;
+
+use crate::{
+    authentication::{GUEST_HEADER, HOST_HEADER},
+    build_rocket,
+    config::Config,
+    limits::QueueLimits,
+    metrics::Metrics,
+    persistence::{memory::InMemoryStore, Store},
+    player::PlayerSettings,
+    provider::{
+        fake::{self, FakeProvider, FAKE_DEVICE_ID},
+        Provider, ProviderFactory,
+    },
+    sessions::SessionRegistry,
+};
+
+struct TestServer {
+    client: Client,
+    provider: Arc<FakeProvider>,
+    store: Store,
+    session_id: Uuid,
+    host_token: String,
+}
+
+impl TestServer {
+    ///Server with one authenticated session and a three track catalog
+    async fn start() -> TestServer {
+        TestServer::start_with_autofill(false).await
+    }
+
+    async fn start_with_autofill(autofill: bool) -> TestServer {
+        let store = InMemoryStore::create();
+        let provider = FakeProvider::new(vec![
+            fake::track("track-a", "Alpha", 180),
+            fake::track("track-b", "Bravo", 200),
+            fake::track("track-c", "Charlie", 150),
+        ]);
+
+        let session_provider: Provider = provider.clone();
+        let providers: ProviderFactory = Arc::new(move |_| Ok(session_provider.clone()));
+        let settings = PlayerSettings {
+            limits: QueueLimits::default(),
+            playback_refresh: Duration::from_millis(20),
+            queue_lead_time: Duration::from_secs(10),
+            autofill: autofill,
+        };
+        let metrics = Metrics::create();
+        let sessions =
+            SessionRegistry::new(store.clone(), settings, providers, metrics.clone());
+
+        let mut session = store.create_session("test").await.unwrap();
+        let mut token = Token::default();
+        token.access_token = "access".to_owned();
+        session.token = Some(token);
+        store.update_session(&session).await.unwrap();
+
+        let rocket = build_rocket(Config::default(), store.clone(), sessions, metrics);
+        TestServer {
+            client: Client::untracked(rocket).await.unwrap(),
+            provider: provider,
+            store: store,
+            session_id: session.id,
+            host_token: session.host_token.unwrap(),
+        }
+    }
+
+    async fn new_guest(&self) -> Uuid {
+        let response = self
+            .client
+            .post(format!("/session/{}/guest", self.session_id))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        response
+            .into_json::<GuestIdentity>()
+            .await
+            .unwrap()
+            .guest_id
+    }
+
+    async fn queue(&self, guest_id: Uuid, track_id: &str) -> Status {
+        self.client
+            .post(format!("/session/{}/queue/{}", self.session_id, track_id))
+            .header(Header::new(GUEST_HEADER, guest_id.to_string()))
+            .dispatch()
+            .await
+            .status()
+    }
+
+    ///Queue a track which the session's rules should turn away, returning why it was
+    async fn rejected(&self, guest_id: Uuid, track_id: &str) -> QueueRejection {
+        let response = self
+            .client
+            .post(format!("/session/{}/queue/{}", self.session_id, track_id))
+            .header(Header::new(GUEST_HEADER, guest_id.to_string()))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Conflict);
+        let error: ErrorBody = response.into_json().await.unwrap();
+        assert_eq!(error.kind, ErrorKind::Rejected);
+        error.rejection.unwrap()
+    }
+
+    ///Send a request to the session as its host
+    async fn control(&self, action: &str) -> Status {
+        self.client
+            .post(format!("/session/{}/{}", self.session_id, action))
+            .header(Header::new(HOST_HEADER, self.host_token.clone()))
+            .dispatch()
+            .await
+            .status()
+    }
+
+    ///Queue every track in the catalog and start playing the first
+    async fn start_playing(&self) {
+        let guest = self.new_guest().await;
+        for track_id in ["track-a", "track-b", "track-c"] {
+            assert_eq!(self.queue(guest, track_id).await, Status::Ok);
+        }
+        assert_eq!(self.control("next_track").await, Status::Ok);
+        self.wait_for(|state| playing(state) == Some("track-a"))
+            .await;
+    }
+
+    async fn devices(&self) -> Vec<Device> {
+        let response = self
+            .client
+            .get(format!("/session/{}/devices", self.session_id))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json().await.unwrap()
+    }
+
+    async fn state(&self) -> PlayerState {
+        let response = self
+            .client
+            .get(format!("/session/{}/current_state", self.session_id))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json().await.unwrap()
+    }
+
+    async fn history(&self, query: &str) -> PlayHistory {
+        let response = self
+            .client
+            .get(format!("/session/{}/history{}", self.session_id, query))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json().await.unwrap()
+    }
+
+    ///Poll the player until its state passes `check`, the player reacts to the fake
+    /// clock on its own refresh interval
+    async fn wait_for<F: Fn(&PlayerState) -> bool>(&self, check: F) -> PlayerState {
+        let mut state = self.state().await;
+        for _ in 0..100 {
+            if check(&state) {
+                return state;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            state = self.state().await;
+        }
+        panic!(
+            "player never reached the expected state, last saw {:?}",
+            state
+        );
+    }
+}
+
+fn playing(state: &PlayerState) -> Option<&str> {
+    state.current_track.as_ref().map(|track| track.id.as_str())
+}
+
+fn queued(state: &PlayerState) -> Vec<&str> {
+    state
+        .queue
+        .iter()
+        .map(|entry| entry.track.id.as_str())
+        .collect()
+}
+
+#[rocket::async_test]
+async fn search_returns_matching_tracks() {
+    let server = TestServer::start().await;
+    let response = server
+        .client
+        .post(format!("/session/{}/search", server.session_id))
+        .body("bra")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let tracks: Vec<Track> = response.into_json().await.unwrap();
+    let names: Vec<&str> = tracks.iter().map(|track| track.name.as_str()).collect();
+    assert_eq!(names, ["Bravo"]);
+    assert_eq!(tracks[0].artists[0].name, "Bravo Band");
+}
+
+#[rocket::async_test]
+async fn skipping_over_a_handed_off_track_keeps_the_queue_moving() {
+    let server = TestServer::start().await;
+    server.start_playing().await;
+    server.provider.advance(Duration::from_secs(175));
+    server
+        .wait_for(|_| server.provider.up_next() == ["track-b"])
+        .await;
+
+    //track-b never starts, someone picks track-c in the Spotify app instead
+    server.provider.play_directly("track-c");
+    server
+        .wait_for(|state| playing(state) == Some("track-c"))
+        .await;
+
+    //the player stops waiting on track-b and hands off what is left in the queue
+    server.provider.advance(Duration::from_secs(145));
+    server
+        .wait_for(|_| server.provider.up_next() == ["track-c"])
+        .await;
+}
+
+#[rocket::async_test]
+async fn crashed_player_is_restarted_with_its_queue() {
+    let server = TestServer::start().await;
+    let guest = server.new_guest().await;
+    assert_eq!(server.queue(guest, "track-a").await, Status::Ok);
+
+    server.provider.crash_next_search();
+    let response = server
+        .client
+        .post(format!("/session/{}/search", server.session_id))
+        .body("bra")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::InternalServerError);
+
+    //requests wait for the restarted player
+    assert_eq!(queued(&server.state().await), ["track-a"]);
+    let response = server
+        .client
+        .get(format!("/session/{}/health", server.session_id))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let health: PlayerHealth = response.into_json().await.unwrap();
+    assert_eq!(health.status, PlayerStatus::Running);
+    assert_eq!(health.restarts, 1);
+    assert_eq!(
+        health.last_crash.as_deref(),
+        Some("search crashed on purpose")
+    );
+
+    assert_eq!(server.queue(guest, "track-b").await, Status::Ok);
+    assert_eq!(queued(&server.state().await), ["track-a", "track-b"]);
+}
+
+#[rocket::async_test]
+async fn probes_report_readiness() {
+    let server = TestServer::start().await;
+    let response = server.client.get("/healthz").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = server
+        .client
+        .get(format!("/readyz?session={}", server.session_id))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let readiness: Readiness = response.into_json().await.unwrap();
+    assert!(readiness.ready);
+    let names: Vec<&str> = readiness
+        .checks
+        .iter()
+        .map(|check| check.name.as_str())
+        .collect();
+    assert_eq!(names, ["database", "players", "session"]);
+
+    let response = server
+        .client
+        .get(format!("/readyz?session={}", Uuid::new_v4()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::ServiceUnavailable);
+    let readiness: Readiness = response.into_json().await.unwrap();
+    assert!(!readiness.ready);
+}
+
+#[rocket::async_test]
+async fn client_config_leaves_the_api_at_its_own_origin() {
+    let server = TestServer::start().await;
+    let response = server.client.get("/config.json").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let config: ClientConfig = response.into_json().await.unwrap();
+    assert_eq!(config.api_base, None);
+}
+
+#[rocket::async_test]
+async fn metrics_count_queue_adds_and_plays() {
+    let server = TestServer::start().await;
+    server.start_playing().await;
+    let guest = server.new_guest().await;
+    assert_eq!(server.queue(guest, "not-a-track").await, Status::BadRequest);
+
+    let response = server.client.get("/metrics").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let metrics = response.into_string().await.unwrap();
+    for line in [
+        "ddj_queue_adds_total{outcome=\"added\"} 3",
+        "ddj_queue_adds_total{outcome=\"unknown_track\"} 1",
+        "ddj_tracks_played_total{requester=\"guest\"} 1",
+        "ddj_spotify_request_duration_seconds_count{call=\"enqueue\"} 1",
+        "ddj_spotify_errors_total{call=\"enqueue\"} 0",
+    ] {
+        assert!(metrics.lines().any(|l| l == line), "missing {}", line);
+    }
+}
+
+#[rocket::async_test]
+async fn queueing_needs_a_guest() {
+    let server = TestServer::start().await;
+    let response = server
+        .client
+        .post(format!("/session/{}/queue/track-a", server.session_id))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Unauthorized);
+    let error: ErrorBody = response.into_json().await.unwrap();
+    assert_eq!(error.kind, ErrorKind::NotAuthenticated);
+}
+
+#[rocket::async_test]
+async fn unknown_tracks_are_rejected() {
+    let server = TestServer::start().await;
+    let guest = server.new_guest().await;
+    let response = server
+        .client
+        .post(format!("/session/{}/queue/not-a-track", server.session_id))
+        .header(Header::new(GUEST_HEADER, guest.to_string()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::BadRequest);
+    let error: ErrorBody = response.into_json().await.unwrap();
+    assert_eq!(error.kind, ErrorKind::Validation);
+    assert!(error.message.contains("not-a-track"));
+    assert!(server.state().await.queue.is_empty());
+}
+
+#[rocket::async_test]
+async fn unknown_sessions_are_answered_with_an_error_body() {
+    let server = TestServer::start().await;
+    let response = server
+        .client
+        .get(format!("/session/{}/current_state", Uuid::new_v4()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::NotFound);
+    let error: ErrorBody = response.into_json().await.unwrap();
+    assert_eq!(error.kind, ErrorKind::NotFound);
+
+    let response = server.client.get("/no/such/route").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    let error: ErrorBody = response.into_json().await.unwrap();
+    assert_eq!(error.kind, ErrorKind::NotFound);
+}
+
+#[rocket::async_test]
+async fn votes_reorder_the_queue() {
+    let server = TestServer::start().await;
+    let guest = server.new_guest().await;
+    for track_id in ["track-a", "track-b", "track-c"] {
+        assert_eq!(server.queue(guest, track_id).await, Status::Ok);
+    }
+
+    let entry_id = server.state().await.queue[2].entry_id;
+    let response = server
+        .client
+        .post(format!(
+            "/session/{}/queue/{}/upvote",
+            server.session_id, entry_id
+        ))
+        .header(Header::new(GUEST_HEADER, guest.to_string()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let state = server.state().await;
+    assert_eq!(queued(&state), ["track-c", "track-a", "track-b"]);
+    assert_eq!(state.queue[0].upvotes, 1);
+}
+
+#[rocket::async_test]
+async fn queued_tracks_play_through() {
+    let server = TestServer::start().await;
+    let guest = server.new_guest().await;
+    for track_id in ["track-a", "track-b", "track-c"] {
+        assert_eq!(server.queue(guest, track_id).await, Status::Ok);
+    }
+
+    assert_eq!(server.control("next_track").await, Status::Ok);
+
+    let state = server
+        .wait_for(|state| playing(state) == Some("track-a"))
+        .await;
+    assert_eq!(queued(&state), ["track-b", "track-c"]);
+    assert!(server.provider.up_next().is_empty());
+
+    //close to the end of the track the next one is handed to the provider
+    server.provider.advance(Duration::from_secs(175));
+    let state = server.wait_for(|state| queued(state) == ["track-c"]).await;
+    assert_eq!(playing(&state), Some("track-a"));
+    assert_eq!(server.provider.up_next(), ["track-b"]);
+
+    server.provider.advance(Duration::from_secs(10));
+    assert_eq!(server.provider.now_playing().as_deref(), Some("track-b"));
+    server
+        .wait_for(|state| playing(state) == Some("track-b"))
+        .await;
+}
+
+#[rocket::async_test]
+async fn devices_can_be_selected() {
+    let server = TestServer::start().await;
+    server.provider.add_device("fake-laptop", "Fake Laptop");
+
+    let devices = server.devices().await;
+    assert_eq!(devices.len(), 2);
+    assert!(devices[0].is_active);
+    assert!(devices.iter().all(|device| !device.is_selected));
+
+    assert_eq!(server.control("devices/fake-laptop").await, Status::Ok);
+
+    let devices = server.devices().await;
+    let laptop = devices.iter().find(|d| d.id == "fake-laptop").unwrap();
+    assert!(laptop.is_selected && laptop.is_active);
+    assert!(!devices[0].is_active);
+
+    let session = server.store.get_session(server.session_id).await.unwrap();
+    assert_eq!(session.unwrap().device_id.as_deref(), Some("fake-laptop"));
+
+    assert_eq!(server.control("devices/nowhere").await, Status::NotFound);
+
+    //playback and controls stay on the chosen device
+    server.start_playing().await;
+    assert_eq!(server.control("volume/30").await, Status::Ok);
+    assert_eq!(server.provider.volume("fake-laptop"), Some(30));
+    assert_eq!(server.provider.volume(FAKE_DEVICE_ID), Some(50));
+    let devices = server.devices().await;
+    let laptop = devices.iter().find(|d| d.id == "fake-laptop").unwrap();
+    assert!(laptop.is_selected && laptop.is_active);
+}
+
+#[rocket::async_test]
+async fn controls_need_the_host_token() {
+    let server = TestServer::start().await;
+    let pause = format!("/session/{}/pause", server.session_id);
+
+    let response = server.client.post(&pause).dispatch().await;
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    let response = server
+        .client
+        .post(&pause)
+        .header(Header::new(HOST_HEADER, "not-the-token"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+    assert!(!server.provider.is_paused());
+
+    let guest = server.new_guest().await;
+    let response = server
+        .client
+        .post(&pause)
+        .header(Header::new(GUEST_HEADER, guest.to_string()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+    assert!(!server.provider.is_paused());
+
+    assert_eq!(server.control("pause").await, Status::Ok);
+    assert!(server.provider.is_paused());
+    assert_eq!(server.control("resume").await, Status::Ok);
+    assert!(!server.provider.is_paused());
+}
+
+#[rocket::async_test]
+async fn hosts_are_refused_by_other_sessions() {
+    let server = TestServer::start().await;
+    let other = server.store.create_session("other").await.unwrap();
+    let other_host = Header::new(HOST_HEADER, other.host_token.unwrap());
+
+    let response = server
+        .client
+        .post(format!("/session/{}/pause", server.session_id))
+        .header(other_host.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+    assert!(!server.provider.is_paused());
+
+    let response = server
+        .client
+        .post(format!("/authenticate_session/{}", server.session_id))
+        .header(other_host)
+        .body("stolen-code")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let session = server.store.get_session(server.session_id).await.unwrap();
+    assert_eq!(session.unwrap().token.unwrap().access_token, "access");
+}
+
+#[rocket::async_test]
+async fn skipping_moves_the_queue_on() {
+    let server = TestServer::start().await;
+    server.start_playing().await;
+
+    assert_eq!(server.control("skip").await, Status::Ok);
+    assert_eq!(server.provider.now_playing().as_deref(), Some("track-b"));
+    let state = server
+        .wait_for(|state| playing(state) == Some("track-b"))
+        .await;
+    assert_eq!(queued(&state), ["track-c"]);
+
+    //track-a was left early, track-b is playing through
+    let history = server.history("").await;
+    let plays: Vec<(&str, bool)> = history
+        .plays
+        .iter()
+        .map(|played| (&played.track.id[..], played.skipped))
+        .collect();
+    assert_eq!(plays, [("track-b", false), ("track-a", true)]);
+
+    assert_eq!(server.control("previous").await, Status::Ok);
+    assert_eq!(server.provider.now_playing().as_deref(), Some("track-a"));
+}
+
+#[rocket::async_test]
+async fn volume_and_position_can_be_set() {
+    let server = TestServer::start().await;
+    server.start_playing().await;
+
+    assert_eq!(server.control("volume/30").await, Status::Ok);
+    assert_eq!(server.provider.volume(FAKE_DEVICE_ID), Some(30));
+    assert_eq!(server.control("volume/101").await, Status::BadRequest);
+
+    assert_eq!(server.control("seek/60000").await, Status::Ok);
+    assert_eq!(server.provider.progress(), Duration::from_secs(60));
+}
+
+#[rocket::async_test]
+async fn guests_cannot_change_the_session() {
+    let server = TestServer::start().await;
+    server.provider.add_device("fake-laptop", "Fake Laptop");
+    let guest = Header::new(GUEST_HEADER, server.new_guest().await.to_string());
+
+    let response = server
+        .client
+        .post(format!("/session/{}/next_track", server.session_id))
+        .header(guest.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+
+    let response = server
+        .client
+        .post(format!(
+            "/session/{}/devices/fake-laptop",
+            server.session_id
+        ))
+        .header(guest.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+    assert!(server
+        .devices()
+        .await
+        .iter()
+        .all(|device| !device.is_selected));
+
+    let response = server
+        .client
+        .post(format!("/authenticate_session/{}", server.session_id))
+        .header(guest)
+        .body("stolen-code")
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let session = server.store.get_session(server.session_id).await.unwrap();
+    assert_eq!(session.unwrap().token.unwrap().access_token, "access");
+}
+
+#[rocket::async_test]
+async fn guests_are_refused_by_other_sessions() {
+    let server = TestServer::start().await;
+    let guest = server.new_guest().await;
+    assert_eq!(server.queue(guest, "track-a").await, Status::Ok);
+    let entry_id = server.state().await.queue[0].entry_id;
+
+    let other = server.store.create_session("other").await.unwrap();
+    let response = server
+        .client
+        .post(format!("/session/{}/guest", other.id))
+        .dispatch()
+        .await;
+    let outsider = response
+        .into_json::<GuestIdentity>()
+        .await
+        .unwrap()
+        .guest_id;
+    let outsider = Header::new(GUEST_HEADER, outsider.to_string());
+
+    let response = server
+        .client
+        .post(format!("/session/{}/queue/track-b", server.session_id))
+        .header(outsider.clone())
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+
+    for vote in ["upvote", "downvote"] {
+        let response = server
+            .client
+            .post(format!(
+                "/session/{}/queue/{}/{}",
+                server.session_id, entry_id, vote
+            ))
+            .header(outsider.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+    let response = server
+        .client
+        .delete(format!(
+            "/session/{}/queue/{}/vote",
+            server.session_id, entry_id
+        ))
+        .header(outsider)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+
+    let state = server.state().await;
+    assert_eq!(queued(&state), ["track-a"]);
+    assert_eq!((state.queue[0].upvotes, state.queue[0].downvotes), (0, 0));
+}
+
+#[rocket::async_test]
+async fn guests_can_only_remove_their_own_tracks() {
+    let server = TestServer::start().await;
+    let guest = server.new_guest().await;
+    let other = server.new_guest().await;
+    assert_eq!(server.queue(guest, "track-a").await, Status::Ok);
+    assert_eq!(server.queue(other, "track-b").await, Status::Ok);
+
+    let queue = server.state().await.queue;
+    let remove = |entry_id: i32, guest_id: Uuid| {
+        server
+            .client
+            .delete(format!("/session/{}/queue/{}", server.session_id, entry_id))
+            .header(Header::new(GUEST_HEADER, guest_id.to_string()))
+            .dispatch()
+    };
+
+    assert_eq!(
+        remove(queue[1].entry_id, guest).await.status(),
+        Status::Forbidden
+    );
+    assert_eq!(remove(queue[0].entry_id, guest).await.status(), Status::Ok);
+    assert_eq!(
+        remove(queue[0].entry_id, guest).await.status(),
+        Status::NotFound
+    );
+    assert_eq!(queued(&server.state().await), ["track-b"]);
+
+    //the host can remove anything
+    let response = server
+        .client
+        .delete(format!(
+            "/session/{}/queue/{}",
+            server.session_id, queue[1].entry_id
+        ))
+        .header(Header::new(HOST_HEADER, server.host_token.clone()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert!(server.state().await.queue.is_empty());
+}
+
+#[rocket::async_test]
+async fn host_can_arrange_and_clear_the_queue() {
+    let server = TestServer::start().await;
+    let guest = server.new_guest().await;
+    for track_id in ["track-a", "track-b", "track-c"] {
+        assert_eq!(server.queue(guest, track_id).await, Status::Ok);
+    }
+    let queue = server.state().await.queue;
+    let last = queue[2].entry_id;
+
+    let response = server
+        .client
+        .post(format!(
+            "/session/{}/queue/{}/position/0",
+            server.session_id, last
+        ))
+        .header(Header::new(GUEST_HEADER, guest.to_string()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+
+    let action = format!("queue/{}/position/0", last);
+    assert_eq!(server.control(&action).await, Status::Ok);
+    assert_eq!(
+        queued(&server.state().await),
+        ["track-c", "track-a", "track-b"]
+    );
+    assert_eq!(
+        server.control("queue/-1/position/0").await,
+        Status::NotFound
+    );
+
+    //track-c keeps the place it was moved to, votes only reorder the rest
+    let response = server
+        .client
+        .post(format!(
+            "/session/{}/queue/{}/upvote",
+            server.session_id, queue[1].entry_id
+        ))
+        .header(Header::new(GUEST_HEADER, guest.to_string()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        queued(&server.state().await),
+        ["track-c", "track-b", "track-a"]
+    );
+
+    let clear = format!("/session/{}/queue", server.session_id);
+    let response = server
+        .client
+        .delete(&clear)
+        .header(Header::new(GUEST_HEADER, guest.to_string()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+
+    let response = server
+        .client
+        .delete(&clear)
+        .header(Header::new(HOST_HEADER, server.host_token.clone()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert!(server.state().await.queue.is_empty());
+}
+
+#[rocket::async_test]
+async fn repeats_are_rejected() {
+    let server = TestServer::start().await;
+    let guest = server.new_guest().await;
+    let other = server.new_guest().await;
+    assert_eq!(server.queue(guest, "track-a").await, Status::Ok);
+    assert_eq!(
+        server.rejected(other, "track-a").await,
+        QueueRejection::AlreadyQueued
+    );
+
+    assert_eq!(server.control("next_track").await, Status::Ok);
+    server
+        .wait_for(|state| playing(state) == Some("track-a"))
+        .await;
+    match server.rejected(other, "track-a").await {
+        QueueRejection::PlayedRecently { cooldown_secs, .. } => {
+            assert_eq!(cooldown_secs, 3600)
+        }
+        rejection => panic!("expected a replay to be rejected, got {:?}", rejection),
+    }
+    assert_eq!(server.queue(other, "track-b").await, Status::Ok);
+}
+
+#[rocket::async_test]
+async fn empty_queue_is_filled_in_until_something_is_queued() {
+    let server = TestServer::start_with_autofill(true).await;
+    let guest = server.new_guest().await;
+    assert_eq!(server.queue(guest, "track-a").await, Status::Ok);
+    assert_eq!(server.control("next_track").await, Status::Ok);
+    let state = server
+        .wait_for(|state| playing(state) == Some("track-a"))
+        .await;
+    assert!(!state.autofill);
+
+    //recommendations are seeded with what has played
+    server.provider.advance(Duration::from_secs(175));
+    server
+        .wait_for(|_| server.provider.up_next() == ["track-b"])
+        .await;
+    server.provider.advance(Duration::from_secs(10));
+    server
+        .wait_for(|state| playing(state) == Some("track-b") && state.autofill)
+        .await;
+
+    assert_eq!(server.queue(guest, "track-c").await, Status::Ok);
+    let state = server
+        .wait_for(|state| playing(state) == Some("track-c"))
+        .await;
+    assert!(!state.autofill);
+    assert!(state.queue.is_empty());
+}
+
+#[rocket::async_test]
+async fn host_can_pick_a_fallback_playlist() {
+    let server = TestServer::start_with_autofill(true).await;
+    server
+        .provider
+        .add_playlist("party-mix", &["track-a", "track-c"]);
+    let guest = server.new_guest().await;
+
+    let response = server
+        .client
+        .post(format!(
+            "/session/{}/fallback_playlist/party-mix",
+            server.session_id
+        ))
+        .header(Header::new(GUEST_HEADER, guest.to_string()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+    assert_eq!(
+        server.control("fallback_playlist/nowhere").await,
+        Status::NotFound
+    );
+    assert_eq!(
+        server.control("fallback_playlist/party-mix").await,
+        Status::Ok
+    );
+    let session = server.store.get_session(server.session_id).await.unwrap();
+    assert_eq!(
+        session.unwrap().fallback_playlist.as_deref(),
+        Some("party-mix")
+    );
+
+    //track-a has just played, so the playlist carries on with track-c
+    assert_eq!(server.queue(guest, "track-a").await, Status::Ok);
+    assert_eq!(server.control("next_track").await, Status::Ok);
+    server
+        .wait_for(|state| playing(state) == Some("track-a"))
+        .await;
+    server.provider.advance(Duration::from_secs(175));
+    server
+        .wait_for(|_| server.provider.up_next() == ["track-c"])
+        .await;
+}
+
+#[rocket::async_test]
+async fn cleared_fallback_playlist_goes_back_to_recommendations() {
+    let server = TestServer::start_with_autofill(true).await;
+    server.provider.add_playlist("party-mix", &["track-c"]);
+    let guest = server.new_guest().await;
+    assert_eq!(
+        server.control("fallback_playlist/party-mix").await,
+        Status::Ok
+    );
+
+    let clear = format!("/session/{}/fallback_playlist", server.session_id);
+    let response = server
+        .client
+        .delete(&clear)
+        .header(Header::new(GUEST_HEADER, guest.to_string()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = server
+        .client
+        .delete(&clear)
+        .header(Header::new(HOST_HEADER, server.host_token.clone()))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    let session = server.store.get_session(server.session_id).await.unwrap();
+    assert_eq!(session.unwrap().fallback_playlist, None);
+
+    assert_eq!(server.queue(guest, "track-a").await, Status::Ok);
+    assert_eq!(server.control("next_track").await, Status::Ok);
+    server
+        .wait_for(|state| playing(state) == Some("track-a"))
+        .await;
+    server.provider.advance(Duration::from_secs(175));
+    server
+        .wait_for(|_| server.provider.up_next() == ["track-b"])
+        .await;
+}
+
+#[rocket::async_test]
+async fn fallback_playlist_moves_on_after_each_fill_in() {
+    let server = TestServer::start_with_autofill(true).await;
+    server
+        .provider
+        .add_playlist("party-mix", &["track-b", "track-c"]);
+    assert_eq!(
+        server.control("fallback_playlist/party-mix").await,
+        Status::Ok
+    );
+    let guest = server.new_guest().await;
+    assert_eq!(server.queue(guest, "track-a").await, Status::Ok);
+    assert_eq!(server.control("next_track").await, Status::Ok);
+    server
+        .wait_for(|state| playing(state) == Some("track-a"))
+        .await;
+
+    server.provider.advance(Duration::from_secs(175));
+    server
+        .wait_for(|_| server.provider.up_next() == ["track-b"])
+        .await;
+    server.provider.advance(Duration::from_secs(10));
+    server
+        .wait_for(|state| playing(state) == Some("track-b") && state.autofill)
+        .await;
+
+    //track-b was recorded as played, so the next fill in is the other track
+    server.provider.advance(Duration::from_secs(190));
+    server
+        .wait_for(|_| server.provider.up_next() == ["track-c"])
+        .await;
+
+    let history = server.history("").await;
+    let played: Vec<(&str, &Requester)> = history
+        .plays
+        .iter()
+        .map(|play| (play.track.id.as_str(), &play.requester))
+        .collect();
+    assert_eq!(
+        played,
+        [
+            ("track-c", &Requester::Autofill),
+            ("track-b", &Requester::Autofill),
+            ("track-a", &Requester::Guest { guest_id: guest }),
+        ]
+    );
+}
+
+#[rocket::async_test]
+async fn played_tracks_are_kept_in_the_history() {
+    let server = TestServer::start().await;
+    let guest = server.new_guest().await;
+    assert_eq!(server.queue(guest, "track-a").await, Status::Ok);
+    assert_eq!(server.queue(guest, "track-b").await, Status::Ok);
+    assert_eq!(server.control("next_track").await, Status::Ok);
+    server
+        .wait_for(|state| playing(state) == Some("track-a"))
+        .await;
+    server.provider.advance(Duration::from_secs(175));
+    server.wait_for(|state| state.queue.is_empty()).await;
+
+    let history = server.history("").await;
+    let played: Vec<&str> = history.plays.iter().map(|p| &p.track.id[..]).collect();
+    assert_eq!(played, ["track-b", "track-a"]);
+    assert_eq!(
+        history.plays[1].requester,
+        Requester::Guest { guest_id: guest }
+    );
+    assert!(history.next_before.is_none());
+
+    let first_page = server.history("?limit=1").await;
+    assert_eq!(first_page.plays[0].track.id, "track-b");
+    let before = first_page.next_before.unwrap();
+    let second_page = server.history(&format!("?limit=1&before={}", before)).await;
+    assert_eq!(second_page.plays[0].track.id, "track-a");
+}
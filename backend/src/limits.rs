@@ -0,0 +1,324 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use ddj_core::types::QueueRejection;
+use uuid::Uuid;
+
+use crate::model::TrackInfo;
+
+///Window the add rate limit is measured over unless configured otherwise
+const DEFAULT_ADD_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+///How long a track has to wait after playing before it can be queued again, unless
+/// configured otherwise
+const DEFAULT_REPLAY_COOLDOWN: Duration = Duration::from_secs(60 * 60);
+
+///Limits on how much a single guest can add to a session's queue, and rules on what
+/// anyone can add. A limit of `None` is not enforced.
+#[derive(Clone, Debug)]
+pub struct QueueLimits {
+    pub max_queued_per_guest: Option<u32>,
+    pub max_adds_per_window: Option<u32>,
+    pub add_window: Duration,
+    ///Refuse tracks which are already waiting in the queue
+    pub reject_duplicates: bool,
+    ///Refuse tracks which played less than this long ago
+    pub replay_cooldown: Option<Duration>,
+    ///Most tracks by one artist allowed to play in a row
+    pub max_artist_streak: Option<u32>,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        Self {
+            max_queued_per_guest: Some(5),
+            max_adds_per_window: Some(10),
+            add_window: DEFAULT_ADD_WINDOW,
+            reject_duplicates: true,
+            replay_cooldown: Some(DEFAULT_REPLAY_COOLDOWN),
+            max_artist_streak: None,
+        }
+    }
+}
+
+impl QueueLimits {
+    ///Check whether a guest who already has `queued` tracks waiting may add another
+    pub fn check_queued(&self, queued: u32) -> Result<(), QueueRejection> {
+        match self.max_queued_per_guest {
+            Some(limit) if queued >= limit => {
+                Err(QueueRejection::TooManyQueued { limit: limit })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    ///Check a track isn't one of the ids already in the queue
+    pub fn check_duplicate<'a, I>(
+        &self,
+        track_id: &str,
+        queued: I,
+    ) -> Result<(), QueueRejection>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        if self.reject_duplicates && queued.into_iter().any(|id| id == track_id) {
+            return Err(QueueRejection::AlreadyQueued);
+        }
+        Ok(())
+    }
+
+    ///Check a track which last played `played_ago` (if ever) may be queued again
+    pub fn check_replay(
+        &self,
+        played_ago: Option<Duration>,
+    ) -> Result<(), QueueRejection> {
+        match (self.replay_cooldown, played_ago) {
+            (Some(cooldown), Some(ago)) if ago < cooldown => {
+                Err(QueueRejection::PlayedRecently {
+                    cooldown_secs: cooldown.as_secs(),
+                    retry_after_secs: (cooldown - ago).as_secs().max(1),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    ///Check a track can follow `preceding`, the tracks which will have played just
+    /// before it with the most recent last, without too many by one artist in a row
+    pub fn check_artist_streak(
+        &self,
+        track: &TrackInfo,
+        preceding: &[TrackInfo],
+    ) -> Result<(), QueueRejection> {
+        let limit = match self.max_artist_streak {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        for artist in track.artists.iter() {
+            let streak = preceding
+                .iter()
+                .rev()
+                .take_while(|previous| {
+                    previous
+                        .artists
+                        .iter()
+                        .any(|other| other.id.0 == artist.id.0)
+                })
+                .count();
+            if streak >= limit as usize {
+                return Err(QueueRejection::ArtistStreak {
+                    artist: artist.name.clone(),
+                    limit: limit,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+///Sliding window of when each guest last added tracks
+#[derive(Default)]
+pub struct AddHistory {
+    adds: HashMap<Uuid, VecDeque<Instant>>,
+}
+
+impl AddHistory {
+    ///Check whether a guest may add another track at `now`
+    pub fn check(
+        &mut self,
+        limits: &QueueLimits,
+        guest_id: Uuid,
+        now: Instant,
+    ) -> Result<(), QueueRejection> {
+        let limit = match limits.max_adds_per_window {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        let adds = self.adds.entry(guest_id).or_default();
+        while let Some(oldest) = adds.front() {
+            if now.duration_since(*oldest) < limits.add_window {
+                break;
+            }
+            adds.pop_front();
+        }
+
+        if adds.len() < limit as usize {
+            return Ok(());
+        }
+
+        //the oldest add in the window has to expire before another is allowed
+        let retry_after = limits.add_window - now.duration_since(*adds.front().unwrap());
+        Err(QueueRejection::TooManyAdds {
+            limit: limit,
+            window_secs: limits.add_window.as_secs(),
+            retry_after_secs: retry_after.as_secs().max(1),
+        })
+    }
+
+    pub fn record(&mut self, guest_id: Uuid, now: Instant) {
+        self.adds.entry(guest_id).or_default().push_back(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Album, Artist, SpotifyItemId};
+
+    fn limits(max_adds: u32) -> QueueLimits {
+        QueueLimits {
+            max_queued_per_guest: Some(2),
+            max_adds_per_window: Some(max_adds),
+            add_window: Duration::from_secs(600),
+            ..QueueLimits::default()
+        }
+    }
+
+    fn track(id: &str, artists: &[&str]) -> TrackInfo {
+        TrackInfo {
+            id: SpotifyItemId(id.to_owned()),
+            name: id.to_owned(),
+            duration: Duration::from_secs(180),
+            album: Album {
+                id: SpotifyItemId(format!("{}-album", id)),
+                name: id.to_owned(),
+                first_image_url: None,
+            },
+            artists: artists
+                .iter()
+                .map(|artist| Artist {
+                    id: SpotifyItemId(artist.to_string()),
+                    name: artist.to_uppercase(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn queued_limit_is_enforced() {
+        let limits = limits(10);
+        assert!(limits.check_queued(1).is_ok());
+        assert_eq!(
+            limits.check_queued(2),
+            Err(QueueRejection::TooManyQueued { limit: 2 })
+        );
+    }
+
+    #[test]
+    fn adds_are_limited_within_the_window() {
+        let limits = limits(2);
+        let mut history = AddHistory::default();
+        let guest = Uuid::new_v4();
+        let start = Instant::now();
+
+        for _ in 0..2 {
+            assert!(history.check(&limits, guest, start).is_ok());
+            history.record(guest, start);
+        }
+
+        let later = start + Duration::from_secs(60);
+        match history.check(&limits, guest, later) {
+            Err(QueueRejection::TooManyAdds {
+                retry_after_secs, ..
+            }) => assert_eq!(retry_after_secs, 540),
+            other => panic!("expected add to be rejected, got {:?}", other),
+        }
+
+        //other guests have their own window
+        assert!(history.check(&limits, Uuid::new_v4(), later).is_ok());
+    }
+
+    #[test]
+    fn adds_expire_from_the_window() {
+        let limits = limits(1);
+        let mut history = AddHistory::default();
+        let guest = Uuid::new_v4();
+        let start = Instant::now();
+
+        history.record(guest, start);
+        assert!(history.check(&limits, guest, start).is_err());
+        assert!(history
+            .check(&limits, guest, start + Duration::from_secs(600))
+            .is_ok());
+    }
+
+    #[test]
+    fn disabled_limits_allow_everything() {
+        let limits = QueueLimits {
+            max_queued_per_guest: None,
+            max_adds_per_window: None,
+            add_window: Duration::from_secs(600),
+            reject_duplicates: false,
+            replay_cooldown: None,
+            max_artist_streak: None,
+        };
+        let mut history = AddHistory::default();
+        let guest = Uuid::new_v4();
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            history.record(guest, now);
+        }
+        assert!(history.check(&limits, guest, now).is_ok());
+        assert!(limits.check_queued(100).is_ok());
+        assert!(limits.check_duplicate("a", ["a"]).is_ok());
+        assert!(limits.check_replay(Some(Duration::ZERO)).is_ok());
+        let previous = [track("b", &["band"]), track("c", &["band"])];
+        assert!(limits
+            .check_artist_streak(&track("a", &["band"]), &previous)
+            .is_ok());
+    }
+
+    #[test]
+    fn duplicates_are_rejected() {
+        let limits = limits(10);
+        assert_eq!(
+            limits.check_duplicate("a", ["b", "a"]),
+            Err(QueueRejection::AlreadyQueued)
+        );
+        assert!(limits.check_duplicate("c", ["b", "a"]).is_ok());
+    }
+
+    #[test]
+    fn recently_played_tracks_are_rejected() {
+        let limits = limits(10);
+        assert!(limits.check_replay(None).is_ok());
+        assert!(limits.check_replay(Some(Duration::from_secs(3600))).is_ok());
+        assert_eq!(
+            limits.check_replay(Some(Duration::from_secs(600))),
+            Err(QueueRejection::PlayedRecently {
+                cooldown_secs: 3600,
+                retry_after_secs: 3000,
+            })
+        );
+    }
+
+    #[test]
+    fn artist_streaks_are_limited() {
+        let limits = QueueLimits {
+            max_artist_streak: Some(2),
+            ..limits(10)
+        };
+        let next = track("d", &["band", "guest"]);
+
+        let previous = [track("a", &["band"]), track("b", &["other"])];
+        assert!(limits.check_artist_streak(&next, &previous).is_ok());
+
+        let previous = [
+            track("a", &["other"]),
+            track("b", &["guest"]),
+            track("c", &["guest", "band"]),
+        ];
+        assert_eq!(
+            limits.check_artist_streak(&next, &previous),
+            Err(QueueRejection::ArtistStreak {
+                artist: "GUEST".to_owned(),
+                limit: 2,
+            })
+        );
+    }
+}
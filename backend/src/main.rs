@@ -1,76 +1,178 @@
 #![feature(associated_type_bounds)]
 
-use authentication::ManagedAuthState;
-
 use rocket::fairing::AdHoc;
 
+use config::Config;
+use metrics::{ManagedMetrics, Metrics};
+use persistence::{migrations, Store};
+use player::PlayerSettings;
+use provider::spotify::SpotifyProvider;
 use rocket::http::Header;
+use rocket::{Build, Rocket};
 use rspotify::Credentials;
+use sessions::{ManagedSessionRegistry, SessionRegistry};
 
-use std::net::Ipv4Addr;
-use std::sync::Arc;
+use std::{env, process};
 
 mod authentication;
+mod config;
+mod error;
+#[cfg(test)]
+mod integration_tests;
+mod limits;
+mod metrics;
 mod model;
 
 mod persistence;
 mod player;
+mod provider;
 mod routes;
+mod sessions;
 
 #[macro_use]
 extern crate rocket;
 
-#[launch]
-async fn rocket() -> _ {
+#[rocket::main]
+async fn main() {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let command = env::args().nth(1);
+    match command.as_deref() {
+        None | Some("serve") => {
+            if let Err(e) = rocket(config).await.launch().await {
+                println!("server stopped with an error: {}", e);
+            }
+        }
+        Some("migrate") => migrate(&config).await,
+        Some("schema-version") => {
+            let data_store = connect_store(&config).await;
+            match data_store.schema_version().await {
+                Ok(version) => println!(
+                    "database schema is at version {}, this build expects version {}",
+                    version,
+                    migrations::SCHEMA_VERSION
+                ),
+                Err(e) => panic!("failed to read schema version: {}", e),
+            }
+        }
+        Some(other) => {
+            println!("unknown command {}", other);
+            println!("usage: backend [serve | migrate | schema-version]");
+        }
+    }
+}
+
+async fn connect_store(config: &Config) -> Store {
+    let data_store_result = persistence::connect(&config.database).await;
+    if let Err(e) = data_store_result {
+        panic!("failed to connect to database: {}", e);
+    }
+    data_store_result.unwrap()
+}
+
+///Apply every pending migration and exit
+async fn migrate(config: &Config) {
+    let data_store = connect_store(config).await;
+    match data_store.migrate().await {
+        Ok(version) => println!("database schema is at version {}", version),
+        Err(e) => panic!("failed to migrate database: {}", e),
+    }
+}
+
+async fn rocket(config: Config) -> Rocket<Build> {
     let creds = Credentials::from_env();
     if creds.is_none() {
         panic!("can't start server without available spotify app credentials");
     }
-    let config = rocket::Config {
-        address: std::net::IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
-        port: 8090,
+    let rocket_config = rocket::Config {
+        address: config.server.address,
+        port: config.server.port,
         ..Default::default()
     };
 
-    let auth: ManagedAuthState = Arc::default();
-    let player_cmd = player::start_player_thread(auth.clone());
-
-    let data_store_result = persistence::pgsql::PostgressDatabase::connect().await;
-    if let Err(e) = data_store_result {
-        panic!("failed to connect to database: {}", e);
-    }
-    let data_store = data_store_result.unwrap();
-    let create_tables_res = data_store.create_tables().await;
-    if let Err(e) = create_tables_res {
-        panic!("failed to create tables: {}", e);
+    let data_store = connect_store(&config).await;
+    let auto_migrate = config.database.auto_migrate;
+    if let Err(e) = migrations::prepare_store(&data_store, auto_migrate).await {
+        panic!("database is not usable: {}", e);
     }
 
+    let settings = PlayerSettings::from_config(&config);
+
+    let metrics = Metrics::create();
+    let sessions = SessionRegistry::new(
+        data_store.clone(),
+        settings,
+        SpotifyProvider::session_factory(),
+        metrics.clone(),
+    );
+    sessions::start_idle_reaper(sessions.clone());
+
+    build_rocket(config, data_store, sessions, metrics).configure(rocket_config)
+}
+
+///Mount every route and hand it the state it needs. Kept apart from `rocket()` so tests
+/// can build the same server around their own store and provider.
+fn build_rocket(
+    config: Config,
+    data_store: Store,
+    sessions: ManagedSessionRegistry,
+    metrics: ManagedMetrics,
+) -> Rocket<Build> {
     rocket::build()
         .mount(
             "/",
             routes![
                 routes::search,
                 routes::play_track,
+                routes::pause,
+                routes::resume,
+                routes::skip,
+                routes::previous,
+                routes::set_volume,
+                routes::seek,
                 routes::add_track_to_queue,
                 routes::get_queued_tracks,
+                routes::remove_queue_entry,
+                routes::move_queue_entry,
+                routes::clear_queue,
                 routes::get_current_state,
+                routes::get_player_health,
+                routes::get_history,
+                routes::get_devices,
+                routes::select_device,
+                routes::set_fallback_playlist,
+                routes::clear_fallback_playlist,
+                routes::player_events,
+                routes::upvote,
+                routes::downvote,
+                routes::clear_vote,
+                routes::new_guest,
                 routes::handle_options,
-                routes::start_auth_flow,
-                routes::finish_auth_flow,
                 routes::create_session,
-                routes::authenticate_session
+                routes::authenticate_session,
+                routes::client_config,
+                routes::healthz,
+                routes::readyz,
+                routes::get_metrics
             ],
         )
-        .manage(player_cmd)
-        .manage(auth)
+        .register("/", catchers![error::json_catcher])
+        .manage(config)
+        .manage(sessions)
         .manage(data_store)
-        .configure(config)
+        .manage(metrics)
         .attach(AdHoc::on_response("CORS Headers", |_, response| {
             Box::pin(async move {
                 response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
                 response.set_header(Header::new(
                     "Access-Control-Allow-Methods",
-                    "POST, GET, PATCH, OPTIONS",
+                    "POST, GET, PATCH, DELETE, OPTIONS",
                 ));
                 response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
                 response
@@ -0,0 +1,296 @@
+//! Counters behind the /metrics endpoint, rendered in Prometheus' text format. Kept by
+//! hand rather than through a metrics library, there are only a handful of them.
+
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::persistence::model::Requester;
+
+pub type ManagedMetrics = Arc<Metrics>;
+
+///Upper bounds of the Spotify latency histogram's buckets, in seconds
+const LATENCY_BUCKETS: [f64; 9] = [0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+///What became of an attempt to add a track to a queue
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueAddOutcome {
+    Added,
+    ///Turned away by one of the session's rules
+    Rejected,
+    ///Turned away by a rate limit
+    RateLimited,
+    UnknownTrack,
+    Failed,
+}
+
+impl QueueAddOutcome {
+    const ALL: [QueueAddOutcome; 5] = [
+        QueueAddOutcome::Added,
+        QueueAddOutcome::Rejected,
+        QueueAddOutcome::RateLimited,
+        QueueAddOutcome::UnknownTrack,
+        QueueAddOutcome::Failed,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            QueueAddOutcome::Added => "added",
+            QueueAddOutcome::Rejected => "rejected",
+            QueueAddOutcome::RateLimited => "rate_limited",
+            QueueAddOutcome::UnknownTrack => "unknown_track",
+            QueueAddOutcome::Failed => "failed",
+        }
+    }
+}
+
+///Each call a music provider makes to its API, timed separately
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProviderCall {
+    Search,
+    Track,
+    Recommendations,
+    PlaylistTracks,
+    Enqueue,
+    SkipToNext,
+    SkipToPrevious,
+    Pause,
+    Resume,
+    SetVolume,
+    Seek,
+    CurrentPlayback,
+    Devices,
+    TransferPlayback,
+}
+
+impl ProviderCall {
+    const ALL: [ProviderCall; 14] = [
+        ProviderCall::Search,
+        ProviderCall::Track,
+        ProviderCall::Recommendations,
+        ProviderCall::PlaylistTracks,
+        ProviderCall::Enqueue,
+        ProviderCall::SkipToNext,
+        ProviderCall::SkipToPrevious,
+        ProviderCall::Pause,
+        ProviderCall::Resume,
+        ProviderCall::SetVolume,
+        ProviderCall::Seek,
+        ProviderCall::CurrentPlayback,
+        ProviderCall::Devices,
+        ProviderCall::TransferPlayback,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            ProviderCall::Search => "search",
+            ProviderCall::Track => "track",
+            ProviderCall::Recommendations => "recommendations",
+            ProviderCall::PlaylistTracks => "playlist_tracks",
+            ProviderCall::Enqueue => "enqueue",
+            ProviderCall::SkipToNext => "skip_to_next",
+            ProviderCall::SkipToPrevious => "skip_to_previous",
+            ProviderCall::Pause => "pause",
+            ProviderCall::Resume => "resume",
+            ProviderCall::SetVolume => "set_volume",
+            ProviderCall::Seek => "seek",
+            ProviderCall::CurrentPlayback => "current_playback",
+            ProviderCall::Devices => "devices",
+            ProviderCall::TransferPlayback => "transfer_playback",
+        }
+    }
+}
+
+///Latencies counted into cumulative buckets, as Prometheus histograms are
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    queue_adds: [AtomicU64; QueueAddOutcome::ALL.len()],
+    ///Indexed guest, host, autofill
+    tracks_played: [AtomicU64; 3],
+    provider_latency: [Histogram; ProviderCall::ALL.len()],
+    provider_errors: [AtomicU64; ProviderCall::ALL.len()],
+    connected_clients: AtomicI64,
+}
+
+impl Metrics {
+    pub fn create() -> ManagedMetrics {
+        Arc::new(Metrics::default())
+    }
+
+    pub fn record_queue_add(&self, outcome: QueueAddOutcome) {
+        self.queue_adds[outcome as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_track_played(&self, requester: &Requester) {
+        let index = match requester {
+            Requester::Guest(_) => 0,
+            Requester::Host => 1,
+            Requester::Autofill => 2,
+        };
+        self.tracks_played[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_provider_call(&self, call: ProviderCall, elapsed: Duration, ok: bool) {
+        self.provider_latency[call as usize].observe(elapsed);
+        if !ok {
+            self.provider_errors[call as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    ///Count a client as connected until the returned guard is dropped
+    pub fn client_connected(self: &Arc<Self>) -> ConnectedClient {
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+        ConnectedClient {
+            metrics: self.clone(),
+        }
+    }
+
+    ///Every metric in Prometheus' text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        describe(
+            &mut out,
+            "ddj_queue_adds_total",
+            "counter",
+            "Tracks guests tried to add to a queue, by what became of them",
+        );
+        for outcome in QueueAddOutcome::ALL {
+            let _ = writeln!(
+                out,
+                "ddj_queue_adds_total{{outcome=\"{}\"}} {}",
+                outcome.label(),
+                self.queue_adds[outcome as usize].load(Ordering::Relaxed)
+            );
+        }
+
+        describe(
+            &mut out,
+            "ddj_tracks_played_total",
+            "counter",
+            "Tracks handed to Spotify to play, by who they were played for",
+        );
+        for (requester, played) in ["guest", "host", "autofill"]
+            .iter()
+            .zip(&self.tracks_played)
+        {
+            let _ = writeln!(
+                out,
+                "ddj_tracks_played_total{{requester=\"{}\"}} {}",
+                requester,
+                played.load(Ordering::Relaxed)
+            );
+        }
+
+        describe(
+            &mut out,
+            "ddj_spotify_request_duration_seconds",
+            "histogram",
+            "How long calls to the Spotify API took",
+        );
+        for call in ProviderCall::ALL {
+            let histogram = &self.provider_latency[call as usize];
+            for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+                let _ = writeln!(
+                    out,
+                    "ddj_spotify_request_duration_seconds_bucket{{call=\"{}\",le=\"{}\"}} {}",
+                    call.label(),
+                    bound,
+                    bucket.load(Ordering::Relaxed)
+                );
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "ddj_spotify_request_duration_seconds_bucket{{call=\"{}\",le=\"+Inf\"}} {}",
+                call.label(),
+                count
+            );
+            let _ = writeln!(
+                out,
+                "ddj_spotify_request_duration_seconds_sum{{call=\"{}\"}} {}",
+                call.label(),
+                histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+            );
+            let _ = writeln!(
+                out,
+                "ddj_spotify_request_duration_seconds_count{{call=\"{}\"}} {}",
+                call.label(),
+                count
+            );
+        }
+
+        describe(
+            &mut out,
+            "ddj_spotify_errors_total",
+            "counter",
+            "Calls to the Spotify API which failed",
+        );
+        for call in ProviderCall::ALL {
+            let _ = writeln!(
+                out,
+                "ddj_spotify_errors_total{{call=\"{}\"}} {}",
+                call.label(),
+                self.provider_errors[call as usize].load(Ordering::Relaxed)
+            );
+        }
+
+        describe(
+            &mut out,
+            "ddj_connected_clients",
+            "gauge",
+            "Clients subscribed to a session's events",
+        );
+        let _ = writeln!(
+            out,
+            "ddj_connected_clients {}",
+            self.connected_clients.load(Ordering::Relaxed)
+        );
+
+        out
+    }
+}
+
+fn describe(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+///A connected client, counted in the metrics for as long as it is kept
+pub struct ConnectedClient {
+    metrics: ManagedMetrics,
+}
+
+impl Drop for ConnectedClient {
+    fn drop(&mut self) {
+        self.metrics
+            .connected_clients
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
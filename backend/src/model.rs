@@ -3,44 +3,66 @@ use sqlx::{postgres::PgRow, FromRow, Row};
 use std::time::Duration;
 
 use rocket::serde::{Deserialize, Serialize};
-use rspotify::model::{FullTrack, Id, SimplifiedAlbum};
+
+use crate::persistence::model::{
+    PlayedTrack, QueuedTrack, Requester, SpotifyAlbum, SpotifyArtist, SpotifyTrack,
+};
 
 #[repr(transparent)]
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct SpotifyItemId(pub String);
 
-impl SpotifyItemId {
-    pub fn into_id<T: Id>(&self) -> T {
-        let res = T::from_id(&self.0);
-        if let Err(_) = res {
-            panic!("invalid characters found in a spotify item id");
-        }
-        return res.unwrap();
-    }
-}
-
-impl<T: Id> From<T> for SpotifyItemId {
-    fn from(id: T) -> Self {
-        let id_str = id.id().to_owned();
-        return Self(id_str);
-    }
-}
-
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TrackInfo {
     pub id: SpotifyItemId,
     pub name: String,
     pub duration: Duration,
     pub album: Album,
+    pub artists: Vec<Artist>,
 }
 
-impl From<FullTrack> for TrackInfo {
-    fn from(track: FullTrack) -> Self {
+impl From<SpotifyTrack> for TrackInfo {
+    fn from(track: SpotifyTrack) -> Self {
         return TrackInfo {
-            id: track.id.unwrap().into(),
+            id: SpotifyItemId(track.id),
             name: track.name,
             duration: track.duration,
-            album: track.album.into(),
+            album: Album {
+                id: SpotifyItemId(track.album.id),
+                name: track.album.name,
+                first_image_url: track.album.cover_image_url,
+            },
+            artists: track
+                .artists
+                .into_iter()
+                .map(|artist| Artist {
+                    id: SpotifyItemId(artist.id),
+                    name: artist.name,
+                })
+                .collect(),
+        };
+    }
+}
+
+impl From<&TrackInfo> for SpotifyTrack {
+    fn from(track: &TrackInfo) -> Self {
+        return SpotifyTrack {
+            id: track.id.0.clone(),
+            name: track.name.clone(),
+            duration: track.duration,
+            album: SpotifyAlbum {
+                name: track.album.name.clone(),
+                id: track.album.id.0.clone(),
+                cover_image_url: track.album.first_image_url.clone(),
+            },
+            artists: track
+                .artists
+                .iter()
+                .map(|artist| SpotifyArtist {
+                    id: artist.id.0.clone(),
+                    name: artist.name.clone(),
+                })
+                .collect(),
         };
     }
 }
@@ -58,30 +80,91 @@ impl Into<Track> for &TrackInfo {
         Track {
             name: self.name.clone(),
             id: self.id.0.clone(),
-            artists: Vec::new(),
+            artists: self.artists.iter().map(|artist| artist.into()).collect(),
             duration: self.duration,
             album_art_link: self.album.first_image_url.clone(),
         }
     }
 }
 
+///A track in a session's queue, identified by its queue entry rather than the track
+/// id since the same track can be queued more than once
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Album {
-    id: SpotifyItemId,
-    name: String,
-    first_image_url: Option<String>,
+pub struct QueueEntry {
+    pub id: i32,
+    pub track: TrackInfo,
+    pub upvotes: u32,
+    pub downvotes: u32,
+}
+
+impl From<QueuedTrack> for QueueEntry {
+    fn from(entry: QueuedTrack) -> Self {
+        return QueueEntry {
+            id: entry.id,
+            track: entry.track.into(),
+            upvotes: entry.upvotes,
+            downvotes: entry.downvotes,
+        };
+    }
+}
+
+impl Into<ddj_core::types::QueuedTrack> for &QueueEntry {
+    fn into(self) -> ddj_core::types::QueuedTrack {
+        ddj_core::types::QueuedTrack {
+            entry_id: self.id,
+            track: (&self.track).into(),
+            upvotes: self.upvotes,
+            downvotes: self.downvotes,
+        }
+    }
+}
+
+impl From<PlayedTrack> for ddj_core::types::PlayedTrack {
+    fn from(played: PlayedTrack) -> Self {
+        let track: TrackInfo = played.track.into();
+        return ddj_core::types::PlayedTrack {
+            play_id: played.id,
+            track: (&track).into(),
+            played_at_ms: played.played_at.timestamp_millis(),
+            requester: played.requester.into(),
+            skipped: played.skipped,
+        };
+    }
 }
 
-impl From<SimplifiedAlbum> for Album {
-    fn from(input: SimplifiedAlbum) -> Self {
-        Self {
-            id: input.id.unwrap().into(),
-            name: input.name,
-            first_image_url: input.images.first().map(|image| image.url.clone()),
+impl From<Requester> for ddj_core::types::Requester {
+    fn from(requester: Requester) -> Self {
+        match requester {
+            Requester::Guest(guest_id) => {
+                ddj_core::types::Requester::Guest { guest_id: guest_id }
+            }
+            Requester::Host => ddj_core::types::Requester::Host,
+            Requester::Autofill => ddj_core::types::Requester::Autofill,
         }
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Artist {
+    pub id: SpotifyItemId,
+    pub name: String,
+}
+
+impl Into<ddj_core::types::Artist> for &Artist {
+    fn into(self) -> ddj_core::types::Artist {
+        ddj_core::types::Artist {
+            name: self.name.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Album {
+    pub id: SpotifyItemId,
+    pub name: String,
+    pub first_image_url: Option<String>,
+}
+
 impl<'r> FromRow<'r, PgRow> for Album {
     fn from_row(row: &'r PgRow) -> Result<Self, sqlx::Error> {
         let id = row.try_get("id")?;
@@ -0,0 +1,721 @@
+//! Behaviour every PersistentStore implementation is expected to share. Implementations
+//! run the whole suite with `persistent_store_tests!(<future producing the store>)`.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use rspotify::Token;
+use sqlx::types::chrono::Utc;
+use uuid::Uuid;
+
+use super::{
+    model::{QueuedTrack, Requester, SpotifyAlbum, SpotifyArtist, SpotifyTrack, Vote},
+    PersistentStore,
+};
+
+macro_rules! persistent_store_tests {
+    ($store:expr) => {
+        persistent_store_tests!(
+            $store,
+            queue_preserves_insertion_order,
+            queue_respects_limit,
+            pop_takes_from_front,
+            pop_from_empty_queue,
+            queues_are_per_session,
+            track_metadata_round_trips,
+            queued_tracks_include_artists,
+            votes_order_the_queue,
+            votes_are_counted_once_per_guest,
+            votes_can_be_withdrawn,
+            votes_are_scoped_to_sessions,
+            guests_round_trip,
+            queued_tracks_are_counted_per_guest,
+            queue_entries_can_be_looked_up,
+            queue_entries_can_be_removed,
+            moved_entries_keep_their_place,
+            moves_are_clamped_to_the_queue,
+            clearing_empties_the_queue,
+            played_tracks_are_recorded,
+            popped_entries_remember_who_queued_them,
+            history_is_paged_latest_first,
+            saved_tracks_can_be_played_without_queueing,
+            skips_are_marked_on_the_latest_play,
+            recent_plays_are_listed_latest_first,
+            last_play_times_are_tracked,
+            sessions_round_trip,
+            session_device_round_trips,
+            fallback_playlists_round_trip,
+            host_tokens_round_trip,
+            unauthenticated_session_update_fails,
+            missing_session_is_none
+        );
+    };
+    ($store:expr, $($test:ident),+) => {
+        $(
+            #[tokio::test]
+            async fn $test() -> anyhow::Result<()> {
+                let store = $store.await;
+                crate::persistence::conformance::$test(&store).await
+            }
+        )+
+    };
+}
+
+fn example_track(name: &str) -> SpotifyTrack {
+    SpotifyTrack {
+        id: Uuid::new_v4().simple().to_string(),
+        name: name.to_owned(),
+        duration: Duration::from_millis(215_250),
+        album: SpotifyAlbum {
+            name: format!("{} (album)", name),
+            id: Uuid::new_v4().simple().to_string(),
+            cover_image_url: Some("http://fake-album-cover.com/image.jpg".to_owned()),
+        },
+        artists: vec![
+            SpotifyArtist {
+                id: Uuid::new_v4().simple().to_string(),
+                name: format!("{} (lead)", name),
+            },
+            SpotifyArtist {
+                id: Uuid::new_v4().simple().to_string(),
+                name: format!("{} (featured)", name),
+            },
+        ],
+    }
+}
+
+async fn queue_tracks(
+    store: &dyn PersistentStore,
+    session_id: Uuid,
+    names: &[&str],
+) -> Result<()> {
+    for name in names {
+        store
+            .add_track_to_queue(session_id, example_track(name), None)
+            .await?;
+    }
+    Ok(())
+}
+
+fn names(queue: &[QueuedTrack]) -> Vec<&str> {
+    queue.iter().map(|entry| &entry.track.name[..]).collect()
+}
+
+fn entry_id(queue: &[QueuedTrack], name: &str) -> i32 {
+    queue
+        .iter()
+        .find(|entry| entry.track.name == name)
+        .map(|entry| entry.id)
+        .unwrap()
+}
+
+pub async fn queue_preserves_insertion_order(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    queue_tracks(store, session.id, &["first", "second", "third"]).await?;
+
+    let queue = store.get_track_queue(session.id, 10).await?;
+    assert_eq!(names(&queue), vec!["first", "second", "third"]);
+    Ok(())
+}
+
+pub async fn queue_respects_limit(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    queue_tracks(store, session.id, &["first", "second", "third"]).await?;
+
+    let queue = store.get_track_queue(session.id, 2).await?;
+    assert_eq!(names(&queue), vec!["first", "second"]);
+    Ok(())
+}
+
+pub async fn pop_takes_from_front(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    queue_tracks(store, session.id, &["first", "second"]).await?;
+
+    let popped = store.pop_track_from_queue(session.id).await?.unwrap();
+    assert_eq!(popped.track.name, "first");
+
+    let queue = store.get_track_queue(session.id, 10).await?;
+    assert_eq!(names(&queue), vec!["second"]);
+    Ok(())
+}
+
+pub async fn pop_from_empty_queue(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    assert!(store.pop_track_from_queue(session.id).await?.is_none());
+    Ok(())
+}
+
+pub async fn queues_are_per_session(store: &dyn PersistentStore) -> Result<()> {
+    let first = store.create_session("conformance").await?;
+    let second = store.create_session("conformance").await?;
+    queue_tracks(store, first.id, &["mine"]).await?;
+    queue_tracks(store, second.id, &["theirs"]).await?;
+
+    let popped = store.pop_track_from_queue(second.id).await?.unwrap();
+    assert_eq!(popped.track.name, "theirs");
+    assert!(store.pop_track_from_queue(second.id).await?.is_none());
+
+    let queue = store.get_track_queue(first.id, 10).await?;
+    assert_eq!(names(&queue), vec!["mine"]);
+    Ok(())
+}
+
+pub async fn track_metadata_round_trips(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    let track = example_track("metadata");
+    store
+        .add_track_to_queue(session.id, track.clone(), None)
+        .await?;
+
+    let stored = store.get_track_by_id(&track.id).await?;
+    assert_eq!(stored.name, track.name);
+    assert_eq!(stored.duration, track.duration);
+    assert_eq!(stored.album.id, track.album.id);
+    assert_eq!(stored.album.name, track.album.name);
+    assert_eq!(stored.album.cover_image_url, track.album.cover_image_url);
+
+    let artists: Vec<&str> = stored.artists.iter().map(|a| &a.id[..]).collect();
+    let expected: Vec<&str> = track.artists.iter().map(|a| &a.id[..]).collect();
+    assert_eq!(artists, expected);
+    Ok(())
+}
+
+pub async fn queued_tracks_include_artists(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    queue_tracks(store, session.id, &["first", "second"]).await?;
+
+    let queue = store.get_track_queue(session.id, 10).await?;
+    for track in queue.iter().map(|entry| &entry.track) {
+        let artists: Vec<&str> = track.artists.iter().map(|a| &a.name[..]).collect();
+        assert_eq!(
+            artists,
+            vec![
+                format!("{} (lead)", track.name),
+                format!("{} (featured)", track.name)
+            ]
+        );
+    }
+    Ok(())
+}
+
+pub async fn votes_order_the_queue(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    queue_tracks(store, session.id, &["first", "second", "third"]).await?;
+    let queue = store.get_track_queue(session.id, 10).await?;
+
+    let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+    store
+        .set_vote(session.id, entry_id(&queue, "third"), alice, Some(Vote::Up))
+        .await?;
+    store
+        .set_vote(session.id, entry_id(&queue, "first"), bob, Some(Vote::Down))
+        .await?;
+
+    let queue = store.get_track_queue(session.id, 10).await?;
+    assert_eq!(names(&queue), vec!["third", "second", "first"]);
+    assert_eq!(queue[0].upvotes, 1);
+    assert_eq!(queue[2].downvotes, 1);
+
+    let popped = store.pop_track_from_queue(session.id).await?.unwrap();
+    assert_eq!(popped.track.name, "third");
+    Ok(())
+}
+
+pub async fn votes_are_counted_once_per_guest(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    queue_tracks(store, session.id, &["first"]).await?;
+    let entry = entry_id(&store.get_track_queue(session.id, 10).await?, "first");
+
+    let guest = Uuid::new_v4();
+    store
+        .set_vote(session.id, entry, guest, Some(Vote::Up))
+        .await?;
+    store
+        .set_vote(session.id, entry, guest, Some(Vote::Up))
+        .await?;
+    store
+        .set_vote(session.id, entry, guest, Some(Vote::Down))
+        .await?;
+
+    let queue = store.get_track_queue(session.id, 10).await?;
+    assert_eq!(queue[0].upvotes, 0);
+    assert_eq!(queue[0].downvotes, 1);
+    Ok(())
+}
+
+pub async fn votes_can_be_withdrawn(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    queue_tracks(store, session.id, &["first"]).await?;
+    let entry = entry_id(&store.get_track_queue(session.id, 10).await?, "first");
+
+    let guest = Uuid::new_v4();
+    store
+        .set_vote(session.id, entry, guest, Some(Vote::Up))
+        .await?;
+    store.set_vote(session.id, entry, guest, None).await?;
+
+    let queue = store.get_track_queue(session.id, 10).await?;
+    assert_eq!(queue[0].upvotes, 0);
+    Ok(())
+}
+
+pub async fn votes_are_scoped_to_sessions(store: &dyn PersistentStore) -> Result<()> {
+    let first = store.create_session("conformance").await?;
+    let second = store.create_session("conformance").await?;
+    queue_tracks(store, first.id, &["first"]).await?;
+    let entry = entry_id(&store.get_track_queue(first.id, 10).await?, "first");
+
+    let accepted = store
+        .set_vote(second.id, entry, Uuid::new_v4(), Some(Vote::Up))
+        .await?;
+    assert!(!accepted);
+    Ok(())
+}
+
+pub async fn guests_round_trip(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    let guest = store.create_guest(session.id).await?;
+    assert_eq!(guest.session_id, session.id);
+
+    assert_eq!(store.get_guest(guest.id).await?, Some(guest));
+    assert!(store.get_guest(Uuid::new_v4()).await?.is_none());
+    Ok(())
+}
+
+pub async fn queued_tracks_are_counted_per_guest(
+    store: &dyn PersistentStore,
+) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    let guest = store.create_guest(session.id).await?;
+    let other = store.create_guest(session.id).await?;
+
+    for name in ["first", "second"] {
+        store
+            .add_track_to_queue(session.id, example_track(name), Some(guest.id))
+            .await?;
+    }
+    store
+        .add_track_to_queue(session.id, example_track("third"), Some(other.id))
+        .await?;
+    queue_tracks(store, session.id, &["anonymous"]).await?;
+
+    assert_eq!(store.count_queued_by_guest(session.id, guest.id).await?, 2);
+    assert_eq!(store.count_queued_by_guest(session.id, other.id).await?, 1);
+
+    store.pop_track_from_queue(session.id).await?;
+    assert_eq!(store.count_queued_by_guest(session.id, guest.id).await?, 1);
+    Ok(())
+}
+
+pub async fn queue_entries_can_be_looked_up(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    let other = store.create_session("conformance").await?;
+    let guest = store.create_guest(session.id).await?;
+    store
+        .add_track_to_queue(session.id, example_track("mine"), Some(guest.id))
+        .await?;
+    queue_tracks(store, session.id, &["anonymous"]).await?;
+
+    let queue = store.get_track_queue(session.id, 10).await?;
+    let entry = store
+        .get_queue_entry(session.id, entry_id(&queue, "mine"))
+        .await?
+        .unwrap();
+    assert_eq!(entry.track.name, "mine");
+    assert_eq!(entry.track.artists.len(), 2);
+    assert_eq!(entry.added_by, Some(guest.id));
+    assert_eq!(queue[0].added_by, Some(guest.id));
+
+    let anonymous = entry_id(&queue, "anonymous");
+    let entry = store.get_queue_entry(session.id, anonymous).await?.unwrap();
+    assert!(entry.added_by.is_none());
+
+    assert!(store.get_queue_entry(other.id, anonymous).await?.is_none());
+    assert!(store.get_queue_entry(session.id, -1).await?.is_none());
+    Ok(())
+}
+
+pub async fn queue_entries_can_be_removed(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    let other = store.create_session("conformance").await?;
+    queue_tracks(store, session.id, &["first", "second", "third"]).await?;
+    let queue = store.get_track_queue(session.id, 10).await?;
+    let second = entry_id(&queue, "second");
+    store
+        .set_vote(session.id, second, Uuid::new_v4(), Some(Vote::Up))
+        .await?;
+
+    assert!(!store.remove_queue_entry(other.id, second).await?);
+    assert!(store.remove_queue_entry(session.id, second).await?);
+    assert!(!store.remove_queue_entry(session.id, second).await?);
+
+    let queue = store.get_track_queue(session.id, 10).await?;
+    assert_eq!(names(&queue), vec!["first", "third"]);
+    Ok(())
+}
+
+pub async fn moved_entries_keep_their_place(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    queue_tracks(store, session.id, &["first", "second", "third", "fourth"]).await?;
+    let queue = store.get_track_queue(session.id, 10).await?;
+
+    assert!(
+        store
+            .move_queue_entry(session.id, entry_id(&queue, "third"), 0)
+            .await?
+    );
+    let moved = store.get_track_queue(session.id, 10).await?;
+    assert_eq!(names(&moved), vec!["third", "first", "second", "fourth"]);
+
+    //votes still order the entries behind the moved one
+    store
+        .set_vote(
+            session.id,
+            entry_id(&queue, "fourth"),
+            Uuid::new_v4(),
+            Some(Vote::Up),
+        )
+        .await?;
+    let voted = store.get_track_queue(session.id, 10).await?;
+    assert_eq!(names(&voted), vec!["third", "fourth", "first", "second"]);
+
+    //but can't move anything ahead of an entry the host has placed
+    assert!(
+        store
+            .move_queue_entry(session.id, entry_id(&queue, "second"), 1)
+            .await?
+    );
+    store
+        .set_vote(
+            session.id,
+            entry_id(&queue, "first"),
+            Uuid::new_v4(),
+            Some(Vote::Up),
+        )
+        .await?;
+    let placed = store.get_track_queue(session.id, 10).await?;
+    assert_eq!(names(&placed), vec!["third", "second", "first", "fourth"]);
+
+    let popped = store.pop_track_from_queue(session.id).await?.unwrap();
+    assert_eq!(popped.track.name, "third");
+    let remaining = store.get_track_queue(session.id, 10).await?;
+    assert_eq!(names(&remaining), vec!["second", "first", "fourth"]);
+
+    assert!(!store.move_queue_entry(session.id, -1, 0).await?);
+    Ok(())
+}
+
+pub async fn moves_are_clamped_to_the_queue(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    queue_tracks(store, session.id, &["first", "second", "third"]).await?;
+    let queue = store.get_track_queue(session.id, 10).await?;
+
+    assert!(
+        store
+            .move_queue_entry(session.id, entry_id(&queue, "first"), 100)
+            .await?
+    );
+    let moved = store.get_track_queue(session.id, 10).await?;
+    assert_eq!(names(&moved), vec!["second", "third", "first"]);
+    Ok(())
+}
+
+pub async fn clearing_empties_the_queue(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    let other = store.create_session("conformance").await?;
+    queue_tracks(store, session.id, &["first", "second"]).await?;
+    queue_tracks(store, other.id, &["theirs"]).await?;
+    let queue = store.get_track_queue(session.id, 10).await?;
+    store
+        .set_vote(
+            session.id,
+            entry_id(&queue, "first"),
+            Uuid::new_v4(),
+            Some(Vote::Down),
+        )
+        .await?;
+
+    assert_eq!(store.clear_queue(session.id).await?, 2);
+    assert!(store.get_track_queue(session.id, 10).await?.is_empty());
+    assert_eq!(store.clear_queue(session.id).await?, 0);
+
+    let theirs = store.get_track_queue(other.id, 10).await?;
+    assert_eq!(names(&theirs), vec!["theirs"]);
+    Ok(())
+}
+
+pub async fn played_tracks_are_recorded(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    queue_tracks(store, session.id, &["played"]).await?;
+
+    let popped = store.pop_track_from_queue(session.id).await?.unwrap();
+    store
+        .record_played_track(session.id, &popped.track.id, popped.requester())
+        .await?;
+    Ok(())
+}
+
+pub async fn popped_entries_remember_who_queued_them(
+    store: &dyn PersistentStore,
+) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    let guest = store.create_guest(session.id).await?;
+    store
+        .add_track_to_queue(session.id, example_track("requested"), Some(guest.id))
+        .await?;
+    let queue = store.get_track_queue(session.id, 10).await?;
+    store
+        .set_vote(session.id, queue[0].id, guest.id, Some(Vote::Up))
+        .await?;
+
+    let popped = store.pop_track_from_queue(session.id).await?.unwrap();
+    assert_eq!(popped.id, queue[0].id);
+    assert_eq!(popped.requester(), Requester::Guest(guest.id));
+    assert_eq!(popped.upvotes, 1);
+    assert_eq!(popped.track.artists.len(), 2);
+    Ok(())
+}
+
+pub async fn history_is_paged_latest_first(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    let other = store.create_session("conformance").await?;
+    let guest = store.create_guest(session.id).await?;
+    queue_tracks(store, session.id, &["first", "second", "third"]).await?;
+    queue_tracks(store, other.id, &["theirs"]).await?;
+
+    let requesters = [
+        Requester::Guest(guest.id),
+        Requester::Host,
+        Requester::Autofill,
+    ];
+    for requester in requesters {
+        let popped = store.pop_track_from_queue(session.id).await?.unwrap();
+        store
+            .record_played_track(session.id, &popped.track.id, requester)
+            .await?;
+    }
+    let theirs = store.pop_track_from_queue(other.id).await?.unwrap();
+    store
+        .record_played_track(other.id, &theirs.track.id, Requester::Host)
+        .await?;
+
+    let latest = store.play_history(session.id, None, 2).await?;
+    let latest_names: Vec<&str> = latest.iter().map(|p| &p.track.name[..]).collect();
+    assert_eq!(latest_names, vec!["third", "second"]);
+    assert_eq!(latest[0].requester, Requester::Autofill);
+    assert_eq!(latest[1].requester, Requester::Host);
+    assert_eq!(latest[0].track.artists.len(), 2);
+    assert!((Utc::now() - latest[0].played_at).num_seconds() < 60);
+
+    let older = store
+        .play_history(session.id, Some(latest[1].id), 2)
+        .await?;
+    assert_eq!(older.len(), 1);
+    assert_eq!(older[0].track.name, "first");
+    assert_eq!(older[0].requester, Requester::Guest(guest.id));
+
+    assert!(store
+        .play_history(session.id, Some(older[0].id), 2)
+        .await?
+        .is_empty());
+    Ok(())
+}
+
+pub async fn saved_tracks_can_be_played_without_queueing(
+    store: &dyn PersistentStore,
+) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    let track = example_track("filler");
+    store.save_track(&track).await?;
+    //saving again is harmless
+    store.save_track(&track).await?;
+    store
+        .record_played_track(session.id, &track.id, Requester::Autofill)
+        .await?;
+
+    assert!(store.get_track_queue(session.id, 10).await?.is_empty());
+    let history = store.play_history(session.id, None, 10).await?;
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].track.name, "filler");
+    assert_eq!(history[0].track.artists.len(), 2);
+    assert_eq!(history[0].requester, Requester::Autofill);
+    Ok(())
+}
+
+pub async fn skips_are_marked_on_the_latest_play(
+    store: &dyn PersistentStore,
+) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    let other = store.create_session("conformance").await?;
+    let track = example_track("skippable");
+    store.save_track(&track).await?;
+    for session_id in [session.id, session.id, other.id] {
+        store
+            .record_played_track(session_id, &track.id, Requester::Host)
+            .await?;
+    }
+
+    assert!(store.mark_play_skipped(session.id, &track.id).await?);
+    assert!(!store.mark_play_skipped(session.id, "never-played").await?);
+
+    let history = store.play_history(session.id, None, 10).await?;
+    let skipped: Vec<bool> = history.iter().map(|played| played.skipped).collect();
+    assert_eq!(skipped, vec![true, false]);
+    let theirs = store.play_history(other.id, None, 10).await?;
+    assert!(!theirs[0].skipped);
+    Ok(())
+}
+
+pub async fn recent_plays_are_listed_latest_first(
+    store: &dyn PersistentStore,
+) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    let other = store.create_session("conformance").await?;
+    queue_tracks(store, session.id, &["first", "second"]).await?;
+    queue_tracks(store, other.id, &["theirs"]).await?;
+
+    for session_id in [session.id, session.id, other.id] {
+        let popped = store.pop_track_from_queue(session_id).await?.unwrap();
+        store
+            .record_played_track(session_id, &popped.track.id, Requester::Host)
+            .await?;
+    }
+
+    let recent = store.recently_played(session.id, 10).await?;
+    let recent_names: Vec<&str> = recent.iter().map(|track| &track.name[..]).collect();
+    assert_eq!(recent_names, vec!["second", "first"]);
+    assert_eq!(recent[0].artists.len(), 2);
+
+    let latest = store.recently_played(session.id, 1).await?;
+    assert_eq!(latest.len(), 1);
+    assert_eq!(latest[0].name, "second");
+    Ok(())
+}
+
+pub async fn last_play_times_are_tracked(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    let other = store.create_session("conformance").await?;
+    queue_tracks(store, session.id, &["played", "waiting"]).await?;
+
+    let played = store.pop_track_from_queue(session.id).await?.unwrap().track;
+    store
+        .record_played_track(session.id, &played.id, Requester::Host)
+        .await?;
+    let waiting = store.get_track_queue(session.id, 10).await?;
+
+    let ago = store.last_played(session.id, &played.id).await?.unwrap();
+    assert!(ago < Duration::from_secs(60));
+    assert!(store
+        .last_played(session.id, &waiting[0].track.id)
+        .await?
+        .is_none());
+    assert!(store.last_played(other.id, &played.id).await?.is_none());
+    Ok(())
+}
+
+pub async fn sessions_round_trip(store: &dyn PersistentStore) -> Result<()> {
+    let mut session = store.create_session("conformance").await?;
+    let created = store.get_session(session.id).await?.unwrap();
+    assert_eq!(created.name, "conformance");
+    assert!(created.token.is_none());
+
+    let mut token = Token::default();
+    token.access_token = "access".to_owned();
+    token.refresh_token = Some("refresh".to_owned());
+    token.expires_at = Some(Utc::now());
+    session.token = Some(token);
+    store.update_session(&session).await?;
+
+    let updated = store.get_session(session.id).await?.unwrap();
+    let updated_token = updated.token.unwrap();
+    assert_eq!(updated_token.access_token, "access");
+    assert_eq!(updated_token.refresh_token.as_deref(), Some("refresh"));
+    Ok(())
+}
+
+pub async fn session_device_round_trips(store: &dyn PersistentStore) -> Result<()> {
+    let mut session = store.create_session("conformance").await?;
+    assert!(session.device_id.is_none());
+
+    store
+        .set_session_device(session.id, Some("speaker"))
+        .await?;
+    let stored = store.get_session(session.id).await?.unwrap();
+    assert_eq!(stored.device_id.as_deref(), Some("speaker"));
+
+    //saving the token doesn't touch the device
+    session.token = Some(Token::default());
+    session.token.as_mut().unwrap().access_token = "access".to_owned();
+    store.update_session(&session).await?;
+    let stored = store.get_session(session.id).await?.unwrap();
+    assert_eq!(stored.device_id.as_deref(), Some("speaker"));
+
+    store.set_session_device(session.id, None).await?;
+    assert!(store
+        .get_session(session.id)
+        .await?
+        .unwrap()
+        .device_id
+        .is_none());
+
+    assert!(store
+        .set_session_device(Uuid::new_v4(), Some("speaker"))
+        .await
+        .is_err());
+    Ok(())
+}
+
+pub async fn fallback_playlists_round_trip(store: &dyn PersistentStore) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    assert!(session.fallback_playlist.is_none());
+
+    store
+        .set_session_fallback_playlist(session.id, Some("party-mix"))
+        .await?;
+    store
+        .set_session_device(session.id, Some("speaker"))
+        .await?;
+    let stored = store.get_session(session.id).await?.unwrap();
+    assert_eq!(stored.fallback_playlist.as_deref(), Some("party-mix"));
+    assert_eq!(stored.device_id.as_deref(), Some("speaker"));
+
+    store
+        .set_session_fallback_playlist(session.id, None)
+        .await?;
+    assert!(store
+        .get_session(session.id)
+        .await?
+        .unwrap()
+        .fallback_playlist
+        .is_none());
+
+    assert!(store
+        .set_session_fallback_playlist(Uuid::new_v4(), Some("party-mix"))
+        .await
+        .is_err());
+    Ok(())
+}
+
+pub async fn host_tokens_round_trip(store: &dyn PersistentStore) -> Result<()> {
+    let first = store.create_session("conformance").await?;
+    let second = store.create_session("conformance").await?;
+    assert!(first.host_token.is_some());
+    assert_ne!(first.host_token, second.host_token);
+
+    let stored = store.get_session(first.id).await?.unwrap();
+    assert_eq!(stored.host_token, first.host_token);
+    Ok(())
+}
+
+pub async fn unauthenticated_session_update_fails(
+    store: &dyn PersistentStore,
+) -> Result<()> {
+    let session = store.create_session("conformance").await?;
+    assert!(store.update_session(&session).await.is_err());
+    Ok(())
+}
+
+pub async fn missing_session_is_none(store: &dyn PersistentStore) -> Result<()> {
+    assert!(store.get_session(Uuid::new_v4()).await?.is_none());
+    Ok(())
+}
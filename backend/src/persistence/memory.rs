@@ -0,0 +1,472 @@
+use std::{cmp::Ordering, collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use sqlx::types::chrono::{DateTime, Utc};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use super::{
+    migrations::SCHEMA_VERSION,
+    model::{
+        PlaySession, PlayedTrack, QueuedTrack, Requester, SessionGuest, SpotifyTrack,
+        Vote,
+    },
+    pinned_order, PersistentStore, Store,
+};
+
+///PersistentStore which keeps everything in process memory. Nothing survives a restart,
+/// so this is only meant for tests and for development without a database.
+#[derive(Default)]
+pub struct InMemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    tracks: HashMap<String, SpotifyTrack>,
+    queues: HashMap<Uuid, Vec<QueueEntry>>,
+    next_entry_id: i32,
+    ///Every play in every session, oldest first
+    played: Vec<Play>,
+    sessions: HashMap<Uuid, PlaySession>,
+    guests: HashMap<Uuid, SessionGuest>,
+}
+
+///Entries are kept in the order they were added
+struct QueueEntry {
+    id: i32,
+    track_id: String,
+    added_by: Option<Uuid>,
+    votes: HashMap<Uuid, Vote>,
+    ///Place the host pinned the entry at, if they did
+    position: Option<u32>,
+}
+
+impl QueueEntry {
+    fn score(&self) -> i32 {
+        self.votes.values().map(|vote| vote.value() as i32).sum()
+    }
+
+    fn count(&self, vote: Vote) -> u32 {
+        self.votes.values().filter(|v| **v == vote).count() as u32
+    }
+}
+
+struct Play {
+    id: i32,
+    session_id: Uuid,
+    track_id: String,
+    played_date: DateTime<Utc>,
+    requester: Requester,
+    skipped: bool,
+}
+
+impl InMemoryStore {
+    pub fn create() -> Store {
+        Arc::new(Self::default())
+    }
+}
+
+impl MemoryState {
+    fn track(&self, id: &str) -> Result<SpotifyTrack> {
+        self.tracks
+            .get(id)
+            .cloned()
+            .ok_or_else(|| anyhow::Error::msg(format!("no track with id {}", id)))
+    }
+
+    ///A session's queue in play order: pinned entries first, then highest score first,
+    /// then oldest first
+    fn ordered_queue(&self, session_id: Uuid) -> Vec<&QueueEntry> {
+        let mut entries: Vec<&QueueEntry> = match self.queues.get(&session_id) {
+            Some(queue) => queue.iter().collect(),
+            None => Vec::new(),
+        };
+        //sort_by is stable, so entries with equal scores keep their insertion order
+        entries.sort_by(|a, b| match (a.position, b.position) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => b.score().cmp(&a.score()),
+        });
+        entries
+    }
+
+    fn queued_track(&self, entry: &QueueEntry) -> Result<QueuedTrack> {
+        Ok(QueuedTrack {
+            id: entry.id,
+            track: self.track(&entry.track_id)?,
+            added_by: entry.added_by,
+            upvotes: entry.count(Vote::Up),
+            downvotes: entry.count(Vote::Down),
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl PersistentStore for InMemoryStore {
+    async fn schema_version(&self) -> Result<u32> {
+        Ok(SCHEMA_VERSION)
+    }
+
+    async fn migrate(&self) -> Result<u32> {
+        Ok(SCHEMA_VERSION)
+    }
+
+    async fn get_track_queue(
+        &self,
+        session_id: Uuid,
+        limit: u32,
+    ) -> Result<Vec<QueuedTrack>> {
+        let state = self.state.lock().await;
+
+        state
+            .ordered_queue(session_id)
+            .into_iter()
+            .take(limit as usize)
+            .map(|entry| state.queued_track(entry))
+            .collect()
+    }
+
+    async fn add_track_to_queue(
+        &self,
+        session_id: Uuid,
+        track: SpotifyTrack,
+        added_by: Option<Uuid>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().await;
+        let track_id = track.id.clone();
+        state.tracks.entry(track_id.clone()).or_insert(track);
+
+        state.next_entry_id += 1;
+        let entry = QueueEntry {
+            id: state.next_entry_id,
+            track_id: track_id,
+            added_by: added_by,
+            votes: HashMap::new(),
+            position: None,
+        };
+        state.queues.entry(session_id).or_default().push(entry);
+        Ok(())
+    }
+
+    async fn pop_track_from_queue(
+        &self,
+        session_id: Uuid,
+    ) -> Result<Option<QueuedTrack>> {
+        let mut state = self.state.lock().await;
+        let next = match state.ordered_queue(session_id).first() {
+            Some(entry) => state.queued_track(entry)?,
+            None => return Ok(None),
+        };
+
+        let queue = state.queues.get_mut(&session_id).unwrap();
+        queue.retain(|entry| entry.id != next.id);
+        Ok(Some(next))
+    }
+
+    async fn get_queue_entry(
+        &self,
+        session_id: Uuid,
+        entry_id: i32,
+    ) -> Result<Option<QueuedTrack>> {
+        let state = self.state.lock().await;
+        let entry = state
+            .queues
+            .get(&session_id)
+            .and_then(|queue| queue.iter().find(|entry| entry.id == entry_id));
+        entry.map(|entry| state.queued_track(entry)).transpose()
+    }
+
+    async fn remove_queue_entry(&self, session_id: Uuid, entry_id: i32) -> Result<bool> {
+        let mut state = self.state.lock().await;
+        let queue = match state.queues.get_mut(&session_id) {
+            Some(queue) => queue,
+            None => return Ok(false),
+        };
+        let before = queue.len();
+        queue.retain(|entry| entry.id != entry_id);
+        Ok(queue.len() < before)
+    }
+
+    async fn move_queue_entry(
+        &self,
+        session_id: Uuid,
+        entry_id: i32,
+        position: u32,
+    ) -> Result<bool> {
+        let mut state = self.state.lock().await;
+        let order: Vec<(i32, bool)> = state
+            .ordered_queue(session_id)
+            .iter()
+            .map(|entry| (entry.id, entry.position.is_some()))
+            .collect();
+        let pinned = match pinned_order(&order, entry_id, position) {
+            Some(pinned) => pinned,
+            None => return Ok(false),
+        };
+
+        let queue = state.queues.get_mut(&session_id).unwrap();
+        for entry in queue.iter_mut() {
+            if let Some(position) = pinned.iter().position(|id| *id == entry.id) {
+                entry.position = Some(position as u32);
+            }
+        }
+        Ok(true)
+    }
+
+    async fn clear_queue(&self, session_id: Uuid) -> Result<u32> {
+        let mut state = self.state.lock().await;
+        let removed = state.queues.remove(&session_id).unwrap_or_default();
+        Ok(removed.len() as u32)
+    }
+
+    async fn set_vote(
+        &self,
+        session_id: Uuid,
+        entry_id: i32,
+        guest_id: Uuid,
+        vote: Option<Vote>,
+    ) -> Result<bool> {
+        let mut state = self.state.lock().await;
+        let entry = state
+            .queues
+            .get_mut(&session_id)
+            .and_then(|queue| queue.iter_mut().find(|entry| entry.id == entry_id));
+
+        match entry {
+            Some(entry) => {
+                match vote {
+                    Some(vote) => entry.votes.insert(guest_id, vote),
+                    None => entry.votes.remove(&guest_id),
+                };
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn save_track(&self, track: &SpotifyTrack) -> Result<()> {
+        let mut state = self.state.lock().await;
+        state
+            .tracks
+            .entry(track.id.clone())
+            .or_insert_with(|| track.clone());
+        Ok(())
+    }
+
+    async fn record_played_track(
+        &self,
+        session_id: Uuid,
+        track_id: &str,
+        requester: Requester,
+    ) -> Result<()> {
+        let mut state = self.state.lock().await;
+        state.track(track_id)?;
+        let id = state.played.len() as i32 + 1;
+        state.played.push(Play {
+            id: id,
+            session_id: session_id,
+            track_id: track_id.to_owned(),
+            played_date: Utc::now(),
+            requester: requester,
+            skipped: false,
+        });
+        Ok(())
+    }
+
+    async fn mark_play_skipped(&self, session_id: Uuid, track_id: &str) -> Result<bool> {
+        let mut state = self.state.lock().await;
+        let latest = state.played.iter_mut().rev().find(|played| {
+            played.session_id == session_id && played.track_id == track_id
+        });
+        match latest {
+            Some(played) => {
+                played.skipped = true;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn last_played(
+        &self,
+        session_id: Uuid,
+        track_id: &str,
+    ) -> Result<Option<Duration>> {
+        let state = self.state.lock().await;
+        let last = state
+            .played
+            .iter()
+            .filter(|played| {
+                played.session_id == session_id && played.track_id == track_id
+            })
+            .map(|played| played.played_date)
+            .max();
+        Ok(last.map(|date| (Utc::now() - date).to_std().unwrap_or(Duration::ZERO)))
+    }
+
+    async fn recently_played(
+        &self,
+        session_id: Uuid,
+        limit: u32,
+    ) -> Result<Vec<SpotifyTrack>> {
+        let state = self.state.lock().await;
+        state
+            .played
+            .iter()
+            .rev()
+            .filter(|played| played.session_id == session_id)
+            .take(limit as usize)
+            .map(|played| state.track(&played.track_id))
+            .collect()
+    }
+
+    async fn play_history(
+        &self,
+        session_id: Uuid,
+        before: Option<i32>,
+        limit: u32,
+    ) -> Result<Vec<PlayedTrack>> {
+        let state = self.state.lock().await;
+        state
+            .played
+            .iter()
+            .rev()
+            .filter(|played| played.session_id == session_id)
+            .filter(|played| before.map_or(true, |before| played.id < before))
+            .take(limit as usize)
+            .map(|played| {
+                Ok(PlayedTrack {
+                    id: played.id,
+                    track: state.track(&played.track_id)?,
+                    played_at: played.played_date,
+                    requester: played.requester,
+                    skipped: played.skipped,
+                })
+            })
+            .collect()
+    }
+
+    async fn get_track_by_id(&self, id: &str) -> Result<SpotifyTrack> {
+        self.state.lock().await.track(id)
+    }
+
+    async fn create_guest(&self, session_id: Uuid) -> Result<SessionGuest> {
+        let guest = SessionGuest {
+            id: Uuid::new_v4(),
+            session_id: session_id,
+        };
+        self.state
+            .lock()
+            .await
+            .guests
+            .insert(guest.id, guest.clone());
+        Ok(guest)
+    }
+
+    async fn get_guest(&self, id: Uuid) -> Result<Option<SessionGuest>> {
+        Ok(self.state.lock().await.guests.get(&id).cloned())
+    }
+
+    async fn count_queued_by_guest(
+        &self,
+        session_id: Uuid,
+        guest_id: Uuid,
+    ) -> Result<u32> {
+        let state = self.state.lock().await;
+        let queued = match state.queues.get(&session_id) {
+            Some(queue) => queue
+                .iter()
+                .filter(|entry| entry.added_by == Some(guest_id))
+                .count(),
+            None => 0,
+        };
+        Ok(queued as u32)
+    }
+
+    async fn create_session(&self, name: &str) -> Result<PlaySession> {
+        let session = PlaySession {
+            id: Uuid::new_v4(),
+            name: name.to_owned(),
+            token: None,
+            device_id: None,
+            host_token: Some(PlaySession::new_host_token()),
+            fallback_playlist: None,
+        };
+        self.state
+            .lock()
+            .await
+            .sessions
+            .insert(session.id, session.clone());
+        Ok(session)
+    }
+
+    async fn update_session(&self, session: &PlaySession) -> Result<()> {
+        if let None = session.token {
+            return Err(anyhow::Error::msg(
+                "can't update database with unauthenticated session",
+            ));
+        }
+
+        let mut state = self.state.lock().await;
+        match state.sessions.get_mut(&session.id) {
+            Some(stored) => {
+                stored.name = session.name.clone();
+                stored.token = session.token.clone();
+                Ok(())
+            }
+            None => Err(anyhow::Error::msg(format!(
+                "no session with id {}",
+                session.id
+            ))),
+        }
+    }
+
+    async fn set_session_device(
+        &self,
+        session_id: Uuid,
+        device_id: Option<&str>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().await;
+        match state.sessions.get_mut(&session_id) {
+            Some(stored) => {
+                stored.device_id = device_id.map(|id| id.to_owned());
+                Ok(())
+            }
+            None => Err(anyhow::Error::msg(format!(
+                "no session with id {}",
+                session_id
+            ))),
+        }
+    }
+
+    async fn set_session_fallback_playlist(
+        &self,
+        session_id: Uuid,
+        playlist_id: Option<&str>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().await;
+        match state.sessions.get_mut(&session_id) {
+            Some(stored) => {
+                stored.fallback_playlist = playlist_id.map(|id| id.to_owned());
+                Ok(())
+            }
+            None => Err(anyhow::Error::msg(format!(
+                "no session with id {}",
+                session_id
+            ))),
+        }
+    }
+
+    async fn get_session(&self, id: Uuid) -> Result<Option<PlaySession>> {
+        Ok(self.state.lock().await.sessions.get(&id).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    persistent_store_tests!(async { InMemoryStore::default() });
+}
@@ -0,0 +1,112 @@
+use anyhow::Result;
+use thiserror::Error;
+
+use super::Store;
+
+///Schema version this build of DDJ expects. Every store implementation must provide
+/// migrations numbered 1 through this version.
+pub const SCHEMA_VERSION: u32 = 11;
+
+///A single forward-only change to a store's schema
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub statements: &'static [&'static str],
+}
+
+#[derive(Debug, Error)]
+pub enum MigrationError {
+    #[error(
+        "database schema is at version {found}, which is newer than the latest version \
+         ({supported}) this build understands"
+    )]
+    SchemaTooNew { found: u32, supported: u32 },
+
+    #[error(
+        "database schema is at version {found} but version {required} is required, \
+         run `backend migrate` to upgrade it"
+    )]
+    SchemaOutdated { found: u32, required: u32 },
+}
+
+///Migrations which still need to be applied to a store at version `current`
+pub fn pending(
+    migrations: &'static [Migration],
+    current: u32,
+) -> Result<&'static [Migration]> {
+    if current > SCHEMA_VERSION {
+        return Err(MigrationError::SchemaTooNew {
+            found: current,
+            supported: SCHEMA_VERSION,
+        }
+        .into());
+    }
+
+    Ok(&migrations[current as usize..])
+}
+
+///Make sure the store's schema matches this build before the server starts. Stores
+/// which are behind are migrated when `auto_migrate` is set, stores which are ahead
+/// are always refused.
+pub async fn prepare_store(store: &Store, auto_migrate: bool) -> Result<()> {
+    let current = store.schema_version().await?;
+    if current > SCHEMA_VERSION {
+        return Err(MigrationError::SchemaTooNew {
+            found: current,
+            supported: SCHEMA_VERSION,
+        }
+        .into());
+    }
+
+    if current < SCHEMA_VERSION {
+        if !auto_migrate {
+            return Err(MigrationError::SchemaOutdated {
+                found: current,
+                required: SCHEMA_VERSION,
+            }
+            .into());
+        }
+        store.migrate().await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_complete(migrations: &[Migration]) {
+        assert_eq!(migrations.len(), SCHEMA_VERSION as usize);
+        for (index, migration) in migrations.iter().enumerate() {
+            assert_eq!(migration.version, index as u32 + 1);
+        }
+    }
+
+    #[test]
+    fn postgres_migrations_are_complete() {
+        assert_complete(crate::persistence::pgsql::MIGRATIONS);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_migrations_are_complete() {
+        assert_complete(crate::persistence::sqlite::MIGRATIONS);
+    }
+
+    #[test]
+    fn newer_schema_is_refused() {
+        let res = pending(crate::persistence::pgsql::MIGRATIONS, SCHEMA_VERSION + 1);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn only_missing_migrations_are_pending() -> Result<()> {
+        let remaining = pending(crate::persistence::pgsql::MIGRATIONS, 1)?;
+        assert_eq!(remaining.first().map(|m| m.version), Some(2));
+        assert!(
+            pending(crate::persistence::pgsql::MIGRATIONS, SCHEMA_VERSION)?.is_empty()
+        );
+        Ok(())
+    }
+}
@@ -1,27 +1,199 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
 use uuid::Uuid;
 
-use self::model::{PlaySession, SpotifyTrack};
+use crate::config::DatabaseConfig;
 
+use self::model::{
+    PlaySession, PlayedTrack, QueuedTrack, Requester, SessionGuest, SpotifyTrack, Vote,
+};
+
+#[cfg(test)]
+#[macro_use]
+mod conformance;
+pub mod memory;
+pub mod migrations;
 pub mod model;
 pub mod pgsql;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 
 ///Trait abstracting storage requirements for DDJ
 #[rocket::async_trait]
 pub trait PersistentStore {
-    async fn create_tables(&self) -> Result<()>;
-    async fn get_track_queue(&self, limit: u32) -> Result<Vec<SpotifyTrack>>;
-    async fn add_track_to_queue(&self, track: SpotifyTrack) -> Result<()>;
-    async fn pop_track_from_queue(&self) -> Result<Option<SpotifyTrack>>;
+    ///Version of the schema the store is currently at, 0 if it has never been migrated
+    async fn schema_version(&self) -> Result<u32>;
+    ///Apply every migration the store is missing, returning the resulting version
+    async fn migrate(&self) -> Result<u32>;
+    ///Queued tracks for a session, in the order they will be played
+    async fn get_track_queue(
+        &self,
+        session_id: Uuid,
+        limit: u32,
+    ) -> Result<Vec<QueuedTrack>>;
+    ///Queue a track for a session, remembering which guest (if any) asked for it
+    async fn add_track_to_queue(
+        &self,
+        session_id: Uuid,
+        track: SpotifyTrack,
+        added_by: Option<Uuid>,
+    ) -> Result<()>;
+    ///Take the next entry to play out of a session's queue
+    async fn pop_track_from_queue(&self, session_id: Uuid)
+        -> Result<Option<QueuedTrack>>;
+    ///A single entry in a session's queue, None if it isn't queued in that session
+    async fn get_queue_entry(
+        &self,
+        session_id: Uuid,
+        entry_id: i32,
+    ) -> Result<Option<QueuedTrack>>;
+    ///Take an entry out of a session's queue. Returns false if it wasn't queued there
+    async fn remove_queue_entry(&self, session_id: Uuid, entry_id: i32) -> Result<bool>;
+    ///Move an entry to `position` in a session's queue, 0 being the next to play. The
+    /// entry and everything ahead of it stay in that order whatever the votes say, see
+    /// `pinned_order`. Returns false if the entry isn't queued there
+    async fn move_queue_entry(
+        &self,
+        session_id: Uuid,
+        entry_id: i32,
+        position: u32,
+    ) -> Result<bool>;
+    ///Remove every entry from a session's queue, returning how many there were
+    async fn clear_queue(&self, session_id: Uuid) -> Result<u32>;
+    ///Set or clear (with `None`) a guest's vote on a queue entry. Returns false if the
+    /// entry isn't in the session's queue
+    async fn set_vote(
+        &self,
+        session_id: Uuid,
+        entry_id: i32,
+        guest_id: Uuid,
+        vote: Option<Vote>,
+    ) -> Result<bool>;
+    ///Add a track to the catalog without queueing it, so plays of it can be recorded
+    async fn save_track(&self, track: &SpotifyTrack) -> Result<()>;
+    ///Add a track to a session's history as it is handed to the music provider
+    async fn record_played_track(
+        &self,
+        session_id: Uuid,
+        track_id: &str,
+        requester: Requester,
+    ) -> Result<()>;
+    ///Mark the latest play of a track in a session's history as skipped. False if the
+    /// session never played it.
+    async fn mark_play_skipped(&self, session_id: Uuid, track_id: &str) -> Result<bool>;
+    ///How long ago a track last played in a session, None if it never has
+    async fn last_played(
+        &self,
+        session_id: Uuid,
+        track_id: &str,
+    ) -> Result<Option<Duration>>;
+    ///The tracks a session played most recently, the latest first
+    async fn recently_played(
+        &self,
+        session_id: Uuid,
+        limit: u32,
+    ) -> Result<Vec<SpotifyTrack>>;
+    ///A page of a session's history, the latest first. Only plays older than the play
+    /// `before` are included when it is given.
+    async fn play_history(
+        &self,
+        session_id: Uuid,
+        before: Option<i32>,
+        limit: u32,
+    ) -> Result<Vec<PlayedTrack>>;
     async fn get_track_by_id(&self, id: &str) -> Result<SpotifyTrack>;
+    async fn create_guest(&self, session_id: Uuid) -> Result<SessionGuest>;
+    async fn get_guest(&self, id: Uuid) -> Result<Option<SessionGuest>>;
+    ///Number of tracks a guest currently has waiting in a session's queue
+    async fn count_queued_by_guest(
+        &self,
+        session_id: Uuid,
+        guest_id: Uuid,
+    ) -> Result<u32>;
     async fn create_session(&self, name: &str) -> Result<PlaySession>;
+    ///Save a session's name and token. The device and fallback playlist are left alone,
+    /// they are set with `set_session_device` and `set_session_fallback_playlist`
     async fn update_session(&self, session: &PlaySession) -> Result<()>;
+    ///Remember (or forget, with `None`) the device a session plays on
+    async fn set_session_device(
+        &self,
+        session_id: Uuid,
+        device_id: Option<&str>,
+    ) -> Result<()>;
+    ///Remember (or forget, with `None`) the playlist a session falls back on
+    async fn set_session_fallback_playlist(
+        &self,
+        session_id: Uuid,
+        playlist_id: Option<&str>,
+    ) -> Result<()>;
     async fn get_session(&self, id: Uuid) -> Result<Option<PlaySession>>;
 }
 
+///Work out which entries end up pinned when the host moves `entry_id` to `position`.
+/// `order` is the queue in play order along with whether each entry is already pinned,
+/// pinned entries always make up the front of the queue. Everything up to and including
+/// the moved entry is pinned, and so is anything which was pinned before. Returns the
+/// pinned entries in order, or None if `entry_id` isn't in the queue.
+pub fn pinned_order(
+    order: &[(i32, bool)],
+    entry_id: i32,
+    position: u32,
+) -> Option<Vec<i32>> {
+    let current = order.iter().position(|(id, _)| *id == entry_id)?;
+    let pinned_before = order
+        .iter()
+        .filter(|(id, pinned)| *pinned && *id != entry_id)
+        .count();
+
+    let mut ids: Vec<i32> = order.iter().map(|(id, _)| *id).collect();
+    ids.remove(current);
+    let position = (position as usize).min(ids.len());
+    ids.insert(position, entry_id);
+
+    ids.truncate(pinned_before.max(position) + 1);
+    Some(ids)
+}
+
 ///Literally a Box<dyn PersistentStore + Send + Sync>
 /// Using this type allows the database implementation to be
 /// swapped at runtime
 pub type Store = Arc<dyn PersistentStore + Send + Sync>;
+
+///Connect to the store the configuration describes
+pub async fn connect(config: &DatabaseConfig) -> Result<Store> {
+    connect_url(&config.connection_url(), config.pool_size).await
+}
+
+///Connect to the store described by a database url, with at most `pool_size`
+/// connections. `postgres://` and `sqlite:` urls are supported, as well as `memory` for
+/// the in-memory store.
+pub async fn connect_url(url: &str, pool_size: u32) -> Result<Store> {
+    if url == "memory" {
+        println!("using in-memory store, nothing will be persisted");
+        return Ok(memory::InMemoryStore::create());
+    }
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        return pgsql::PostgressDatabase::connect_to(url, pool_size).await;
+    }
+    if url.starts_with("sqlite:") {
+        return connect_sqlite(url, pool_size).await;
+    }
+
+    Err(anyhow::Error::msg(format!(
+        "unsupported database url {}",
+        url
+    )))
+}
+
+#[cfg(feature = "sqlite")]
+async fn connect_sqlite(url: &str, pool_size: u32) -> Result<Store> {
+    sqlite::SqliteDatabase::connect(url, pool_size).await
+}
+
+#[cfg(not(feature = "sqlite"))]
+async fn connect_sqlite(_url: &str, _pool_size: u32) -> Result<Store> {
+    Err(anyhow::Error::msg(
+        "sqlite support was not compiled in, rebuild with --features sqlite",
+    ))
+}
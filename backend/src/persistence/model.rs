@@ -2,6 +2,7 @@ use std::time::Duration;
 
 use rspotify::Token;
 use serde::{Deserialize, Serialize};
+use sqlx::types::chrono::{DateTime, Utc};
 use uuid::Uuid;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -10,17 +11,127 @@ pub struct SpotifyTrack {
     pub name: String,
     pub duration: Duration,
     pub album: SpotifyAlbum,
+    pub artists: Vec<SpotifyArtist>,
+}
+
+///A track waiting in a session's queue, along with its votes
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QueuedTrack {
+    pub id: i32,
+    pub track: SpotifyTrack,
+    ///Guest who queued the track, if it was queued by a guest
+    pub added_by: Option<Uuid>,
+    pub upvotes: u32,
+    pub downvotes: u32,
+}
+
+impl QueuedTrack {
+    ///Who to credit when the track plays
+    pub fn requester(&self) -> Requester {
+        match self.added_by {
+            Some(guest_id) => Requester::Guest(guest_id),
+            None => Requester::Host,
+        }
+    }
+}
+
+///Who a played track was played for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Requester {
+    ///A guest queued it
+    Guest(Uuid),
+    ///It was queued without a guest, by the host
+    Host,
+    ///The player picked it because the queue ran dry
+    Autofill,
+}
+
+impl Requester {
+    ///Rebuild a requester from the columns it is stored in
+    pub fn from_columns(guest_id: Option<Uuid>, autofill: bool) -> Requester {
+        match (guest_id, autofill) {
+            (_, true) => Requester::Autofill,
+            (Some(guest_id), false) => Requester::Guest(guest_id),
+            (None, false) => Requester::Host,
+        }
+    }
+
+    pub fn guest_id(&self) -> Option<Uuid> {
+        match self {
+            Requester::Guest(guest_id) => Some(*guest_id),
+            _ => None,
+        }
+    }
+
+    pub fn is_autofill(&self) -> bool {
+        *self == Requester::Autofill
+    }
+}
+
+///A track a session has played, as kept in its history
+#[derive(Clone, Debug)]
+pub struct PlayedTrack {
+    pub id: i32,
+    pub track: SpotifyTrack,
+    pub played_at: DateTime<Utc>,
+    pub requester: Requester,
+    ///Whether it was left well before its end
+    pub skipped: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Vote {
+    Up,
+    Down,
+}
+
+impl Vote {
+    pub fn value(&self) -> i16 {
+        match self {
+            Vote::Up => 1,
+            Vote::Down => -1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpotifyArtist {
+    pub id: String,
+    pub name: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SpotifyAlbum {
     pub name: String,
     pub id: String,
-    pub cover_image_url: String,
+    pub cover_image_url: Option<String>,
+}
+
+///Anonymous participant in a single PlaySession
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionGuest {
+    pub id: Uuid,
+    pub session_id: Uuid,
 }
 
+#[derive(Clone)]
 pub struct PlaySession {
     pub id: Uuid,
     pub name: String,
     pub token: Option<Token>,
+    ///Device the host picked to play the session on
+    pub device_id: Option<String>,
+    ///Secret handed to whoever created the session, needed to control playback.
+    /// Sessions created before host tokens existed have none.
+    pub host_token: Option<String>,
+    ///Playlist the player falls back on when the queue runs dry, instead of
+    /// recommendations based on what has played
+    pub fallback_playlist: Option<String>,
+}
+
+impl PlaySession {
+    ///Create a random host token for a new session
+    pub fn new_host_token() -> String {
+        Uuid::new_v4().simple().to_string()
+    }
 }
@@ -1,6 +1,5 @@
 use std::{
     collections::{HashMap, HashSet},
-    env,
     marker::PhantomData,
     ops::{Deref, DerefMut},
     sync::Arc,
@@ -10,47 +9,113 @@ use std::{
 use rspotify::Token;
 use sqlx::{
     postgres::{PgPoolOptions, PgRow},
-    types::chrono::{self, DateTime, Utc},
+    types::chrono::{self, DateTime, NaiveDateTime, Utc},
     Database, Executor, Pool, Postgres, Row, Transaction,
 };
 use uuid::Uuid;
 
 use crate::{
     authentication::scopes,
-    persistence::model::{PlaySession, SpotifyAlbum},
+    persistence::model::{
+        PlaySession, PlayedTrack, QueuedTrack, Requester, SessionGuest, SpotifyAlbum,
+        SpotifyArtist, Vote,
+    },
 };
 
-use super::{model::SpotifyTrack, PersistentStore, Store};
+use super::{
+    migrations::{self, Migration},
+    model::SpotifyTrack,
+    pinned_order, PersistentStore, Store,
+};
 
 use anyhow::Result;
 
-macro_rules! create_table {
-    ($query:expr, $executor:expr) => {{
-        let res = sqlx::query($query).execute($executor).await;
-        if let Err(e) = &res {
-            eprintln!("failed to run {}: {}", $query, e);
-        } else {
-            println!("successfully ran {}", stringify!($query));
-        }
-        res
-    }};
-}
+pub(super) const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        statements: &[
+            queries::CREATE_SESSION_TABLE,
+            queries::CREATE_ALUBMS_TABLE,
+            queries::CREATE_ARTIST_TABLE,
+            queries::CREATE_TRACKS_TABLE,
+            queries::CREATE_PLAYED_TRACKS_TABLE,
+            queries::CREATE_TRACK_QUEUE_TABLE,
+            queries::CREATE_ARTIST_TO_TRACK_TABLE,
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "scope queued and played tracks to sessions",
+        statements: &[
+            queries::ADD_SESSION_TO_QUEUED_TRACKS,
+            queries::ADD_SESSION_TO_PLAYED_TRACKS,
+            queries::DEFAULT_PLAYED_DATE,
+            queries::TRACK_DURATION_TO_MILLIS,
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "keep the order of a track's artists",
+        statements: &[queries::ADD_POSITION_TO_ARTIST_TO_TRACK],
+    },
+    Migration {
+        version: 4,
+        description: "votes on queued tracks",
+        statements: &[queries::CREATE_VOTES_TABLE],
+    },
+    Migration {
+        version: 5,
+        description: "guests and who queued each track",
+        statements: &[
+            queries::CREATE_GUESTS_TABLE,
+            queries::ADD_GUEST_TO_QUEUED_TRACKS,
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "remember each session's playback device",
+        statements: &[queries::ADD_DEVICE_TO_SESSIONS],
+    },
+    Migration {
+        version: 7,
+        description: "host tokens for sessions",
+        statements: &[queries::ADD_HOST_TOKEN_TO_SESSIONS],
+    },
+    Migration {
+        version: 8,
+        description: "queue positions set by the host",
+        statements: &[queries::ADD_POSITION_TO_QUEUED_TRACKS],
+    },
+    Migration {
+        version: 9,
+        description: "fallback playlists for sessions",
+        statements: &[queries::ADD_FALLBACK_PLAYLIST_TO_SESSIONS],
+    },
+    Migration {
+        version: 10,
+        description: "who each played track was played for",
+        statements: &[
+            queries::ADD_REQUESTER_TO_PLAYED_TRACKS,
+            queries::ADD_AUTOFILL_TO_PLAYED_TRACKS,
+        ],
+    },
+    Migration {
+        version: 11,
+        description: "whether each played track was skipped",
+        statements: &[queries::ADD_SKIPPED_TO_PLAYED_TRACKS],
+    },
+];
 
 pub struct PostgressDatabase {
     executor: Pool<Postgres>,
 }
 
 impl PostgressDatabase {
-    async fn new<'c>() -> Result<Self> {
-        let hostname = env::var("POSTGRES_HOST")?;
-        let user = env::var("POSTGRES_USER")?;
-        let password = env::var("POSTGRES_PASSWORD")?;
-
-        let connection_str = format!("postgres://{user}:{password}@{hostname}/ddj");
-
+    async fn with_url(url: &str, pool_size: u32) -> Result<Self> {
         let conn_pool = PgPoolOptions::new()
-            .max_connections(5)
-            .connect(&connection_str)
+            .max_connections(pool_size)
+            .connect(url)
             .await?;
 
         Ok(Self {
@@ -58,87 +123,215 @@ impl PostgressDatabase {
         })
     }
 
-    pub async fn connect() -> Result<Store> {
-        let db = Self::new().await?;
+    pub async fn connect_to(url: &str, pool_size: u32) -> Result<Store> {
+        let db = Self::with_url(url, pool_size).await?;
         Ok(Arc::new(db))
     }
+
+    ///Add a track, its album and its artists to the catalog unless they are already
+    /// there
+    async fn insert_track(
+        tx: &mut Transaction<'_, Postgres>,
+        track: &SpotifyTrack,
+    ) -> Result<()> {
+        const INSERT_TRACK_QUERY: &str = "
+            INSERT INTO tracks (id, name, album_id, duration)
+                VALUES ($1, $2, $3, $4)
+            ON CONFLICT DO NOTHING;
+        ";
+        const INSERT_ALBUM_QUERY: &str = "
+            INSERT INTO albums (id, name, cover_image_url)
+                VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING;
+        ";
+        const INSERT_ARTIST_QUERY: &str = "
+            INSERT INTO artists (id, name)
+                VALUES ($1, $2)
+            ON CONFLICT DO NOTHING;
+        ";
+        const INSERT_ARTIST_TO_TRACK_QUERY: &str = "
+            INSERT INTO artist_to_track (track_id, artist_id, position)
+                VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING;
+        ";
+
+        sqlx::query(INSERT_ALBUM_QUERY)
+            .bind(&track.album.id)
+            .bind(&track.album.name)
+            .bind(&track.album.cover_image_url)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(INSERT_TRACK_QUERY)
+            .bind(&track.id)
+            .bind(&track.name)
+            .bind(&track.album.id)
+            .bind(track.duration.as_millis() as i64)
+            .execute(&mut *tx)
+            .await?;
+
+        for (position, artist) in track.artists.iter().enumerate() {
+            sqlx::query(INSERT_ARTIST_QUERY)
+                .bind(&artist.id)
+                .bind(&artist.name)
+                .execute(&mut *tx)
+                .await?;
+
+            sqlx::query(INSERT_ARTIST_TO_TRACK_QUERY)
+                .bind(&track.id)
+                .bind(&artist.id)
+                .bind(position as i32)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    ///Fill in the artists of tracks read from the database
+    async fn attach_artists(&self, tracks: &mut [&mut SpotifyTrack]) -> Result<()> {
+        const QUERY: &str = "
+            SELECT
+                artist_to_track.track_id    AS track_id,
+                artists.id                  AS artist_id,
+                artists.name                AS artist_name
+            FROM artist_to_track
+            JOIN artists ON artist_to_track.artist_id = artists.id
+            WHERE artist_to_track.track_id = ANY($1)
+            ORDER BY artist_to_track.position ASC;
+        ";
+
+        let track_ids: Vec<String> =
+            tracks.iter().map(|track| track.id.clone()).collect();
+        let rows = sqlx::query(QUERY)
+            .bind(&track_ids)
+            .fetch_all(&self.executor)
+            .await?;
+
+        for row in rows {
+            let track_id: String = row.try_get("track_id")?;
+            let artist = SpotifyArtist {
+                id: row.try_get("artist_id")?,
+                name: row.try_get("artist_name")?,
+            };
+            for track in tracks.iter_mut().filter(|track| track.id == track_id) {
+                track.artists.push(artist.clone());
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[rocket::async_trait]
 impl PersistentStore for PostgressDatabase {
-    async fn create_tables(&self) -> Result<()> {
-        create_table!(queries::CREATE_SESSION_TABLE, &self.executor)?;
-        create_table!(queries::CREATE_ALUBMS_TABLE, &self.executor)?;
-        create_table!(queries::CREATE_ARTIST_TABLE, &self.executor)?;
-        create_table!(queries::CREATE_TRACKS_TABLE, &self.executor)?;
-        create_table!(queries::CREATE_PLAYED_TRACKS_TABLE, &self.executor)?;
-        create_table!(queries::CREATE_TRACK_QUEUE_TABLE, &self.executor)?;
-        create_table!(queries::CREATE_ARTIST_TO_TRACK_TABLE, &self.executor)?;
+    async fn schema_version(&self) -> Result<u32> {
+        const QUERY: &str =
+            "SELECT COALESCE(MAX(version), 0) AS version FROM schema_version;";
 
-        Ok(())
+        sqlx::query(queries::CREATE_SCHEMA_VERSION_TABLE)
+            .execute(&self.executor)
+            .await?;
+        let row = sqlx::query(QUERY).fetch_one(&self.executor).await?;
+        let version: i32 = row.try_get("version")?;
+
+        Ok(version as u32)
     }
 
-    async fn get_track_queue(&self, limit: u32) -> Result<Vec<SpotifyTrack>> {
+    async fn migrate(&self) -> Result<u32> {
+        const RECORD_VERSION_QUERY: &str = "
+            INSERT INTO schema_version (version, description)
+                VALUES ($1, $2);
+        ";
+
+        let current = self.schema_version().await?;
+        for migration in migrations::pending(MIGRATIONS, current)? {
+            let mut tx = self.executor.begin().await?;
+            for statement in migration.statements {
+                sqlx::query(statement).execute(&mut tx).await?;
+            }
+            sqlx::query(RECORD_VERSION_QUERY)
+                .bind(migration.version as i32)
+                .bind(migration.description)
+                .execute(&mut tx)
+                .await?;
+            tx.commit().await?;
+
+            println!(
+                "applied migration {}: {}",
+                migration.version, migration.description
+            );
+        }
+
+        self.schema_version().await
+    }
+
+    async fn get_track_queue(
+        &self,
+        session_id: Uuid,
+        limit: u32,
+    ) -> Result<Vec<QueuedTrack>> {
         const QUERY: &str = "
             SELECT
-                queued_tracks.track_id  AS track_id, 
-                tracks.name             AS track_name, 
-                tracks.duration         AS track_dur, 
-                tracks.album_id         AS album_id, 
-                albums.name             AS album_name, 
-                albums.cover_image_url  AS album_image
+                queued_tracks.id        AS entry_id,
+                queued_tracks.track_id  AS track_id,
+                queued_tracks.added_by  AS added_by,
+                tracks.name             AS track_name,
+                tracks.duration         AS track_dur,
+                tracks.album_id         AS album_id,
+                albums.name             AS album_name,
+                albums.cover_image_url  AS album_image,
+                SUM(CASE WHEN votes.value > 0 THEN 1 ELSE 0 END) AS upvotes,
+                SUM(CASE WHEN votes.value < 0 THEN 1 ELSE 0 END) AS downvotes
             FROM queued_tracks
             LEFT JOIN tracks ON queued_tracks.track_id = tracks.id
             LEFT JOIN albums ON tracks.album_id = albums.id
-            ORDER BY queued_tracks.added_date DESC 
-            LIMIT ($1);";
+            LEFT JOIN votes ON votes.queue_entry_id = queued_tracks.id
+            WHERE queued_tracks.session_id = $1
+            GROUP BY queued_tracks.id, tracks.id, albums.id
+            ORDER BY
+                queued_tracks.position IS NULL,
+                queued_tracks.position ASC,
+                COALESCE(SUM(votes.value), 0) DESC,
+                queued_tracks.added_date ASC,
+                queued_tracks.id ASC
+            LIMIT ($2);";
 
         let result = sqlx::query(QUERY)
+            .bind(&session_id)
             .bind(limit as i32)
             .fetch_all(&self.executor)
             .await?;
 
-        result
+        let mut entries = result
             .into_iter()
-            .map(|row| extract_track_from_row(&row))
-            .collect()
+            .map(|row| extract_queued_track_from_row(&row))
+            .collect::<Result<Vec<QueuedTrack>>>()?;
+        let mut tracks: Vec<&mut SpotifyTrack> =
+            entries.iter_mut().map(|entry| &mut entry.track).collect();
+        self.attach_artists(&mut tracks).await?;
+
+        Ok(entries)
     }
 
-    async fn add_track_to_queue(&self, track: SpotifyTrack) -> Result<()> {
-        const INSERT_TRACK_QUERY: &str = "
-            INSERT INTO tracks (id, name, album_id, duration)
-                VALUES ($1, $2, $3, $4)
-            ON CONFLICT DO NOTHING;
-        ";
-        const INSERT_ALBUM_QUERY: &str = "
-            INSERT INTO albums (id, name, cover_image_url)
-                VALUES ($1, $2, $2)
-            ON CONFLICT DO NOTHING;
-        ";
+    async fn add_track_to_queue(
+        &self,
+        session_id: Uuid,
+        track: SpotifyTrack,
+        added_by: Option<Uuid>,
+    ) -> Result<()> {
         const INSERT_QUEUED_QUERY: &str = "
-            INSERT INTO queued_tracks (track_id)
-                VALUES ($1)
+            INSERT INTO queued_tracks (session_id, track_id, added_by)
+                VALUES ($1, $2, $3)
         ";
 
         let mut tx = self.executor.begin().await?;
-
-        sqlx::query(INSERT_ALBUM_QUERY)
-            .bind(&track.album.id)
-            .bind(&track.album.name)
-            .bind(&track.album.cover_image_url)
-            .execute(&mut tx)
-            .await?;
-
-        sqlx::query(INSERT_TRACK_QUERY)
-            .bind(&track.id)
-            .bind(&track.name)
-            .bind(&track.album.id)
-            .bind(track.duration.as_secs() as i64)
-            .execute(&mut tx)
-            .await?;
+        Self::insert_track(&mut tx, &track).await?;
 
         sqlx::query(INSERT_QUEUED_QUERY)
+            .bind(&session_id)
             .bind(&track.id)
+            .bind(&added_by)
             .execute(&mut tx)
             .await?;
 
@@ -147,18 +340,25 @@ impl PersistentStore for PostgressDatabase {
         Ok(())
     }
 
+    async fn save_track(&self, track: &SpotifyTrack) -> Result<()> {
+        let mut tx = self.executor.begin().await?;
+        Self::insert_track(&mut tx, track).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
     async fn get_track_by_id(&self, id: &str) -> Result<SpotifyTrack> {
         const QUERY: &str = "
             SELECT 
-                tracks.track_id         AS track_id, 
+                tracks.id               AS track_id, 
                 tracks.name             AS track_name, 
                 tracks.duration         AS track_dur, 
                 tracks.album_id         AS album_id, 
                 albums.name             AS album_name, 
                 albums.cover_image_url  AS album_image 
             FROM tracks
-            LEFT JOIN albums ON track.album_id = album.id
-            WHERE tracks.track_id = $1
+            LEFT JOIN albums ON tracks.album_id = albums.id
+            WHERE tracks.id = $1
             LIMIT 1;
         ";
 
@@ -167,18 +367,41 @@ impl PersistentStore for PostgressDatabase {
             .fetch_one(&self.executor)
             .await?;
 
-        extract_track_from_row(&result)
+        let mut track = extract_track_from_row(&result)?;
+        self.attach_artists(&mut [&mut track]).await?;
+
+        Ok(track)
     }
 
-    async fn pop_track_from_queue(&self) -> Result<Option<SpotifyTrack>> {
+    async fn pop_track_from_queue(
+        &self,
+        session_id: Uuid,
+    ) -> Result<Option<QueuedTrack>> {
         const GET_NEXT_TRACK_QUERY: &str = "
-            SELECT id FROM queued_tracks ORDER BY added_date DESC LIMIT 1;
+            SELECT
+                queued_tracks.id,
+                queued_tracks.track_id,
+                queued_tracks.added_by,
+                SUM(CASE WHEN votes.value > 0 THEN 1 ELSE 0 END) AS upvotes,
+                SUM(CASE WHEN votes.value < 0 THEN 1 ELSE 0 END) AS downvotes
+            FROM queued_tracks
+            LEFT JOIN votes ON votes.queue_entry_id = queued_tracks.id
+            WHERE queued_tracks.session_id = $1
+            GROUP BY queued_tracks.id
+            ORDER BY
+                queued_tracks.position IS NULL,
+                queued_tracks.position ASC,
+                COALESCE(SUM(votes.value), 0) DESC,
+                queued_tracks.added_date ASC,
+                queued_tracks.id ASC
+            LIMIT 1;
         ";
         const REMOVE_AND_RETURN_QUERY: &str = "DELETE FROM queued_tracks WHERE id = $1;";
 
         let mut tx = self.executor.begin().await?;
 
         let result = sqlx::query(GET_NEXT_TRACK_QUERY)
+            .bind(&session_id)
             .fetch_optional(&mut tx)
             .await?;
 
@@ -186,18 +409,28 @@ impl PersistentStore for PostgressDatabase {
             return Ok(None);
         }
         let unwrapped_res = result.unwrap();
-        let target_id: &str = unwrapped_res.try_get("id")?;
+        let entry_id: i32 = unwrapped_res.try_get("id")?;
+        let target_id: String = unwrapped_res.try_get("track_id")?;
+        let added_by: Option<Uuid> = unwrapped_res.try_get("added_by")?;
+        let upvotes: i64 = unwrapped_res.try_get("upvotes")?;
+        let downvotes: i64 = unwrapped_res.try_get("downvotes")?;
 
         sqlx::query(REMOVE_AND_RETURN_QUERY)
-            .bind(target_id)
+            .bind(entry_id)
             .execute(&mut tx)
             .await?;
 
         tx.commit().await?;
 
-        let track = self.get_track_by_id(target_id).await;
+        let track = self.get_track_by_id(&target_id).await;
         match track {
-            Ok(t) => Ok(Some(t)),
+            Ok(t) => Ok(Some(QueuedTrack {
+                id: entry_id,
+                track: t,
+                added_by: added_by,
+                upvotes: upvotes as u32,
+                downvotes: downvotes as u32,
+            })),
             Err(e) => Err(anyhow::Error::msg(format!(
                 "failed to retrieve track from db: {}",
                 e
@@ -205,15 +438,408 @@ impl PersistentStore for PostgressDatabase {
         }
     }
 
+    async fn get_queue_entry(
+        &self,
+        session_id: Uuid,
+        entry_id: i32,
+    ) -> Result<Option<QueuedTrack>> {
+        const QUERY: &str = "
+            SELECT
+                queued_tracks.id        AS entry_id,
+                queued_tracks.track_id  AS track_id,
+                queued_tracks.added_by  AS added_by,
+                tracks.name             AS track_name,
+                tracks.duration         AS track_dur,
+                tracks.album_id         AS album_id,
+                albums.name             AS album_name,
+                albums.cover_image_url  AS album_image,
+                SUM(CASE WHEN votes.value > 0 THEN 1 ELSE 0 END) AS upvotes,
+                SUM(CASE WHEN votes.value < 0 THEN 1 ELSE 0 END) AS downvotes
+            FROM queued_tracks
+            LEFT JOIN tracks ON queued_tracks.track_id = tracks.id
+            LEFT JOIN albums ON tracks.album_id = albums.id
+            LEFT JOIN votes ON votes.queue_entry_id = queued_tracks.id
+            WHERE queued_tracks.session_id = $1 AND queued_tracks.id = $2
+            GROUP BY queued_tracks.id, tracks.id, albums.id;
+        ";
+
+        let maybe_row = sqlx::query(QUERY)
+            .bind(&session_id)
+            .bind(entry_id)
+            .fetch_optional(&self.executor)
+            .await?;
+        let mut entry = match maybe_row {
+            Some(row) => extract_queued_track_from_row(&row)?,
+            None => return Ok(None),
+        };
+        self.attach_artists(&mut [&mut entry.track]).await?;
+
+        Ok(Some(entry))
+    }
+
+    async fn remove_queue_entry(&self, session_id: Uuid, entry_id: i32) -> Result<bool> {
+        const QUERY: &str = "
+            DELETE FROM queued_tracks WHERE id = $1 AND session_id = $2;
+        ";
+
+        let result = sqlx::query(QUERY)
+            .bind(entry_id)
+            .bind(&session_id)
+            .execute(&self.executor)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn move_queue_entry(
+        &self,
+        session_id: Uuid,
+        entry_id: i32,
+        position: u32,
+    ) -> Result<bool> {
+        const ORDER_QUERY: &str = "
+            SELECT
+                queued_tracks.id,
+                queued_tracks.position IS NOT NULL AS pinned
+            FROM queued_tracks
+            LEFT JOIN votes ON votes.queue_entry_id = queued_tracks.id
+            WHERE queued_tracks.session_id = $1
+            GROUP BY queued_tracks.id
+            ORDER BY
+                queued_tracks.position IS NULL,
+                queued_tracks.position ASC,
+                COALESCE(SUM(votes.value), 0) DESC,
+                queued_tracks.added_date ASC,
+                queued_tracks.id ASC;
+        ";
+        const PIN_QUERY: &str = "
+            UPDATE queued_tracks SET position = $1 WHERE id = $2;
+        ";
+
+        let mut tx = self.executor.begin().await?;
+
+        let rows = sqlx::query(ORDER_QUERY)
+            .bind(&session_id)
+            .fetch_all(&mut tx)
+            .await?;
+        let order = rows
+            .iter()
+            .map(|row| -> Result<(i32, bool)> {
+                Ok((row.try_get("id")?, row.try_get("pinned")?))
+            })
+            .collect::<Result<Vec<(i32, bool)>>>()?;
+
+        let pinned = match pinned_order(&order, entry_id, position) {
+            Some(pinned) => pinned,
+            None => return Ok(false),
+        };
+        for (position, id) in pinned.iter().enumerate() {
+            sqlx::query(PIN_QUERY)
+                .bind(position as i32)
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn clear_queue(&self, session_id: Uuid) -> Result<u32> {
+        const QUERY: &str = "
+            DELETE FROM queued_tracks WHERE session_id = $1;
+        ";
+
+        let result = sqlx::query(QUERY)
+            .bind(&session_id)
+            .execute(&self.executor)
+            .await?;
+
+        Ok(result.rows_affected() as u32)
+    }
+
+    async fn record_played_track(
+        &self,
+        session_id: Uuid,
+        track_id: &str,
+        requester: Requester,
+    ) -> Result<()> {
+        const QUERY: &str = "
+            INSERT INTO played_tracks (session_id, track_id, requested_by, autofill)
+                VALUES ($1, $2, $3, $4);
+        ";
+
+        sqlx::query(QUERY)
+            .bind(&session_id)
+            .bind(track_id)
+            .bind(requester.guest_id())
+            .bind(requester.is_autofill())
+            .execute(&self.executor)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn mark_play_skipped(&self, session_id: Uuid, track_id: &str) -> Result<bool> {
+        const QUERY: &str = "
+            UPDATE played_tracks SET skipped = true
+                WHERE id = (
+                    SELECT MAX(id) FROM played_tracks
+                        WHERE session_id = $1 AND track_id = $2
+                );
+        ";
+
+        let result = sqlx::query(QUERY)
+            .bind(&session_id)
+            .bind(track_id)
+            .execute(&self.executor)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn last_played(
+        &self,
+        session_id: Uuid,
+        track_id: &str,
+    ) -> Result<Option<Duration>> {
+        const QUERY: &str = "
+            SELECT
+                CAST(EXTRACT(EPOCH FROM (LOCALTIMESTAMP - MAX(played_date))) AS bigint)
+                    AS secs_ago
+            FROM played_tracks
+            WHERE session_id = $1 AND track_id = $2;
+        ";
+
+        let row = sqlx::query(QUERY)
+            .bind(&session_id)
+            .bind(track_id)
+            .fetch_one(&self.executor)
+            .await?;
+        let secs_ago: Option<i64> = row.try_get("secs_ago")?;
+
+        Ok(secs_ago.map(|secs| Duration::from_secs(secs.max(0) as u64)))
+    }
+
+    async fn recently_played(
+        &self,
+        session_id: Uuid,
+        limit: u32,
+    ) -> Result<Vec<SpotifyTrack>> {
+        const QUERY: &str = "
+            SELECT
+                tracks.id               AS track_id,
+                tracks.name             AS track_name,
+                tracks.duration         AS track_dur,
+                tracks.album_id         AS album_id,
+                albums.name             AS album_name,
+                albums.cover_image_url  AS album_image
+            FROM played_tracks
+            JOIN tracks ON played_tracks.track_id = tracks.id
+            LEFT JOIN albums ON tracks.album_id = albums.id
+            WHERE played_tracks.session_id = $1
+            ORDER BY
+                played_tracks.played_date DESC,
+                played_tracks.id DESC
+            LIMIT ($2);
+        ";
+
+        let rows = sqlx::query(QUERY)
+            .bind(&session_id)
+            .bind(limit as i32)
+            .fetch_all(&self.executor)
+            .await?;
+
+        let mut played = rows
+            .iter()
+            .map(extract_track_from_row)
+            .collect::<Result<Vec<SpotifyTrack>>>()?;
+        let mut tracks: Vec<&mut SpotifyTrack> = played.iter_mut().collect();
+        self.attach_artists(&mut tracks).await?;
+
+        Ok(played)
+    }
+
+    async fn play_history(
+        &self,
+        session_id: Uuid,
+        before: Option<i32>,
+        limit: u32,
+    ) -> Result<Vec<PlayedTrack>> {
+        const QUERY: &str = "
+            SELECT
+                played_tracks.id            AS play_id,
+                played_tracks.played_date AT TIME ZONE current_setting('TimeZone')
+                                            AS played_at,
+                played_tracks.requested_by  AS requested_by,
+                played_tracks.autofill      AS autofill,
+                played_tracks.skipped       AS skipped,
+                tracks.id                   AS track_id,
+                tracks.name                 AS track_name,
+                tracks.duration             AS track_dur,
+                tracks.album_id             AS album_id,
+                albums.name                 AS album_name,
+                albums.cover_image_url      AS album_image
+            FROM played_tracks
+            JOIN tracks ON played_tracks.track_id = tracks.id
+            LEFT JOIN albums ON tracks.album_id = albums.id
+            WHERE played_tracks.session_id = $1 AND ($2::integer IS NULL OR played_tracks.id < $2)
+            ORDER BY played_tracks.id DESC
+            LIMIT ($3);
+        ";
+
+        let rows = sqlx::query(QUERY)
+            .bind(&session_id)
+            .bind(before)
+            .bind(limit as i32)
+            .fetch_all(&self.executor)
+            .await?;
+
+        let mut history = rows
+            .iter()
+            .map(|row| -> Result<PlayedTrack> {
+                let requested_by: Option<Uuid> = row.try_get("requested_by")?;
+                let autofill: bool = row.try_get("autofill")?;
+                Ok(PlayedTrack {
+                    id: row.try_get("play_id")?,
+                    track: extract_track_from_row(row)?,
+                    played_at: row.try_get("played_at")?,
+                    requester: Requester::from_columns(requested_by, autofill),
+                    skipped: row.try_get("skipped")?,
+                })
+            })
+            .collect::<Result<Vec<PlayedTrack>>>()?;
+        let mut tracks: Vec<&mut SpotifyTrack> =
+            history.iter_mut().map(|played| &mut played.track).collect();
+        self.attach_artists(&mut tracks).await?;
+
+        Ok(history)
+    }
+
+    async fn set_vote(
+        &self,
+        session_id: Uuid,
+        entry_id: i32,
+        guest_id: Uuid,
+        vote: Option<Vote>,
+    ) -> Result<bool> {
+        const FIND_ENTRY_QUERY: &str = "
+            SELECT id FROM queued_tracks WHERE id = $1 AND session_id = $2;
+        ";
+        const UPSERT_VOTE_QUERY: &str = "
+            INSERT INTO votes (queue_entry_id, guest_id, value)
+                VALUES ($1, $2, $3)
+            ON CONFLICT (queue_entry_id, guest_id) DO UPDATE SET value = excluded.value;
+        ";
+        const DELETE_VOTE_QUERY: &str = "
+            DELETE FROM votes WHERE queue_entry_id = $1 AND guest_id = $2;
+        ";
+
+        let mut tx = self.executor.begin().await?;
+
+        let entry = sqlx::query(FIND_ENTRY_QUERY)
+            .bind(entry_id)
+            .bind(&session_id)
+            .fetch_optional(&mut tx)
+            .await?;
+        if let None = entry {
+            return Ok(false);
+        }
+
+        match vote {
+            Some(vote) => {
+                sqlx::query(UPSERT_VOTE_QUERY)
+                    .bind(entry_id)
+                    .bind(&guest_id)
+                    .bind(vote.value())
+                    .execute(&mut tx)
+                    .await?;
+            }
+            None => {
+                sqlx::query(DELETE_VOTE_QUERY)
+                    .bind(entry_id)
+                    .bind(&guest_id)
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn create_guest(&self, session_id: Uuid) -> Result<SessionGuest> {
+        const QUERY: &str = "
+            INSERT INTO guests (id, session_id)
+                VALUES ($1, $2);
+        ";
+        let guest = SessionGuest {
+            id: Uuid::new_v4(),
+            session_id: session_id,
+        };
+        sqlx::query(QUERY)
+            .bind(&guest.id)
+            .bind(&guest.session_id)
+            .execute(&self.executor)
+            .await?;
+
+        Ok(guest)
+    }
+
+    async fn get_guest(&self, id: Uuid) -> Result<Option<SessionGuest>> {
+        const QUERY: &str = "
+            SELECT id, session_id FROM guests WHERE id = $1;
+        ";
+
+        let maybe_row = sqlx::query(QUERY)
+            .bind(&id)
+            .fetch_optional(&self.executor)
+            .await?;
+
+        let res = maybe_row.map(|row| -> Result<SessionGuest> {
+            Ok(SessionGuest {
+                id: row.try_get("id")?,
+                session_id: row.try_get("session_id")?,
+            })
+        });
+
+        res.transpose()
+    }
+
+    async fn count_queued_by_guest(
+        &self,
+        session_id: Uuid,
+        guest_id: Uuid,
+    ) -> Result<u32> {
+        const QUERY: &str = "
+            SELECT COUNT(*) AS queued FROM queued_tracks
+            WHERE session_id = $1 AND added_by = $2;
+        ";
+
+        let row = sqlx::query(QUERY)
+            .bind(&session_id)
+            .bind(&guest_id)
+            .fetch_one(&self.executor)
+            .await?;
+        let queued: i64 = row.try_get("queued")?;
+
+        Ok(queued as u32)
+    }
+
     async fn create_session(&self, name: &str) -> Result<super::model::PlaySession> {
         const QUERY: &str = "
-            INSERT INTO sessions (id, name, access_token, refresh_token, expires_at)
-                VALUES ($1, $2, '', '', current_timestamp);
+            INSERT INTO sessions
+                (id, name, access_token, refresh_token, expires_at, host_token)
+                VALUES ($1, $2, '', '', current_timestamp, $3);
         ";
         let uuid = Uuid::new_v4();
+        let host_token = PlaySession::new_host_token();
         sqlx::query(QUERY)
             .bind(&uuid)
             .bind(name)
+            .bind(&host_token)
             .execute(&self.executor)
             .await?;
 
@@ -221,6 +847,9 @@ impl PersistentStore for PostgressDatabase {
             id: uuid,
             name: name.to_owned(),
             token: None,
+            device_id: None,
+            host_token: Some(host_token),
+            fallback_playlist: None,
         })
     }
 
@@ -243,19 +872,68 @@ impl PersistentStore for PostgressDatabase {
             token.refresh_token.as_ref().unwrap()
         };
 
-        let expires_at = token.expires_at.as_ref();
+        let expires_at = token.expires_at.map(|expiry| expiry.naive_utc());
 
         sqlx::query(QUERY)
             .bind(&session.name)
             .bind(&token.access_token)
             .bind(refresh_token)
             .bind(expires_at)
+            .bind(&session.id)
             .execute(&self.executor)
             .await?;
 
         Ok(())
     }
 
+    async fn set_session_device(
+        &self,
+        session_id: Uuid,
+        device_id: Option<&str>,
+    ) -> Result<()> {
+        const QUERY: &str = "
+            UPDATE sessions SET device_id = $1 WHERE id = $2;
+        ";
+
+        let result = sqlx::query(QUERY)
+            .bind(device_id)
+            .bind(&session_id)
+            .execute(&self.executor)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(anyhow::Error::msg(format!(
+                "no session with id {}",
+                session_id
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn set_session_fallback_playlist(
+        &self,
+        session_id: Uuid,
+        playlist_id: Option<&str>,
+    ) -> Result<()> {
+        const QUERY: &str = "
+            UPDATE sessions SET fallback_playlist = $1 WHERE id = $2;
+        ";
+
+        let result = sqlx::query(QUERY)
+            .bind(playlist_id)
+            .bind(&session_id)
+            .execute(&self.executor)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(anyhow::Error::msg(format!(
+                "no session with id {}",
+                session_id
+            )));
+        }
+
+        Ok(())
+    }
+
     async fn get_session(&self, id: Uuid) -> Result<Option<PlaySession>> {
         const QUERY: &str = "
             SELECT * FROM sessions WHERE id=$1;
@@ -270,13 +948,19 @@ impl PersistentStore for PostgressDatabase {
             let name: String = row.try_get("name")?;
             let access_token: String = row.try_get("access_token")?;
             let refresh_token: String = row.try_get("refresh_token")?;
-            let expires_at: DateTime<Utc> = row.try_get("expires_at")?;
+            let expires_at: NaiveDateTime = row.try_get("expires_at")?;
+            let device_id: Option<String> = row.try_get("device_id")?;
+            let host_token: Option<String> = row.try_get("host_token")?;
+            let fallback_playlist: Option<String> = row.try_get("fallback_playlist")?;
 
             if access_token == "" {
                 return Ok(PlaySession {
                     id,
                     name,
                     token: None,
+                    device_id,
+                    host_token,
+                    fallback_playlist,
                 });
             }
 
@@ -288,7 +972,7 @@ impl PersistentStore for PostgressDatabase {
 
             let mut token = Token::default();
             token.access_token = access_token;
-            token.expires_at = Some(expires_at);
+            token.expires_at = Some(DateTime::from_utc(expires_at, Utc));
             token.refresh_token = rt_option;
             token.scopes = scopes();
 
@@ -296,13 +980,30 @@ impl PersistentStore for PostgressDatabase {
                 id,
                 name,
                 token: Some(token),
+                device_id,
+                host_token,
+                fallback_playlist,
             })
         });
 
-        todo!()
+        res.transpose()
     }
 }
 
+fn extract_queued_track_from_row(row: &PgRow) -> Result<QueuedTrack> {
+    let entry_id = row.try_get("entry_id")?;
+    let upvotes: i64 = row.try_get("upvotes")?;
+    let downvotes: i64 = row.try_get("downvotes")?;
+
+    Ok(QueuedTrack {
+        id: entry_id,
+        track: extract_track_from_row(row)?,
+        added_by: row.try_get("added_by")?,
+        upvotes: upvotes as u32,
+        downvotes: downvotes as u32,
+    })
+}
+
 fn extract_track_from_row(row: &PgRow) -> Result<SpotifyTrack> {
     let track_id = row.try_get("track_id")?;
     let track_name = row.try_get("track_name")?;
@@ -315,12 +1016,13 @@ fn extract_track_from_row(row: &PgRow) -> Result<SpotifyTrack> {
     let track = SpotifyTrack {
         id: track_id,
         name: track_name,
-        duration: Duration::from_secs(track_duration as u64),
+        duration: Duration::from_millis(track_duration as u64),
         album: SpotifyAlbum {
             id: album_id,
             name: album_name,
             cover_image_url: album_cover_image_url,
         },
+        artists: Vec::new(),
     };
 
     Ok(track)
@@ -384,16 +1086,106 @@ mod queries {
         expires_at timestamp
     );
 ";
+
+    pub const ADD_POSITION_TO_ARTIST_TO_TRACK: &str = "
+    ALTER TABLE artist_to_track ADD COLUMN IF NOT EXISTS position integer;
+";
+
+    pub const CREATE_VOTES_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS votes (
+        queue_entry_id integer REFERENCES queued_tracks (id) ON DELETE CASCADE,
+        guest_id uuid,
+        value smallint,
+        PRIMARY KEY(queue_entry_id, guest_id)
+    );
+";
+
+    pub const CREATE_GUESTS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS guests (
+        id uuid PRIMARY KEY,
+        session_id uuid REFERENCES sessions (id),
+        created_at timestamp DEFAULT current_timestamp
+    );
+";
+
+    pub const ADD_GUEST_TO_QUEUED_TRACKS: &str = "
+    ALTER TABLE queued_tracks
+        ADD COLUMN IF NOT EXISTS added_by uuid REFERENCES guests (id);
+";
+
+    pub const ADD_DEVICE_TO_SESSIONS: &str = "
+    ALTER TABLE sessions ADD COLUMN IF NOT EXISTS device_id text;
+";
+
+    pub const ADD_POSITION_TO_QUEUED_TRACKS: &str = "
+    ALTER TABLE queued_tracks ADD COLUMN IF NOT EXISTS position integer;
+";
+
+    pub const ADD_REQUESTER_TO_PLAYED_TRACKS: &str = "
+    ALTER TABLE played_tracks
+        ADD COLUMN IF NOT EXISTS requested_by uuid REFERENCES guests (id);
+";
+
+    pub const ADD_AUTOFILL_TO_PLAYED_TRACKS: &str = "
+    ALTER TABLE played_tracks
+        ADD COLUMN IF NOT EXISTS autofill boolean NOT NULL DEFAULT false;
+";
+
+    pub const ADD_SKIPPED_TO_PLAYED_TRACKS: &str = "
+    ALTER TABLE played_tracks
+        ADD COLUMN IF NOT EXISTS skipped boolean NOT NULL DEFAULT false;
+";
+
+    pub const ADD_FALLBACK_PLAYLIST_TO_SESSIONS: &str = "
+    ALTER TABLE sessions ADD COLUMN IF NOT EXISTS fallback_playlist text;
+";
+
+    pub const ADD_HOST_TOKEN_TO_SESSIONS: &str = "
+    ALTER TABLE sessions ADD COLUMN IF NOT EXISTS host_token text;
+";
+
+    pub const CREATE_SCHEMA_VERSION_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS schema_version (
+        version integer PRIMARY KEY,
+        description text,
+        applied_at timestamp DEFAULT current_timestamp
+    );
+";
+
+    pub const ADD_SESSION_TO_QUEUED_TRACKS: &str = "
+    ALTER TABLE queued_tracks
+        ADD COLUMN IF NOT EXISTS session_id uuid REFERENCES sessions (id);
+";
+
+    pub const ADD_SESSION_TO_PLAYED_TRACKS: &str = "
+    ALTER TABLE played_tracks
+        ADD COLUMN IF NOT EXISTS session_id uuid REFERENCES sessions (id);
+";
+
+    pub const DEFAULT_PLAYED_DATE: &str = "
+    ALTER TABLE played_tracks
+        ALTER COLUMN played_date SET DEFAULT current_timestamp;
+";
+
+    pub const TRACK_DURATION_TO_MILLIS: &str = "
+    UPDATE tracks SET duration = duration * 1000;
+";
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::config::Config;
 
+    ///Database named by `DDJ_DATABASE_URL` or the `POSTGRES_*` variables, as the server
+    /// would find it
     async fn setup_db<'c>() -> PostgressDatabase {
-        let db = PostgressDatabase::new().await.unwrap();
-        db.create_tables().await.unwrap();
+        let mut config = Config::default();
+        config.apply_env().unwrap();
+        let url = config.database.connection_url();
+        let db = PostgressDatabase::with_url(&url, 1).await.unwrap();
+        db.migrate().await.unwrap();
         db
     }
 
@@ -424,28 +1216,38 @@ mod tests {
     //     test_result
     // }
 
+    persistent_store_tests!(setup_db());
+
     #[tokio::test]
     async fn test_add_track_to_queue() -> Result<()> {
         let db = setup_db().await;
 
-        db.create_tables().await?;
-        db.add_track_to_queue(SpotifyTrack {
-            id: "abcde".to_owned(),
-            name: "Example Song".to_owned(),
-            duration: Duration::from_secs(360),
-            album: SpotifyAlbum {
-                name: "Example Album".to_owned(),
-                id: "abcdefg".to_owned(),
-                cover_image_url: "http://fake-album-cover.com/image.jpg".to_owned(),
+        db.migrate().await?;
+        let session = db.create_session("test session").await?;
+        db.add_track_to_queue(
+            session.id,
+            SpotifyTrack {
+                id: "abcde".to_owned(),
+                name: "Example Song".to_owned(),
+                duration: Duration::from_secs(360),
+                album: SpotifyAlbum {
+                    name: "Example Album".to_owned(),
+                    id: "abcdefg".to_owned(),
+                    cover_image_url: Some(
+                        "http://fake-album-cover.com/image.jpg".to_owned(),
+                    ),
+                },
+                artists: Vec::new(),
             },
-        })
+            None,
+        )
         .await?;
 
-        let tracks = db.get_track_queue(1).await?;
+        let tracks = db.get_track_queue(session.id, 1).await?;
         assert_eq!(tracks.len(), 1);
 
         let retrieved = tracks.get(0).unwrap();
-        assert_eq!(retrieved.name, "Example Song");
+        assert_eq!(retrieved.track.name, "Example Song");
 
         teardown_tb(db).await;
 
@@ -0,0 +1,1167 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use rspotify::Token;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteRow},
+    types::chrono::{DateTime, Utc},
+    Pool, Row, Sqlite, Transaction,
+};
+use uuid::Uuid;
+
+use crate::{
+    authentication::scopes,
+    persistence::model::{
+        PlaySession, PlayedTrack, QueuedTrack, Requester, SessionGuest, SpotifyAlbum,
+        SpotifyArtist, Vote,
+    },
+};
+
+use super::{
+    migrations::{self, Migration},
+    model::SpotifyTrack,
+    pinned_order, PersistentStore, Store,
+};
+
+use anyhow::Result;
+
+///Versions line up with the Postgres migrations. The SQLite schema was introduced after
+/// queues were scoped to sessions, so the second migration has nothing to do here.
+pub(super) const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        statements: &[
+            queries::CREATE_SESSION_TABLE,
+            queries::CREATE_ALUBMS_TABLE,
+            queries::CREATE_ARTIST_TABLE,
+            queries::CREATE_TRACKS_TABLE,
+            queries::CREATE_PLAYED_TRACKS_TABLE,
+            queries::CREATE_TRACK_QUEUE_TABLE,
+            queries::CREATE_ARTIST_TO_TRACK_TABLE,
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "scope queued and played tracks to sessions",
+        statements: &[],
+    },
+    Migration {
+        version: 3,
+        description: "keep the order of a track's artists",
+        statements: &[queries::ADD_POSITION_TO_ARTIST_TO_TRACK],
+    },
+    Migration {
+        version: 4,
+        description: "votes on queued tracks",
+        statements: &[queries::CREATE_VOTES_TABLE],
+    },
+    Migration {
+        version: 5,
+        description: "guests and who queued each track",
+        statements: &[
+            queries::CREATE_GUESTS_TABLE,
+            queries::ADD_GUEST_TO_QUEUED_TRACKS,
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "remember each session's playback device",
+        statements: &[queries::ADD_DEVICE_TO_SESSIONS],
+    },
+    Migration {
+        version: 7,
+        description: "host tokens for sessions",
+        statements: &[queries::ADD_HOST_TOKEN_TO_SESSIONS],
+    },
+    Migration {
+        version: 8,
+        description: "queue positions set by the host",
+        statements: &[queries::ADD_POSITION_TO_QUEUED_TRACKS],
+    },
+    Migration {
+        version: 9,
+        description: "fallback playlists for sessions",
+        statements: &[queries::ADD_FALLBACK_PLAYLIST_TO_SESSIONS],
+    },
+    Migration {
+        version: 10,
+        description: "who each played track was played for",
+        statements: &[
+            queries::ADD_REQUESTER_TO_PLAYED_TRACKS,
+            queries::ADD_AUTOFILL_TO_PLAYED_TRACKS,
+        ],
+    },
+    Migration {
+        version: 11,
+        description: "whether each played track was skipped",
+        statements: &[queries::ADD_SKIPPED_TO_PLAYED_TRACKS],
+    },
+];
+
+///PersistentStore backed by a single SQLite file, for small deployments that
+/// don't want to run a Postgres server
+pub struct SqliteDatabase {
+    executor: Pool<Sqlite>,
+}
+
+impl SqliteDatabase {
+    async fn new(url: &str, pool_size: u32) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+            .foreign_keys(true);
+
+        let conn_pool = SqlitePoolOptions::new()
+            .max_connections(pool_size)
+            .connect_with(options)
+            .await?;
+
+        Ok(Self {
+            executor: conn_pool,
+        })
+    }
+
+    pub async fn connect(url: &str, pool_size: u32) -> Result<Store> {
+        let db = Self::new(url, pool_size).await?;
+        Ok(Arc::new(db))
+    }
+
+    ///Add a track, its album and its artists to the catalog unless they are already
+    /// there
+    async fn insert_track(
+        tx: &mut Transaction<'_, Sqlite>,
+        track: &SpotifyTrack,
+    ) -> Result<()> {
+        const INSERT_TRACK_QUERY: &str = "
+            INSERT INTO tracks (id, name, album_id, duration)
+                VALUES (?, ?, ?, ?)
+            ON CONFLICT DO NOTHING;
+        ";
+        const INSERT_ALBUM_QUERY: &str = "
+            INSERT INTO albums (id, name, cover_image_url)
+                VALUES (?, ?, ?)
+            ON CONFLICT DO NOTHING;
+        ";
+        const INSERT_ARTIST_QUERY: &str = "
+            INSERT INTO artists (id, name)
+                VALUES (?, ?)
+            ON CONFLICT DO NOTHING;
+        ";
+        const INSERT_ARTIST_TO_TRACK_QUERY: &str = "
+            INSERT INTO artist_to_track (track_id, artist_id, position)
+                VALUES (?, ?, ?)
+            ON CONFLICT DO NOTHING;
+        ";
+
+        sqlx::query(INSERT_ALBUM_QUERY)
+            .bind(&track.album.id)
+            .bind(&track.album.name)
+            .bind(&track.album.cover_image_url)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(INSERT_TRACK_QUERY)
+            .bind(&track.id)
+            .bind(&track.name)
+            .bind(&track.album.id)
+            .bind(track.duration.as_millis() as i64)
+            .execute(&mut *tx)
+            .await?;
+
+        for (position, artist) in track.artists.iter().enumerate() {
+            sqlx::query(INSERT_ARTIST_QUERY)
+                .bind(&artist.id)
+                .bind(&artist.name)
+                .execute(&mut *tx)
+                .await?;
+
+            sqlx::query(INSERT_ARTIST_TO_TRACK_QUERY)
+                .bind(&track.id)
+                .bind(&artist.id)
+                .bind(position as i32)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    ///Fill in the artists of tracks read from the database
+    async fn attach_artists(&self, tracks: &mut [&mut SpotifyTrack]) -> Result<()> {
+        const QUERY: &str = "
+            SELECT
+                artists.id      AS artist_id,
+                artists.name    AS artist_name
+            FROM artist_to_track
+            JOIN artists ON artist_to_track.artist_id = artists.id
+            WHERE artist_to_track.track_id = ?
+            ORDER BY artist_to_track.position ASC;
+        ";
+
+        for track in tracks.iter_mut() {
+            let rows = sqlx::query(QUERY)
+                .bind(&track.id)
+                .fetch_all(&self.executor)
+                .await?;
+
+            for row in rows {
+                track.artists.push(SpotifyArtist {
+                    id: row.try_get("artist_id")?,
+                    name: row.try_get("artist_name")?,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl PersistentStore for SqliteDatabase {
+    async fn schema_version(&self) -> Result<u32> {
+        const QUERY: &str =
+            "SELECT COALESCE(MAX(version), 0) AS version FROM schema_version;";
+
+        sqlx::query(queries::CREATE_SCHEMA_VERSION_TABLE)
+            .execute(&self.executor)
+            .await?;
+        let row = sqlx::query(QUERY).fetch_one(&self.executor).await?;
+        let version: i64 = row.try_get("version")?;
+
+        Ok(version as u32)
+    }
+
+    async fn migrate(&self) -> Result<u32> {
+        const RECORD_VERSION_QUERY: &str = "
+            INSERT INTO schema_version (version, description)
+                VALUES (?, ?);
+        ";
+
+        let current = self.schema_version().await?;
+        for migration in migrations::pending(MIGRATIONS, current)? {
+            let mut tx = self.executor.begin().await?;
+            for statement in migration.statements {
+                sqlx::query(statement).execute(&mut tx).await?;
+            }
+            sqlx::query(RECORD_VERSION_QUERY)
+                .bind(migration.version)
+                .bind(migration.description)
+                .execute(&mut tx)
+                .await?;
+            tx.commit().await?;
+
+            println!(
+                "applied migration {}: {}",
+                migration.version, migration.description
+            );
+        }
+
+        self.schema_version().await
+    }
+
+    async fn get_track_queue(
+        &self,
+        session_id: Uuid,
+        limit: u32,
+    ) -> Result<Vec<QueuedTrack>> {
+        const QUERY: &str = "
+            SELECT
+                queued_tracks.id        AS entry_id,
+                queued_tracks.track_id  AS track_id,
+                queued_tracks.added_by  AS added_by,
+                tracks.name             AS track_name,
+                tracks.duration         AS track_dur,
+                tracks.album_id         AS album_id,
+                albums.name             AS album_name,
+                albums.cover_image_url  AS album_image,
+                SUM(CASE WHEN votes.value > 0 THEN 1 ELSE 0 END) AS upvotes,
+                SUM(CASE WHEN votes.value < 0 THEN 1 ELSE 0 END) AS downvotes
+            FROM queued_tracks
+            LEFT JOIN tracks ON queued_tracks.track_id = tracks.id
+            LEFT JOIN albums ON tracks.album_id = albums.id
+            LEFT JOIN votes ON votes.queue_entry_id = queued_tracks.id
+            WHERE queued_tracks.session_id = ?
+            GROUP BY queued_tracks.id, tracks.id, albums.id
+            ORDER BY
+                queued_tracks.position IS NULL,
+                queued_tracks.position ASC,
+                COALESCE(SUM(votes.value), 0) DESC,
+                queued_tracks.added_date ASC,
+                queued_tracks.id ASC
+            LIMIT ?;";
+
+        let result = sqlx::query(QUERY)
+            .bind(session_id.to_string())
+            .bind(limit)
+            .fetch_all(&self.executor)
+            .await?;
+
+        let mut entries = result
+            .into_iter()
+            .map(|row| extract_queued_track_from_row(&row))
+            .collect::<Result<Vec<QueuedTrack>>>()?;
+        let mut tracks: Vec<&mut SpotifyTrack> =
+            entries.iter_mut().map(|entry| &mut entry.track).collect();
+        self.attach_artists(&mut tracks).await?;
+
+        Ok(entries)
+    }
+
+    async fn add_track_to_queue(
+        &self,
+        session_id: Uuid,
+        track: SpotifyTrack,
+        added_by: Option<Uuid>,
+    ) -> Result<()> {
+        const INSERT_QUEUED_QUERY: &str = "
+            INSERT INTO queued_tracks (session_id, track_id, added_by)
+                VALUES (?, ?, ?)
+        ";
+
+        let mut tx = self.executor.begin().await?;
+        Self::insert_track(&mut tx, &track).await?;
+
+        sqlx::query(INSERT_QUEUED_QUERY)
+            .bind(session_id.to_string())
+            .bind(&track.id)
+            .bind(added_by.map(|id| id.to_string()))
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn save_track(&self, track: &SpotifyTrack) -> Result<()> {
+        let mut tx = self.executor.begin().await?;
+        Self::insert_track(&mut tx, track).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_track_by_id(&self, id: &str) -> Result<SpotifyTrack> {
+        const QUERY: &str = "
+            SELECT
+                tracks.id               AS track_id,
+                tracks.name             AS track_name,
+                tracks.duration         AS track_dur,
+                tracks.album_id         AS album_id,
+                albums.name             AS album_name,
+                albums.cover_image_url  AS album_image
+            FROM tracks
+            LEFT JOIN albums ON tracks.album_id = albums.id
+            WHERE tracks.id = ?
+            LIMIT 1;
+        ";
+
+        let result = sqlx::query(QUERY)
+            .bind(id)
+            .fetch_one(&self.executor)
+            .await?;
+
+        let mut track = extract_track_from_row(&result)?;
+        self.attach_artists(&mut [&mut track]).await?;
+
+        Ok(track)
+    }
+
+    async fn pop_track_from_queue(
+        &self,
+        session_id: Uuid,
+    ) -> Result<Option<QueuedTrack>> {
+        const GET_NEXT_TRACK_QUERY: &str = "
+            SELECT
+                queued_tracks.id,
+                queued_tracks.track_id,
+                queued_tracks.added_by,
+                SUM(CASE WHEN votes.value > 0 THEN 1 ELSE 0 END) AS upvotes,
+                SUM(CASE WHEN votes.value < 0 THEN 1 ELSE 0 END) AS downvotes
+            FROM queued_tracks
+            LEFT JOIN votes ON votes.queue_entry_id = queued_tracks.id
+            WHERE queued_tracks.session_id = ?
+            GROUP BY queued_tracks.id
+            ORDER BY
+                queued_tracks.position IS NULL,
+                queued_tracks.position ASC,
+                COALESCE(SUM(votes.value), 0) DESC,
+                queued_tracks.added_date ASC,
+                queued_tracks.id ASC
+            LIMIT 1;
+        ";
+        const REMOVE_AND_RETURN_QUERY: &str = "DELETE FROM queued_tracks WHERE id = ?;";
+
+        let mut tx = self.executor.begin().await?;
+
+        let result = sqlx::query(GET_NEXT_TRACK_QUERY)
+            .bind(session_id.to_string())
+            .fetch_optional(&mut tx)
+            .await?;
+
+        if let None = result {
+            return Ok(None);
+        }
+        let unwrapped_res = result.unwrap();
+        let entry_id: i64 = unwrapped_res.try_get("id")?;
+        let target_id: String = unwrapped_res.try_get("track_id")?;
+        let added_by: Option<String> = unwrapped_res.try_get("added_by")?;
+        let upvotes: i64 = unwrapped_res.try_get("upvotes")?;
+        let downvotes: i64 = unwrapped_res.try_get("downvotes")?;
+
+        sqlx::query(REMOVE_AND_RETURN_QUERY)
+            .bind(entry_id)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        let track = self.get_track_by_id(&target_id).await;
+        match track {
+            Ok(t) => Ok(Some(QueuedTrack {
+                id: entry_id as i32,
+                track: t,
+                added_by: added_by.map(|id| Uuid::parse_str(&id)).transpose()?,
+                upvotes: upvotes as u32,
+                downvotes: downvotes as u32,
+            })),
+            Err(e) => Err(anyhow::Error::msg(format!(
+                "failed to retrieve track from db: {}",
+                e
+            ))),
+        }
+    }
+
+    async fn get_queue_entry(
+        &self,
+        session_id: Uuid,
+        entry_id: i32,
+    ) -> Result<Option<QueuedTrack>> {
+        const QUERY: &str = "
+            SELECT
+                queued_tracks.id        AS entry_id,
+                queued_tracks.track_id  AS track_id,
+                queued_tracks.added_by  AS added_by,
+                tracks.name             AS track_name,
+                tracks.duration         AS track_dur,
+                tracks.album_id         AS album_id,
+                albums.name             AS album_name,
+                albums.cover_image_url  AS album_image,
+                SUM(CASE WHEN votes.value > 0 THEN 1 ELSE 0 END) AS upvotes,
+                SUM(CASE WHEN votes.value < 0 THEN 1 ELSE 0 END) AS downvotes
+            FROM queued_tracks
+            LEFT JOIN tracks ON queued_tracks.track_id = tracks.id
+            LEFT JOIN albums ON tracks.album_id = albums.id
+            LEFT JOIN votes ON votes.queue_entry_id = queued_tracks.id
+            WHERE queued_tracks.session_id = ? AND queued_tracks.id = ?
+            GROUP BY queued_tracks.id, tracks.id, albums.id;
+        ";
+
+        let maybe_row = sqlx::query(QUERY)
+            .bind(session_id.to_string())
+            .bind(entry_id)
+            .fetch_optional(&self.executor)
+            .await?;
+        let mut entry = match maybe_row {
+            Some(row) => extract_queued_track_from_row(&row)?,
+            None => return Ok(None),
+        };
+        self.attach_artists(&mut [&mut entry.track]).await?;
+
+        Ok(Some(entry))
+    }
+
+    async fn remove_queue_entry(&self, session_id: Uuid, entry_id: i32) -> Result<bool> {
+        const QUERY: &str = "
+            DELETE FROM queued_tracks WHERE id = ? AND session_id = ?;
+        ";
+
+        let result = sqlx::query(QUERY)
+            .bind(entry_id)
+            .bind(session_id.to_string())
+            .execute(&self.executor)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn move_queue_entry(
+        &self,
+        session_id: Uuid,
+        entry_id: i32,
+        position: u32,
+    ) -> Result<bool> {
+        const ORDER_QUERY: &str = "
+            SELECT
+                queued_tracks.id,
+                queued_tracks.position IS NOT NULL AS pinned
+            FROM queued_tracks
+            LEFT JOIN votes ON votes.queue_entry_id = queued_tracks.id
+            WHERE queued_tracks.session_id = ?
+            GROUP BY queued_tracks.id
+            ORDER BY
+                queued_tracks.position IS NULL,
+                queued_tracks.position ASC,
+                COALESCE(SUM(votes.value), 0) DESC,
+                queued_tracks.added_date ASC,
+                queued_tracks.id ASC;
+        ";
+        const PIN_QUERY: &str = "
+            UPDATE queued_tracks SET position = ? WHERE id = ?;
+        ";
+
+        let mut tx = self.executor.begin().await?;
+
+        let rows = sqlx::query(ORDER_QUERY)
+            .bind(session_id.to_string())
+            .fetch_all(&mut tx)
+            .await?;
+        let order = rows
+            .iter()
+            .map(|row| -> Result<(i32, bool)> {
+                Ok((row.try_get("id")?, row.try_get("pinned")?))
+            })
+            .collect::<Result<Vec<(i32, bool)>>>()?;
+
+        let pinned = match pinned_order(&order, entry_id, position) {
+            Some(pinned) => pinned,
+            None => return Ok(false),
+        };
+        for (position, id) in pinned.iter().enumerate() {
+            sqlx::query(PIN_QUERY)
+                .bind(position as i32)
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn clear_queue(&self, session_id: Uuid) -> Result<u32> {
+        const QUERY: &str = "
+            DELETE FROM queued_tracks WHERE session_id = ?;
+        ";
+
+        let result = sqlx::query(QUERY)
+            .bind(session_id.to_string())
+            .execute(&self.executor)
+            .await?;
+
+        Ok(result.rows_affected() as u32)
+    }
+
+    async fn record_played_track(
+        &self,
+        session_id: Uuid,
+        track_id: &str,
+        requester: Requester,
+    ) -> Result<()> {
+        const QUERY: &str = "
+            INSERT INTO played_tracks (session_id, track_id, requested_by, autofill)
+                VALUES (?, ?, ?, ?);
+        ";
+
+        sqlx::query(QUERY)
+            .bind(session_id.to_string())
+            .bind(track_id)
+            .bind(requester.guest_id().map(|id| id.to_string()))
+            .bind(requester.is_autofill())
+            .execute(&self.executor)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn mark_play_skipped(&self, session_id: Uuid, track_id: &str) -> Result<bool> {
+        const QUERY: &str = "
+            UPDATE played_tracks SET skipped = true
+                WHERE id = (
+                    SELECT MAX(id) FROM played_tracks
+                        WHERE session_id = ? AND track_id = ?
+                );
+        ";
+
+        let result = sqlx::query(QUERY)
+            .bind(session_id.to_string())
+            .bind(track_id)
+            .execute(&self.executor)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn last_played(
+        &self,
+        session_id: Uuid,
+        track_id: &str,
+    ) -> Result<Option<Duration>> {
+        const QUERY: &str = "
+            SELECT
+                CAST((julianday('now') - julianday(MAX(played_date))) * 86400 AS integer)
+                    AS secs_ago
+            FROM played_tracks
+            WHERE session_id = ? AND track_id = ?;
+        ";
+
+        let row = sqlx::query(QUERY)
+            .bind(session_id.to_string())
+            .bind(track_id)
+            .fetch_one(&self.executor)
+            .await?;
+        let secs_ago: Option<i64> = row.try_get("secs_ago")?;
+
+        Ok(secs_ago.map(|secs| Duration::from_secs(secs.max(0) as u64)))
+    }
+
+    async fn recently_played(
+        &self,
+        session_id: Uuid,
+        limit: u32,
+    ) -> Result<Vec<SpotifyTrack>> {
+        const QUERY: &str = "
+            SELECT
+                tracks.id               AS track_id,
+                tracks.name             AS track_name,
+                tracks.duration         AS track_dur,
+                tracks.album_id         AS album_id,
+                albums.name             AS album_name,
+                albums.cover_image_url  AS album_image
+            FROM played_tracks
+            JOIN tracks ON played_tracks.track_id = tracks.id
+            LEFT JOIN albums ON tracks.album_id = albums.id
+            WHERE played_tracks.session_id = ?
+            ORDER BY
+                played_tracks.played_date DESC,
+                played_tracks.id DESC
+            LIMIT (?);
+        ";
+
+        let rows = sqlx::query(QUERY)
+            .bind(session_id.to_string())
+            .bind(limit as i32)
+            .fetch_all(&self.executor)
+            .await?;
+
+        let mut played = rows
+            .iter()
+            .map(extract_track_from_row)
+            .collect::<Result<Vec<SpotifyTrack>>>()?;
+        let mut tracks: Vec<&mut SpotifyTrack> = played.iter_mut().collect();
+        self.attach_artists(&mut tracks).await?;
+
+        Ok(played)
+    }
+
+    async fn play_history(
+        &self,
+        session_id: Uuid,
+        before: Option<i32>,
+        limit: u32,
+    ) -> Result<Vec<PlayedTrack>> {
+        const QUERY: &str = "
+            SELECT
+                played_tracks.id            AS play_id,
+                played_tracks.played_date   AS played_at,
+                played_tracks.requested_by  AS requested_by,
+                played_tracks.autofill      AS autofill,
+                played_tracks.skipped       AS skipped,
+                tracks.id                   AS track_id,
+                tracks.name                 AS track_name,
+                tracks.duration             AS track_dur,
+                tracks.album_id             AS album_id,
+                albums.name                 AS album_name,
+                albums.cover_image_url      AS album_image
+            FROM played_tracks
+            JOIN tracks ON played_tracks.track_id = tracks.id
+            LEFT JOIN albums ON tracks.album_id = albums.id
+            WHERE played_tracks.session_id = ? AND (? IS NULL OR played_tracks.id < ?)
+            ORDER BY played_tracks.id DESC
+            LIMIT (?);
+        ";
+
+        let rows = sqlx::query(QUERY)
+            .bind(session_id.to_string())
+            .bind(before)
+            .bind(before)
+            .bind(limit as i32)
+            .fetch_all(&self.executor)
+            .await?;
+
+        let mut history = rows
+            .iter()
+            .map(|row| -> Result<PlayedTrack> {
+                let requested_by: Option<String> = row.try_get("requested_by")?;
+                let requested_by =
+                    requested_by.map(|id| Uuid::parse_str(&id)).transpose()?;
+                let autofill: bool = row.try_get("autofill")?;
+                Ok(PlayedTrack {
+                    id: row.try_get::<i64, _>("play_id")? as i32,
+                    track: extract_track_from_row(row)?,
+                    played_at: row.try_get("played_at")?,
+                    requester: Requester::from_columns(requested_by, autofill),
+                    skipped: row.try_get("skipped")?,
+                })
+            })
+            .collect::<Result<Vec<PlayedTrack>>>()?;
+        let mut tracks: Vec<&mut SpotifyTrack> =
+            history.iter_mut().map(|played| &mut played.track).collect();
+        self.attach_artists(&mut tracks).await?;
+
+        Ok(history)
+    }
+
+    async fn set_vote(
+        &self,
+        session_id: Uuid,
+        entry_id: i32,
+        guest_id: Uuid,
+        vote: Option<Vote>,
+    ) -> Result<bool> {
+        const FIND_ENTRY_QUERY: &str = "
+            SELECT id FROM queued_tracks WHERE id = ? AND session_id = ?;
+        ";
+        const UPSERT_VOTE_QUERY: &str = "
+            INSERT INTO votes (queue_entry_id, guest_id, value)
+                VALUES (?, ?, ?)
+            ON CONFLICT (queue_entry_id, guest_id) DO UPDATE SET value = excluded.value;
+        ";
+        const DELETE_VOTE_QUERY: &str = "
+            DELETE FROM votes WHERE queue_entry_id = ? AND guest_id = ?;
+        ";
+
+        let mut tx = self.executor.begin().await?;
+
+        let entry = sqlx::query(FIND_ENTRY_QUERY)
+            .bind(entry_id)
+            .bind(session_id.to_string())
+            .fetch_optional(&mut tx)
+            .await?;
+        if let None = entry {
+            return Ok(false);
+        }
+
+        match vote {
+            Some(vote) => {
+                sqlx::query(UPSERT_VOTE_QUERY)
+                    .bind(entry_id)
+                    .bind(guest_id.to_string())
+                    .bind(vote.value())
+                    .execute(&mut tx)
+                    .await?;
+            }
+            None => {
+                sqlx::query(DELETE_VOTE_QUERY)
+                    .bind(entry_id)
+                    .bind(guest_id.to_string())
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn create_guest(&self, session_id: Uuid) -> Result<SessionGuest> {
+        const QUERY: &str = "
+            INSERT INTO guests (id, session_id)
+                VALUES (?, ?);
+        ";
+        let guest = SessionGuest {
+            id: Uuid::new_v4(),
+            session_id: session_id,
+        };
+        sqlx::query(QUERY)
+            .bind(guest.id.to_string())
+            .bind(guest.session_id.to_string())
+            .execute(&self.executor)
+            .await?;
+
+        Ok(guest)
+    }
+
+    async fn get_guest(&self, id: Uuid) -> Result<Option<SessionGuest>> {
+        const QUERY: &str = "
+            SELECT session_id FROM guests WHERE id = ?;
+        ";
+
+        let maybe_row = sqlx::query(QUERY)
+            .bind(id.to_string())
+            .fetch_optional(&self.executor)
+            .await?;
+
+        let res = maybe_row.map(|row| -> Result<SessionGuest> {
+            let session_id: String = row.try_get("session_id")?;
+            Ok(SessionGuest {
+                id: id,
+                session_id: Uuid::parse_str(&session_id)?,
+            })
+        });
+
+        res.transpose()
+    }
+
+    async fn count_queued_by_guest(
+        &self,
+        session_id: Uuid,
+        guest_id: Uuid,
+    ) -> Result<u32> {
+        const QUERY: &str = "
+            SELECT COUNT(*) AS queued FROM queued_tracks
+            WHERE session_id = ? AND added_by = ?;
+        ";
+
+        let row = sqlx::query(QUERY)
+            .bind(session_id.to_string())
+            .bind(guest_id.to_string())
+            .fetch_one(&self.executor)
+            .await?;
+        let queued: i64 = row.try_get("queued")?;
+
+        Ok(queued as u32)
+    }
+
+    async fn create_session(&self, name: &str) -> Result<PlaySession> {
+        const QUERY: &str = "
+            INSERT INTO sessions
+                (id, name, access_token, refresh_token, expires_at, host_token)
+                VALUES (?, ?, '', '', NULL, ?);
+        ";
+        let uuid = Uuid::new_v4();
+        let host_token = PlaySession::new_host_token();
+        sqlx::query(QUERY)
+            .bind(uuid.to_string())
+            .bind(name)
+            .bind(&host_token)
+            .execute(&self.executor)
+            .await?;
+
+        Ok(PlaySession {
+            id: uuid,
+            name: name.to_owned(),
+            token: None,
+            device_id: None,
+            host_token: Some(host_token),
+            fallback_playlist: None,
+        })
+    }
+
+    async fn update_session(&self, session: &PlaySession) -> Result<()> {
+        const QUERY: &str = "
+            UPDATE sessions
+            SET name = ?, access_token = ?, refresh_token = ?, expires_at = ?
+            WHERE id = ?;
+        ";
+
+        if let None = session.token {
+            return Err(anyhow::Error::msg(
+                "can't update database with unauthenticated session",
+            ));
+        }
+        let token = session.token.as_ref().unwrap();
+        let refresh_token = token.refresh_token.as_deref().unwrap_or("");
+
+        sqlx::query(QUERY)
+            .bind(&session.name)
+            .bind(&token.access_token)
+            .bind(refresh_token)
+            .bind(token.expires_at)
+            .bind(session.id.to_string())
+            .execute(&self.executor)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_session_device(
+        &self,
+        session_id: Uuid,
+        device_id: Option<&str>,
+    ) -> Result<()> {
+        const QUERY: &str = "
+            UPDATE sessions SET device_id = ? WHERE id = ?;
+        ";
+
+        let result = sqlx::query(QUERY)
+            .bind(device_id)
+            .bind(session_id.to_string())
+            .execute(&self.executor)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(anyhow::Error::msg(format!(
+                "no session with id {}",
+                session_id
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn set_session_fallback_playlist(
+        &self,
+        session_id: Uuid,
+        playlist_id: Option<&str>,
+    ) -> Result<()> {
+        const QUERY: &str = "
+            UPDATE sessions SET fallback_playlist = ? WHERE id = ?;
+        ";
+
+        let result = sqlx::query(QUERY)
+            .bind(playlist_id)
+            .bind(session_id.to_string())
+            .execute(&self.executor)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(anyhow::Error::msg(format!(
+                "no session with id {}",
+                session_id
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn get_session(&self, id: Uuid) -> Result<Option<PlaySession>> {
+        const QUERY: &str = "
+            SELECT * FROM sessions WHERE id = ?;
+        ";
+
+        let maybe_row = sqlx::query(QUERY)
+            .bind(id.to_string())
+            .fetch_optional(&self.executor)
+            .await?;
+
+        let res = maybe_row.map(|row| -> Result<PlaySession> {
+            let name: String = row.try_get("name")?;
+            let access_token: String = row.try_get("access_token")?;
+            let refresh_token: String = row.try_get("refresh_token")?;
+            let expires_at: Option<DateTime<Utc>> = row.try_get("expires_at")?;
+            let device_id: Option<String> = row.try_get("device_id")?;
+            let host_token: Option<String> = row.try_get("host_token")?;
+            let fallback_playlist: Option<String> = row.try_get("fallback_playlist")?;
+
+            if access_token == "" {
+                return Ok(PlaySession {
+                    id,
+                    name,
+                    token: None,
+                    device_id,
+                    host_token,
+                    fallback_playlist,
+                });
+            }
+
+            let mut token = Token::default();
+            token.access_token = access_token;
+            token.expires_at = expires_at;
+            token.refresh_token = Some(refresh_token).filter(|rt| rt != "");
+            token.scopes = scopes();
+
+            Ok(PlaySession {
+                id,
+                name,
+                token: Some(token),
+                device_id,
+                host_token,
+                fallback_playlist,
+            })
+        });
+
+        res.transpose()
+    }
+}
+
+fn extract_queued_track_from_row(row: &SqliteRow) -> Result<QueuedTrack> {
+    let entry_id = row.try_get("entry_id")?;
+    let upvotes: i64 = row.try_get("upvotes")?;
+    let downvotes: i64 = row.try_get("downvotes")?;
+    let added_by: Option<String> = row.try_get("added_by")?;
+
+    Ok(QueuedTrack {
+        id: entry_id,
+        track: extract_track_from_row(row)?,
+        added_by: added_by.map(|id| Uuid::parse_str(&id)).transpose()?,
+        upvotes: upvotes as u32,
+        downvotes: downvotes as u32,
+    })
+}
+
+fn extract_track_from_row(row: &SqliteRow) -> Result<SpotifyTrack> {
+    let track_id = row.try_get("track_id")?;
+    let track_name = row.try_get("track_name")?;
+    let track_duration: i64 = row.try_get("track_dur")?;
+
+    let album_id = row.try_get("album_id")?;
+    let album_name = row.try_get("album_name")?;
+    let album_cover_image_url = row.try_get("album_image")?;
+
+    let track = SpotifyTrack {
+        id: track_id,
+        name: track_name,
+        duration: Duration::from_millis(track_duration as u64),
+        album: SpotifyAlbum {
+            id: album_id,
+            name: album_name,
+            cover_image_url: album_cover_image_url,
+        },
+        artists: Vec::new(),
+    };
+
+    Ok(track)
+}
+
+mod queries {
+    pub const CREATE_TRACKS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS tracks (
+        name text,
+        id text PRIMARY KEY,
+        album_id text REFERENCES albums (id),
+        duration integer
+    );
+";
+
+    pub const CREATE_ARTIST_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS artists (
+        name text,
+        id text PRIMARY KEY
+    );
+";
+
+    pub const CREATE_ARTIST_TO_TRACK_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS artist_to_track (
+        track_id text REFERENCES tracks (id),
+        artist_id text REFERENCES artists (id),
+        PRIMARY KEY(track_id, artist_id)
+    );
+";
+
+    pub const CREATE_ALUBMS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS albums (
+        id text PRIMARY KEY,
+        name text,
+        cover_image_url text
+    );
+";
+
+    pub const CREATE_PLAYED_TRACKS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS played_tracks (
+        id integer PRIMARY KEY AUTOINCREMENT,
+        played_date timestamp DEFAULT current_timestamp,
+        session_id text REFERENCES sessions (id),
+        track_id text REFERENCES tracks (id)
+    );
+";
+
+    pub const CREATE_TRACK_QUEUE_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS queued_tracks (
+        id integer PRIMARY KEY AUTOINCREMENT,
+        added_date timestamp DEFAULT current_timestamp,
+        session_id text REFERENCES sessions (id),
+        track_id text REFERENCES tracks (id)
+    );
+";
+
+    pub const CREATE_SESSION_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS sessions (
+        id text PRIMARY KEY,
+        name text,
+        access_token text,
+        refresh_token text,
+        expires_at timestamp
+    );
+";
+
+    pub const ADD_POSITION_TO_ARTIST_TO_TRACK: &str = "
+    ALTER TABLE artist_to_track ADD COLUMN position integer;
+";
+
+    pub const CREATE_VOTES_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS votes (
+        queue_entry_id integer REFERENCES queued_tracks (id) ON DELETE CASCADE,
+        guest_id text,
+        value smallint,
+        PRIMARY KEY(queue_entry_id, guest_id)
+    );
+";
+
+    pub const CREATE_GUESTS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS guests (
+        id text PRIMARY KEY,
+        session_id text REFERENCES sessions (id),
+        created_at timestamp DEFAULT current_timestamp
+    );
+";
+
+    pub const ADD_GUEST_TO_QUEUED_TRACKS: &str = "
+    ALTER TABLE queued_tracks
+        ADD COLUMN added_by text REFERENCES guests (id);
+";
+
+    pub const ADD_DEVICE_TO_SESSIONS: &str = "
+    ALTER TABLE sessions ADD COLUMN device_id text;
+";
+
+    pub const ADD_POSITION_TO_QUEUED_TRACKS: &str = "
+    ALTER TABLE queued_tracks ADD COLUMN position integer;
+";
+
+    pub const ADD_REQUESTER_TO_PLAYED_TRACKS: &str = "
+    ALTER TABLE played_tracks
+        ADD COLUMN requested_by text REFERENCES guests (id);
+";
+
+    pub const ADD_AUTOFILL_TO_PLAYED_TRACKS: &str = "
+    ALTER TABLE played_tracks
+        ADD COLUMN autofill boolean NOT NULL DEFAULT false;
+";
+
+    pub const ADD_SKIPPED_TO_PLAYED_TRACKS: &str = "
+    ALTER TABLE played_tracks
+        ADD COLUMN skipped boolean NOT NULL DEFAULT false;
+";
+
+    pub const ADD_FALLBACK_PLAYLIST_TO_SESSIONS: &str = "
+    ALTER TABLE sessions ADD COLUMN fallback_playlist text;
+";
+
+    pub const ADD_HOST_TOKEN_TO_SESSIONS: &str = "
+    ALTER TABLE sessions ADD COLUMN host_token text;
+";
+
+    pub const CREATE_SCHEMA_VERSION_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS schema_version (
+        version integer PRIMARY KEY,
+        description text,
+        applied_at timestamp DEFAULT current_timestamp
+    );
+";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn setup_db() -> SqliteDatabase {
+        //every connection to :memory: gets its own database, so the pool must only hold one
+        let options = SqliteConnectOptions::from_str("sqlite::memory:")
+            .unwrap()
+            .foreign_keys(true);
+        let conn_pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(options)
+            .await
+            .unwrap();
+
+        let db = SqliteDatabase {
+            executor: conn_pool,
+        };
+        db.migrate().await.unwrap();
+        db
+    }
+
+    persistent_store_tests!(setup_db());
+}
@@ -1,269 +1,1672 @@
-use std::{collections::VecDeque, time::Duration};
+use std::{
+    cmp::Reverse,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::{Error, Result};
-use rspotify::{
-    clients::{BaseClient, OAuthClient},
-    model::{AdditionalType, CurrentPlaybackContext, Device, PlayableItem, TrackId},
-    AuthCodeSpotify,
-};
+use ddj_core::types::{Device, PlayerEvent, PlayerHealth, PlayerStatus, QueueRejection};
+use tokio::select;
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::oneshot;
+use tokio::sync::{broadcast, oneshot, watch, Mutex};
+use tokio::task::{JoinError, JoinHandle};
+use uuid::Uuid;
+
+use crate::{
+    config::Config,
+    limits::{AddHistory, QueueLimits},
+    metrics::{ManagedMetrics, QueueAddOutcome},
+    model::{QueueEntry, TrackInfo},
+    persistence::{
+        model::{Requester, SpotifyTrack, Vote},
+        Store,
+    },
+    provider::{Playback, PlaybackDevice, Provider},
+};
+
+///Maximum number of queued tracks returned when the queue is inspected
+const QUEUE_VIEW_LIMIT: u32 = 100;
+
+///How many events a slow subscriber can fall behind before it starts missing them
+const EVENT_BUFFER: usize = 32;
+
+///How far a track can be from its end when it changes before it counts as skipped
+const SKIP_TOLERANCE: Duration = Duration::from_secs(5);
+
+///How long past the end of the current track the player waits for a track it handed
+/// off to start before giving up on it
+const HAND_OFF_GRACE: Duration = Duration::from_secs(30);
+
+///How many of the latest plays are avoided when the player fills in for an empty queue
+const AUTOFILL_HISTORY: u32 = 20;
+
+///How many of the latest plays seed recommendations
+const AUTOFILL_SEEDS: usize = 5;
+
+///How many recommendations to choose from when filling in
+const AUTOFILL_CANDIDATES: u32 = 20;
+
+///How long the supervisor waits before restarting a crashed player, doubled for every
+/// crash in a row
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+///Longest the supervisor waits before restarting a player
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+///How long a restarted player has to run before its earlier crashes stop lengthening
+/// the wait for the next restart
+const STABLE_RUN: Duration = Duration::from_secs(5 * 60);
+
+///Settings shared by every session's player
+#[derive(Clone, Debug)]
+pub struct PlayerSettings {
+    pub limits: QueueLimits,
+    ///How often the cached playback state is refreshed from the provider
+    pub playback_refresh: Duration,
+    ///How long before the end of the current track the next one is handed to the
+    /// provider
+    pub queue_lead_time: Duration,
+    ///Whether the player fills in with tracks of its own when the queue runs dry
+    pub autofill: bool,
+}
+
+impl PlayerSettings {
+    pub fn from_config(config: &Config) -> PlayerSettings {
+        PlayerSettings {
+            limits: config.limits.queue_limits(),
+            playback_refresh: config.player.playback_refresh(),
+            queue_lead_time: config.player.queue_lead_time(),
+            autofill: config.player.autofill,
+        }
+    }
+}
+
+///Playback state as last fetched from the music provider
+#[derive(Clone, Debug)]
+pub struct PlaybackSnapshot {
+    pub track: Option<TrackInfo>,
+    pub is_playing: bool,
+    pub progress: Option<Duration>,
+    pub device: Option<PlaybackDevice>,
+    pub fetched_at: Instant,
+    ///Whether the track is one the player picked itself because the queue was empty
+    pub autofill: bool,
+}
+
+impl PlaybackSnapshot {
+    fn empty() -> PlaybackSnapshot {
+        PlaybackSnapshot {
+            track: None,
+            is_playing: false,
+            progress: None,
+            device: None,
+            fetched_at: Instant::now(),
+            autofill: false,
+        }
+    }
+
+    fn from_playback(playback: Option<Playback>) -> PlaybackSnapshot {
+        match playback {
+            Some(playback) => PlaybackSnapshot {
+                track: playback.track,
+                is_playing: playback.is_playing,
+                progress: playback.progress,
+                device: playback.device,
+                fetched_at: Instant::now(),
+                autofill: false,
+            },
+            None => PlaybackSnapshot::empty(),
+        }
+    }
+
+    ///When a track handed off during this snapshot should have started by
+    fn hand_off_deadline(&self) -> Instant {
+        self.fetched_at + self.remaining().unwrap_or(Duration::ZERO) + HAND_OFF_GRACE
+    }
+
+    ///How long ago the snapshot was taken
+    pub fn age(&self) -> Duration {
+        self.fetched_at.elapsed()
+    }
+
+    ///Time left in the current track when the snapshot was taken
+    pub fn remaining(&self) -> Option<Duration> {
+        let track = self.track.as_ref()?;
+        let progress = self.progress?;
+        Some(track.duration.saturating_sub(progress))
+    }
+
+    ///Whether the track in this snapshot was left early for the one in `next`, as
+    /// opposed to playing through to its end
+    fn was_skipped(&self, next: &PlaybackSnapshot) -> bool {
+        let (previous, current) = match (&self.track, &next.track) {
+            (Some(previous), Some(current)) => (previous, current),
+            _ => return false,
+        };
+        if previous.id.0 == current.id.0 || !self.is_playing {
+            return false;
+        }
 
-use crate::{authentication::ManagedAuthState, model::TrackInfo};
+        let remaining = match self.remaining() {
+            Some(remaining) => remaining,
+            None => return false,
+        };
+        let elapsed = next.fetched_at.saturating_duration_since(self.fetched_at);
+        remaining.saturating_sub(elapsed) > SKIP_TOLERANCE
+    }
+}
 
 pub type PlayerCommandQueue = Sender<PlayerCommand>;
 
+#[derive(Debug, thiserror::Error)]
+pub enum AddTrackError {
+    #[error("{0}")]
+    Rejected(QueueRejection),
+
+    #[error("no track found with id {0}")]
+    UnknownTrack(String),
+
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+///What became of a request to take an entry out of the queue
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemoveEntryOutcome {
+    Removed,
+    ///The entry isn't in the session's queue
+    NotFound,
+    ///The entry was queued by someone other than the guest asking to remove it
+    NotOwner,
+}
+
+#[derive(Clone)]
 pub struct PlayerCommader {
     sender: PlayerCommandQueue,
+    health: watch::Receiver<PlayerHealth>,
+    ///Dropped along with the last commander, which tells the player to stop
+    _handle: Arc<watch::Sender<()>>,
 }
 
 impl PlayerCommader {
-    fn new(sender: PlayerCommandQueue) -> PlayerCommader {
-        return PlayerCommader { sender: sender };
+    fn new(
+        sender: PlayerCommandQueue,
+        health: watch::Receiver<PlayerHealth>,
+        handle: watch::Sender<()>,
+    ) -> PlayerCommader {
+        return PlayerCommader {
+            sender: sender,
+            health: health,
+            _handle: Arc::new(handle),
+        };
     }
 
-    pub async fn get_currently_playing_track(&self) -> Result<Option<TrackInfo>> {
+    ///How the player has been doing, as its supervisor last saw it
+    pub fn health(&self) -> PlayerHealth {
+        self.health.borrow().clone()
+    }
+
+    ///The cached playback state, which may be up to one refresh interval old
+    pub async fn get_currently_playing_track(&self) -> Result<PlaybackSnapshot> {
         let (tx, rx) = tokio::sync::oneshot::channel();
         self.sender.send(PlayerCommand::GetCurrentTrack(tx)).await?;
 
-        return Ok(rx.await.unwrap());
+        return rx.await?;
     }
 
-    pub async fn add_track_to_queue(&self, track_id: TrackId) -> Result<()> {
-        self.sender.send(PlayerCommand::AddTrack(track_id)).await?;
-        Ok(())
+    ///Add a track on behalf of a guest, subject to the session's queue limits
+    pub async fn add_track_to_queue(
+        &self,
+        track_id: String,
+        guest_id: Option<Uuid>,
+    ) -> Result<(), AddTrackError> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.sender
+            .send(PlayerCommand::AddTrack(track_id, guest_id, tx))
+            .await
+            .map_err(anyhow::Error::from)?;
+        rx.await.map_err(anyhow::Error::from)?
     }
 
-    pub async fn get_queued_tracks(&self) -> Result<Vec<TrackInfo>> {
+    pub async fn get_queued_tracks(&self) -> Result<Vec<QueueEntry>> {
         let (tx, rx) = tokio::sync::oneshot::channel();
         self.sender.send(PlayerCommand::GetTrackQueue(tx)).await?;
-        Ok(rx.await.unwrap())
+        rx.await?
+    }
+
+    ///Record a guest's vote on a queue entry, or withdraw it when `vote` is None.
+    /// Returns false if the entry isn't in this session's queue.
+    pub async fn vote(
+        &self,
+        entry_id: i32,
+        guest_id: Uuid,
+        vote: Option<Vote>,
+    ) -> Result<bool> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.sender
+            .send(PlayerCommand::Vote(entry_id, guest_id, vote, tx))
+            .await?;
+        rx.await?
+    }
+
+    ///Take an entry out of the queue. When `owner` is set the entry is only removed if
+    /// that guest queued it.
+    pub async fn remove_entry(
+        &self,
+        entry_id: i32,
+        owner: Option<Uuid>,
+    ) -> Result<RemoveEntryOutcome> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.sender
+            .send(PlayerCommand::RemoveEntry(entry_id, owner, tx))
+            .await?;
+        rx.await?
+    }
+
+    ///Move an entry to a position in the queue, 0 being next. Returns false if the
+    /// entry isn't in this session's queue.
+    pub async fn move_entry(&self, entry_id: i32, position: u32) -> Result<bool> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.sender
+            .send(PlayerCommand::MoveEntry(entry_id, position, tx))
+            .await?;
+        rx.await?
+    }
+
+    ///Empty the queue, returning how many entries were removed
+    pub async fn clear_queue(&self) -> Result<u32> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.sender.send(PlayerCommand::ClearQueue(tx)).await?;
+        rx.await?
     }
 
     pub async fn start(&self) -> Result<()> {
-        self.sender.send(PlayerCommand::Start).await?;
+        self.control(PlayerCommand::Start).await
+    }
+
+    ///Send a playback control and wait for the provider to carry it out
+    async fn control<F>(&self, command: F) -> Result<()>
+    where
+        F: FnOnce(oneshot::Sender<Result<()>>) -> PlayerCommand,
+    {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.sender.send(command(tx)).await?;
+        rx.await?
+    }
+
+    pub async fn pause(&self) -> Result<()> {
+        self.control(PlayerCommand::Pause).await
+    }
+
+    pub async fn resume(&self) -> Result<()> {
+        self.control(PlayerCommand::Resume).await
+    }
+
+    ///Skip the current track. The next track in the session's queue plays next.
+    pub async fn skip(&self) -> Result<()> {
+        self.control(PlayerCommand::Skip).await
+    }
+
+    pub async fn previous(&self) -> Result<()> {
+        self.control(PlayerCommand::Previous).await
+    }
+
+    pub async fn set_volume(&self, percent: u8) -> Result<()> {
+        self.control(|tx| PlayerCommand::SetVolume(percent, tx))
+            .await
+    }
+
+    pub async fn seek(&self, position: Duration) -> Result<()> {
+        self.control(|tx| PlayerCommand::Seek(position, tx)).await
+    }
+
+    pub async fn search(&self, query: String, limit: u32) -> Result<Vec<TrackInfo>> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.sender
+            .send(PlayerCommand::Search(query, limit, tx))
+            .await?;
+        rx.await?
+    }
+
+    ///Set or clear the playlist the player falls back on, false if the provider doesn't
+    /// know the playlist
+    pub async fn set_fallback_playlist(
+        &self,
+        playlist_id: Option<String>,
+    ) -> Result<bool> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.sender
+            .send(PlayerCommand::SetFallbackPlaylist(playlist_id, tx))
+            .await?;
+        rx.await?
+    }
+
+    pub async fn devices(&self) -> Result<Vec<Device>> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.sender.send(PlayerCommand::GetDevices(tx)).await?;
+        rx.await?
+    }
+
+    ///Play the session on a device from now on. Returns false if there is no such
+    /// device.
+    pub async fn select_device(&self, device_id: String) -> Result<bool> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.sender
+            .send(PlayerCommand::SelectDevice(device_id, tx))
+            .await?;
+        rx.await?
+    }
+
+    ///Receive every event the player emits from now on. The channel closes when the
+    /// player shuts down.
+    pub async fn subscribe(&self) -> Result<broadcast::Receiver<PlayerEvent>> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.sender.send(PlayerCommand::Subscribe(tx)).await?;
+        Ok(rx.await?)
+    }
+
+    pub async fn is_idle(&self) -> Result<bool> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.sender.send(PlayerCommand::IsIdle(tx)).await?;
+        rx.await?
+    }
+
+    pub async fn shutdown(&self) -> Result<()> {
+        self.sender.send(PlayerCommand::Shutdown).await?;
         Ok(())
     }
 }
 
+///Commands waiting for the player. Shared with its supervisor so they outlive a player
+/// which crashes.
+type SharedCommandReceiver = Arc<Mutex<Receiver<PlayerCommand>>>;
+
+///The Wake a player has scheduled. Shared with its supervisor, which cancels it when the
+/// player crashes so it can't reach the player started in its place.
+#[derive(Clone, Default)]
+struct WakeTimer(Arc<std::sync::Mutex<Option<JoinHandle<()>>>>);
+
+impl WakeTimer {
+    ///Replace the scheduled wake, if there is one
+    fn set(&self, timer: JoinHandle<()>) {
+        if let Some(previous) = self.0.lock().unwrap().replace(timer) {
+            previous.abort();
+        }
+    }
+
+    fn cancel(&self) {
+        if let Some(timer) = self.0.lock().unwrap().take() {
+            timer.abort();
+        }
+    }
+}
+
+///What a player is restarted with after a crash. Its channels are kept, so commanders
+/// and subscribers carry on with the new player as if nothing happened.
+struct PlayerContext {
+    provider: Provider,
+    store: Store,
+    session_id: Uuid,
+    settings: PlayerSettings,
+    cmd_rx: SharedCommandReceiver,
+    cmd_tx: PlayerCommandQueue,
+    ///Closed once every commander for the player has been dropped
+    commanders: watch::Receiver<()>,
+    wake_timer: WakeTimer,
+    events: broadcast::Sender<PlayerEvent>,
+    health: Arc<watch::Sender<PlayerHealth>>,
+    metrics: ManagedMetrics,
+}
+
+impl PlayerContext {
+    ///A fresh player picking up the device and fallback playlist saved for the
+    /// session. None if the session is gone.
+    async fn restore(&self) -> Result<Option<PlayerState>> {
+        let session = match self.store.get_session(self.session_id).await? {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+        Ok(Some(PlayerState::new(
+            self,
+            session.device_id,
+            session.fallback_playlist,
+        )))
+    }
+
+    fn set_status(&self, status: PlayerStatus) {
+        self.health.send_modify(|health| health.status = status);
+    }
+}
+
 struct PlayerState {
-    auth_state: ManagedAuthState,
-    queue: VecDeque<TrackInfo>,
-    cmd_rx: Receiver<PlayerCommand>,
+    provider: Provider,
+    store: Store,
+    session_id: Uuid,
+    cmd_rx: SharedCommandReceiver,
     cmd_tx: PlayerCommandQueue,
-    target_device: Option<Device>,
+    target_device: Option<PlaybackDevice>,
+    ///Id of the device the host picked for the session, if they have
+    selected_device: Option<String>,
+    ///Playlist to fill in from when the queue runs dry, recommendations are used without one
+    fallback_playlist: Option<String>,
+    ///Id of the last track the player picked itself because the queue was empty
+    autofilled: Option<String>,
+    playback: Option<PlaybackSnapshot>,
+    settings: PlayerSettings,
+    add_history: AddHistory,
+    events: broadcast::Sender<PlayerEvent>,
+    health: Arc<watch::Sender<PlayerHealth>>,
+    metrics: ManagedMetrics,
+    ///Order of the queue as it was last announced, to tell when votes reorder it
+    announced_order: Vec<i32>,
+    ///Whether the player has been started and should keep the music going
+    active: bool,
+    ///Track handed to the provider that hasn't started playing yet
+    handed_off: Option<HandOff>,
+    commanders: watch::Receiver<()>,
+    wake_timer: WakeTimer,
+}
+
+///A track handed to the provider to play next. No other track is handed off until it
+/// starts or the player gives up waiting on it.
+#[derive(Clone, Debug)]
+struct HandOff {
+    track_id: String,
+    ///Track which was playing when it was handed off, the one it is meant to follow
+    after: Option<String>,
+    ///When to stop waiting for it, pushed back for as long as playback is paused
+    deadline: Instant,
 }
 
 #[derive(Debug)]
 pub enum PlayerCommand {
     ///Start the player from pause
-    Start,
+    Start(oneshot::Sender<Result<()>>),
 
-    ///Wake and configure the next track. Sent on a timer
+    ///Check the playback position and queue the next track if it is time. Sent on a
+    /// timer shortly before the current track ends
     Wake,
 
-    ///Add a track suggestion to the queue
-    AddTrack(TrackId),
+    ///Add a track suggestion to the queue, optionally on behalf of a guest
+    AddTrack(
+        String,
+        Option<Uuid>,
+        oneshot::Sender<Result<(), AddTrackError>>,
+    ),
 
-    ///Return the currently playing track using the sender
-    GetCurrentTrack(oneshot::Sender<Option<TrackInfo>>),
+    ///Refresh the cached playback state. Sent on a timer
+    RefreshPlayback,
 
-    ///Return the queue of tracks
-    GetTrackQueue(oneshot::Sender<Vec<TrackInfo>>),
+    ///Return the cached playback state using the sender
+    GetCurrentTrack(oneshot::Sender<Result<PlaybackSnapshot>>),
+
+    ///Return the queue of tracks, highest voted first
+    GetTrackQueue(oneshot::Sender<Result<Vec<QueueEntry>>>),
+
+    ///Set or clear a guest's vote on a queue entry
+    Vote(i32, Uuid, Option<Vote>, oneshot::Sender<Result<bool>>),
+
+    ///Remove a queue entry, only if the given guest queued it when one is given
+    RemoveEntry(
+        i32,
+        Option<Uuid>,
+        oneshot::Sender<Result<RemoveEntryOutcome>>,
+    ),
+
+    ///Move a queue entry to a position, answering false if there is no such entry
+    MoveEntry(i32, u32, oneshot::Sender<Result<bool>>),
+
+    ///Remove every entry from the queue
+    ClearQueue(oneshot::Sender<Result<u32>>),
+
+    ///Pause playback on the session's device
+    Pause(oneshot::Sender<Result<()>>),
+
+    ///Resume playback on the session's device
+    Resume(oneshot::Sender<Result<()>>),
+
+    ///Skip the current track, moving the queue on with it
+    Skip(oneshot::Sender<Result<()>>),
+
+    ///Go back to the previous track
+    Previous(oneshot::Sender<Result<()>>),
+
+    ///Set the device volume, from 0 to 100
+    SetVolume(u8, oneshot::Sender<Result<()>>),
+
+    ///Jump to a position in the current track
+    Seek(Duration, oneshot::Sender<Result<()>>),
+
+    ///Search the session's music provider for tracks, returning at most the given
+    /// number of results
+    Search(String, u32, oneshot::Sender<Result<Vec<TrackInfo>>>),
+
+    ///Set or clear the playlist the player fills in from, answering false if the provider
+    /// doesn't know the playlist
+    SetFallbackPlaylist(Option<String>, oneshot::Sender<Result<bool>>),
+
+    ///Return the devices the session could play on
+    GetDevices(oneshot::Sender<Result<Vec<Device>>>),
+
+    ///Switch the session to another device, answering false if there is no such device
+    SelectDevice(String, oneshot::Sender<Result<bool>>),
+
+    ///Return a receiver for the player's events
+    Subscribe(oneshot::Sender<broadcast::Receiver<PlayerEvent>>),
+
+    ///Report whether the player has nothing left to do
+    IsIdle(oneshot::Sender<Result<bool>>),
+
+    ///Stop the player task
+    Shutdown,
 }
 
 impl PlayerState {
-    fn new(auth_state: ManagedAuthState) -> (PlayerState, PlayerCommandQueue) {
-        let (tx, rx) = tokio::sync::mpsc::channel(64); //TODO: consider unbounded channel here
-        (
-            PlayerState {
-                auth_state: auth_state,
-                queue: VecDeque::new(),
-                cmd_rx: rx,
-                cmd_tx: tx.clone(),
-                target_device: None,
-            },
-            tx,
-        )
+    fn new(
+        context: &PlayerContext,
+        selected_device: Option<String>,
+        fallback_playlist: Option<String>,
+    ) -> PlayerState {
+        PlayerState {
+            provider: context.provider.clone(),
+            store: context.store.clone(),
+            session_id: context.session_id,
+            cmd_rx: context.cmd_rx.clone(),
+            cmd_tx: context.cmd_tx.clone(),
+            target_device: None,
+            selected_device: selected_device,
+            fallback_playlist: fallback_playlist,
+            autofilled: None,
+            playback: None,
+            settings: context.settings.clone(),
+            add_history: AddHistory::default(),
+            events: context.events.clone(),
+            health: context.health.clone(),
+            metrics: context.metrics.clone(),
+            announced_order: Vec::new(),
+            active: false,
+            handed_off: None,
+            commanders: context.commanders.clone(),
+            wake_timer: context.wake_timer.clone(),
+        }
     }
 
-    async fn spotify(&self) -> Option<AuthCodeSpotify> {
-        let mut auth_value = self.auth_state.lock().await;
-        if auth_value.is_none() {
-            return None;
-        } else {
-            let a = auth_value.as_mut().unwrap();
-            return Some(a.client().await);
+    ///The next command, or None once every commander for the player has been dropped.
+    /// The timers and the refresher hold senders of their own, so the channel alone
+    /// never closes.
+    async fn next_command(&mut self) -> Option<PlayerCommand> {
+        let mut cmd_rx = self.cmd_rx.lock().await;
+        select! {
+            cmd = cmd_rx.recv() => cmd,
+            //nothing is ever sent, so this only returns once the commanders are gone
+            _ = self.commanders.changed() => None,
         }
     }
 
-    async fn find_target_device(&mut self) -> Result<()> {
-        if let Some(spotify) = self.spotify().await {
-            let playback = spotify
-                .current_playback(
-                    None,
-                    Some([&AdditionalType::Track, &AdditionalType::Episode]),
-                )
-                .await?;
+    ///Pick up after the player before this one crashed. Whether it was keeping the music
+    /// going died with it, so this one does if anything is still playing. A track it had
+    /// already handed to the provider may be handed over again.
+    async fn recover(&mut self) -> Result<()> {
+        self.refresh_playback().await?;
+        self.active = self.playback.as_ref().map_or(false, |p| p.is_playing);
+        self.update_schedule(None).await
+    }
 
-            match playback {
-                Some(player) => {
-                    let device = player.device;
-                    self.target_device = Some(device);
+    ///Count playback refreshes which failed in a row, for the player's health
+    fn record_refresh(&self, refreshed: bool) {
+        self.health.send_modify(|health| {
+            health.failed_refreshes = match refreshed {
+                true => 0,
+                false => health.failed_refreshes + 1,
+            }
+        });
+    }
+
+    ///Fetch the playback state from the provider and cache it, telling subscribers if a
+    /// different track is playing and rescheduling the next track around it
+    async fn refresh_playback(&mut self) -> Result<()> {
+        let playback = self.provider.current_playback().await?;
+        let mut snapshot = PlaybackSnapshot::from_playback(playback);
+        snapshot.autofill = match (&snapshot.track, &self.autofilled) {
+            (Some(track), Some(autofilled)) => &track.id.0 == autofilled,
+            _ => false,
+        };
+
+        let previous_id = self
+            .playback
+            .as_ref()
+            .and_then(|playback| playback.track.as_ref())
+            .map(|track| &track.id.0);
+        let current_id = snapshot.track.as_ref().map(|track| &track.id.0);
+        if self.playback.is_none() || previous_id != current_id {
+            self.emit(PlayerEvent::TrackChanged {
+                current_track: snapshot.track.as_ref().map(|track| track.into()),
+                autofill: snapshot.autofill,
+            });
+        }
+
+        let previous = self.playback.replace(snapshot);
+        self.update_schedule(previous).await
+    }
 
-                    Ok(())
+    ///Work out when the next track has to be handed to the provider from the latest playback
+    /// snapshot, queueing it straight away if that time has already come
+    async fn update_schedule(
+        &mut self,
+        previous: Option<PlaybackSnapshot>,
+    ) -> Result<()> {
+        let playback = match &self.playback {
+            Some(playback) => playback.clone(),
+            None => return Ok(()),
+        };
+        let current_id = playback.track.as_ref().map(|track| track.id.0.clone());
+
+        if let Some(previous) = &previous {
+            if previous.was_skipped(&playback) {
+                println!("current track was skipped, rescheduling");
+                if let Some(skipped) = &previous.track {
+                    if let Err(e) = self
+                        .store
+                        .mark_play_skipped(self.session_id, &skipped.id.0)
+                        .await
+                    {
+                        println!("failed to record skipped track: {}", e);
+                    }
                 }
-                None => Err(Error::msg("no playback device available")),
             }
+        }
+
+        if let Some(handed_off) = &mut self.handed_off {
+            if !playback.is_playing {
+                handed_off.deadline = playback.hand_off_deadline();
+            }
+        }
+        if let Some(handed_off) = &self.handed_off {
+            if current_id.as_ref() == Some(&handed_off.track_id) {
+                //the track we handed off has started
+                self.handed_off = None;
+            } else if current_id != handed_off.after {
+                //skipped past it, or the provider dropped its queue for something else
+                println!("{} was passed over", handed_off.track_id);
+                self.handed_off = None;
+            } else if Instant::now() >= handed_off.deadline {
+                println!("{} never started", handed_off.track_id);
+                self.handed_off = None;
+            }
+        }
+
+        self.cancel_wake();
+        if !self.active || !playback.is_playing || self.handed_off.is_some() {
+            return Ok(());
+        }
+        let remaining = match playback.remaining() {
+            Some(remaining) => remaining,
+            None => return Ok(()),
+        };
+
+        let lead_time = self.settings.queue_lead_time;
+        if remaining <= lead_time {
+            self.advance_to_next_track().await
         } else {
+            self.schedule_wake(remaining - lead_time);
             Ok(())
         }
     }
 
+    ///Send a Wake after `delay`, replacing any wake that was already scheduled
+    fn schedule_wake(&mut self, delay: Duration) {
+        self.cancel_wake();
+        println!("waking player in {} seconds", delay.as_secs());
+
+        let tx_clone = self.cmd_tx.clone();
+        self.wake_timer.set(tokio::task::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Err(_) = tx_clone.send(PlayerCommand::Wake).await {
+                println!("player stopped before it could be woken");
+            }
+        }));
+    }
+
+    fn cancel_wake(&mut self) {
+        self.wake_timer.cancel();
+    }
+
+    ///Refresh the playback state and pick the device to send tracks to
+    async fn find_target_device(&mut self) -> Result<()> {
+        self.refresh_playback().await?;
+        match self.choose_device().await? {
+            Some(device) => {
+                self.target_device = Some(device);
+                Ok(())
+            }
+            None => Err(Error::msg("no playback device available")),
+        }
+    }
+
+    ///The device picked for the session if the provider still has it, otherwise the
+    /// one it is playing on
+    async fn choose_device(&self) -> Result<Option<PlaybackDevice>> {
+        let devices = self.provider.devices().await?;
+        if let Some(selected) = &self.selected_device {
+            if let Some(device) = devices.iter().find(|device| &device.id == selected) {
+                return Ok(Some(device.clone()));
+            }
+        }
+
+        let playing = self.playback.as_ref().and_then(|p| p.device.clone());
+        Ok(playing.or_else(|| devices.into_iter().find(|device| device.is_active)))
+    }
+
     async fn advance_to_next_track(&mut self) -> Result<()> {
-        if let Some(spotify) = self.spotify().await {
-            let playstate_response = spotify
-                .current_playback(
-                    None,
-                    Some([&AdditionalType::Track, &AdditionalType::Episode]),
-                )
+        if let None = self.target_device {
+            self.target_device = self.choose_device().await?;
+        }
+        let device_id = self.device_id()?;
+
+        let front = self.store.pop_track_from_queue(self.session_id).await?;
+        if let Some(entry) = front {
+            let requester = entry.requester();
+            self.setup_next_track(entry.track.into(), requester, &device_id)
                 .await?;
+            self.announce_queue().await;
+            Ok(())
+        } else {
+            self.autofill(&device_id).await
+        }
+    }
 
-            if playstate_response.is_none() {
-                return Err(anyhow::Error::msg("player state is unavailable"));
+    ///Keep the music going once the queue has run dry, with a track from the fallback
+    /// playlist or one recommended from what has played recently
+    async fn autofill(&mut self, device_id: &str) -> Result<()> {
+        if !self.settings.autofill {
+            println!("no track to play");
+            return Ok(());
+        }
+        let track = match self.pick_autofill_track().await? {
+            Some(track) => track,
+            None => {
+                println!("nothing to fill in with, no track to play");
+                return Ok(());
             }
+        };
 
-            let front = self.queue.pop_front();
-            if let Some(track_info) = front {
-                self.setup_next_track(track_info, self.device_id()).await;
-            } else {
-                println!("no track to play");
+        println!("queue is empty, filling in with {}", track.name);
+        let track_id = track.id.0.clone();
+        self.setup_next_track(track, Requester::Autofill, device_id)
+            .await?;
+        self.autofilled = Some(track_id);
+        Ok(())
+    }
+
+    ///Pick the candidate which played least recently, preferring ones which haven't
+    /// played at all
+    async fn pick_autofill_track(&self) -> Result<Option<TrackInfo>> {
+        let recent = self
+            .store
+            .recently_played(self.session_id, AUTOFILL_HISTORY)
+            .await?;
+
+        let candidates = match &self.fallback_playlist {
+            Some(playlist_id) => {
+                match self.provider.playlist_tracks(playlist_id).await? {
+                    Some(tracks) => tracks,
+                    None => {
+                        println!("fallback playlist {} has gone", playlist_id);
+                        Vec::new()
+                    }
+                }
+            }
+            None => {
+                let seeds: Vec<String> = recent
+                    .iter()
+                    .take(AUTOFILL_SEEDS)
+                    .map(|track| track.id.clone())
+                    .collect();
+                if seeds.is_empty() {
+                    return Ok(None);
+                }
+                self.provider
+                    .recommendations(&seeds, AUTOFILL_CANDIDATES)
+                    .await?
+            }
+        };
+
+        //recent plays are latest first, so the further back the better
+        Ok(candidates.into_iter().min_by_key(|candidate| {
+            Reverse(
+                recent
+                    .iter()
+                    .position(|played| played.id == candidate.id.0)
+                    .unwrap_or(usize::MAX),
+            )
+        }))
+    }
+
+    ///Cut an autofilled track short once something has been queued, anything queued
+    /// takes priority over tracks the player picked itself
+    async fn preempt_autofill(&mut self) -> Result<()> {
+        let autofill_playing = self
+            .playback
+            .as_ref()
+            .map_or(false, |playback| playback.autofill && playback.is_playing);
+        if !self.active || !autofill_playing || self.handed_off.is_some() {
+            return Ok(());
+        }
+        if self.get_queued_tracks().await?.is_empty() {
+            return Ok(());
+        }
+
+        println!("queued track preempts the autofilled one");
+        self.skip().await
+    }
+
+    async fn set_fallback_playlist(
+        &mut self,
+        playlist_id: Option<String>,
+    ) -> Result<bool> {
+        if let Some(playlist_id) = &playlist_id {
+            if self.provider.playlist_tracks(playlist_id).await?.is_none() {
+                return Ok(false);
             }
         }
 
+        self.store
+            .set_session_fallback_playlist(self.session_id, playlist_id.as_deref())
+            .await?;
+        self.fallback_playlist = playlist_id;
+        Ok(true)
+    }
+
+    fn device_id(&self) -> Result<String> {
+        match &self.target_device {
+            Some(device) => Ok(device.id.clone()),
+            None => Err(Error::msg("no playback device has been chosen")),
+        }
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        self.active = true;
+        self.find_target_device().await?;
+        let device_id = self.device_id()?;
+
+        let front = self.store.pop_track_from_queue(self.session_id).await?;
+        if let Some(entry) = front {
+            let requester = entry.requester();
+            self.setup_next_track(entry.track.into(), requester, &device_id)
+                .await?;
+            if let Err(e) = self.provider.skip_to_next(&device_id).await {
+                println!("failed to skip to the queued track: {}", e);
+            }
+            if let Err(e) = self.refresh_playback().await {
+                println!("failed to refresh playback state: {}", e);
+            }
+            self.announce_queue().await;
+        }
         Ok(())
     }
 
-    fn device_id(&self) -> &str {
-        self.target_device.as_ref().unwrap().id.as_ref().unwrap()
+    ///Device to send playback controls to, picking one if none has been yet
+    async fn control_device(&mut self) -> Result<String> {
+        if let None = self.target_device {
+            self.target_device = self.choose_device().await?;
+        }
+        self.device_id()
     }
 
-    async fn start(&mut self) {
-        match self.find_target_device().await {
-            Err(e) => println!("failed to find device: {}", e),
-            _ => (),
-        };
-        if let Some(track) = self.queue.pop_front() {
-            if let Some(spotify) = self.spotify().await {
-                let device_id = self.device_id();
-                self.setup_next_track(track, device_id).await;
-                spotify.next_track(Some(device_id)).await.unwrap();
-            }
-        }
-    }
-
-    async fn setup_next_track(&self, track: TrackInfo, device_id: &str) {
-        if let Some(spotify) = self.spotify().await {
-            spotify
-                .add_item_to_queue(&track.id.into_id::<TrackId>(), Some(device_id))
-                .await
-                .unwrap();
-
-            let tx_clone = self.cmd_tx.clone();
-            tokio::task::spawn(async move {
-                println!(
-                    "waking player thread in {} seconds",
-                    (track.duration - Duration::from_secs(10)).as_secs()
-                );
-                tokio::time::sleep(track.duration - Duration::from_secs(10)).await;
-                tx_clone.send(PlayerCommand::Wake).await.unwrap();
-            });
+    ///Move on to the next track right away. Our queue moves on too: if its next track
+    /// hasn't been handed to the provider yet it is handed over first, so the skip
+    /// lands on it rather than on whatever the provider had lined up.
+    async fn skip(&mut self) -> Result<()> {
+        let device_id = self.control_device().await?;
+        if let None = self.handed_off {
+            let front = self.store.pop_track_from_queue(self.session_id).await?;
+            if let Some(entry) = front {
+                let requester = entry.requester();
+                self.setup_next_track(entry.track.into(), requester, &device_id)
+                    .await?;
+                self.announce_queue().await;
+            }
         }
+
+        self.provider.skip_to_next(&device_id).await?;
+        self.refresh_playback().await
+    }
+
+    async fn previous(&mut self) -> Result<()> {
+        let device_id = self.control_device().await?;
+        self.provider.skip_to_previous(&device_id).await?;
+        self.refresh_playback().await
+    }
+
+    async fn pause(&mut self) -> Result<()> {
+        let device_id = self.control_device().await?;
+        self.provider.pause(&device_id).await?;
+        self.refresh_playback().await
     }
 
-    async fn add_track_to_queue(&mut self, track_id: TrackId) -> Result<()> {
-        if let Some(spotify) = self.spotify().await {
-            let full_track = spotify.track(&track_id).await?;
-            self.queue.push_back(full_track.into());
+    async fn resume(&mut self) -> Result<()> {
+        let device_id = self.control_device().await?;
+        self.provider.resume(&device_id).await?;
+        self.refresh_playback().await
+    }
+
+    async fn set_volume(&mut self, percent: u8) -> Result<()> {
+        let device_id = self.control_device().await?;
+        self.provider.set_volume(&device_id, percent).await
+    }
+
+    ///Jump within the current track, the next track is rescheduled around the new
+    /// position
+    async fn seek(&mut self, position: Duration) -> Result<()> {
+        let device_id = self.control_device().await?;
+        self.provider.seek(&device_id, position).await?;
+        self.refresh_playback().await
+    }
+
+    ///Devices the provider can play on, marking the one picked for the session
+    async fn list_devices(&self) -> Result<Vec<Device>> {
+        let devices = self.provider.devices().await?;
+        Ok(devices
+            .into_iter()
+            .map(|device| Device {
+                is_selected: self.selected_device.as_ref() == Some(&device.id),
+                id: device.id,
+                name: device.name,
+                is_active: device.is_active,
+                volume_percent: device.volume_percent,
+            })
+            .collect())
+    }
+
+    ///Play the session on another device from now on, moving anything already playing
+    /// over to it. Returns false if the provider doesn't know the device.
+    async fn select_device(&mut self, device_id: &str) -> Result<bool> {
+        let devices = self.provider.devices().await?;
+        let device = match devices.into_iter().find(|device| device.id == device_id) {
+            Some(device) => device,
+            None => return Ok(false),
+        };
+
+        let is_playing = self.playback.as_ref().map_or(false, |p| p.is_playing);
+        self.provider
+            .transfer_playback(&device.id, is_playing)
+            .await?;
+        self.store
+            .set_session_device(self.session_id, Some(&device.id))
+            .await?;
+
+        println!("session {} now plays on {}", self.session_id, device.name);
+        self.selected_device = Some(device.id.clone());
+        self.target_device = Some(device);
+        Ok(true)
+    }
+
+    ///Hand a track to the provider to play after the current one, adding it to the
+    /// session's history
+    async fn setup_next_track(
+        &mut self,
+        track: TrackInfo,
+        requester: Requester,
+        device_id: &str,
+    ) -> Result<()> {
+        self.provider.enqueue(&track.id.0, device_id).await?;
+        self.handed_off = Some(HandOff {
+            track_id: track.id.0.clone(),
+            after: self
+                .playback
+                .as_ref()
+                .and_then(|playback| playback.track.as_ref())
+                .map(|playing| playing.id.0.clone()),
+            deadline: self.playback.as_ref().map_or_else(
+                || Instant::now() + HAND_OFF_GRACE,
+                PlaybackSnapshot::hand_off_deadline,
+            ),
+        });
+        self.metrics.record_track_played(&requester);
+
+        if let Err(e) = self.record_play(&track, requester).await {
+            println!("failed to record played track: {}", e);
         }
         Ok(())
     }
 
-    async fn get_currently_playing(&self) -> Result<Option<TrackInfo>> {
-        if let Some(spotify) = self.spotify().await {
-            let playstate_response: Option<CurrentPlaybackContext> = spotify
-                .current_playback(
-                    None,
-                    Some([&AdditionalType::Track, &AdditionalType::Episode]),
-                )
+    ///Add a track to the session's history. Autofilled tracks were never queued, so
+    /// they are added to the catalog first
+    async fn record_play(&self, track: &TrackInfo, requester: Requester) -> Result<()> {
+        if requester == Requester::Autofill {
+            self.store.save_track(&SpotifyTrack::from(track)).await?;
+        }
+        self.store
+            .record_played_track(self.session_id, &track.id.0, requester)
+            .await
+    }
+
+    async fn add_track_to_queue(
+        &mut self,
+        track_id: String,
+        guest_id: Option<Uuid>,
+    ) -> Result<(), AddTrackError> {
+        let now = Instant::now();
+        if let Some(guest_id) = guest_id {
+            let queued = self
+                .store
+                .count_queued_by_guest(self.session_id, guest_id)
                 .await?;
+            self.settings
+                .limits
+                .check_queued(queued)
+                .map_err(AddTrackError::Rejected)?;
+            self.add_history
+                .check(&self.settings.limits, guest_id, now)
+                .map_err(AddTrackError::Rejected)?;
+        }
 
-            Ok(playstate_response
-                .map(|playersate| {
-                    playersate
-                        .item
-                        .map(|item| match item {
-                            PlayableItem::Track(full_track) => {
-                                Some(TrackInfo::from(full_track))
-                            }
-                            PlayableItem::Episode(_) => None,
-                        })
-                        .flatten()
-                })
-                .flatten())
-        } else {
-            Ok(None)
+        let track_info = match self.provider.track(&track_id).await? {
+            Some(track) => track,
+            None => return Err(AddTrackError::UnknownTrack(track_id)),
+        };
+        self.check_queue_rules(&track_info).await?;
+        self.store
+            .add_track_to_queue(self.session_id, (&track_info).into(), guest_id)
+            .await?;
+
+        if let Some(guest_id) = guest_id {
+            self.add_history.record(guest_id, now);
+        }
+        self.announce_queue().await;
+        Ok(())
+    }
+
+    ///Enforce the session's rules on what can be queued. Unlike the rate limits these
+    /// apply to everyone, not just guests.
+    async fn check_queue_rules(&self, track: &TrackInfo) -> Result<(), AddTrackError> {
+        let limits = &self.settings.limits;
+        let queue = self.get_queued_tracks().await?;
+        limits
+            .check_duplicate(
+                &track.id.0,
+                queue.iter().map(|entry| entry.track.id.0.as_str()),
+            )
+            .map_err(AddTrackError::Rejected)?;
+
+        if limits.replay_cooldown.is_some() {
+            let played_ago = self.store.last_played(self.session_id, &track.id.0).await?;
+            limits
+                .check_replay(played_ago)
+                .map_err(AddTrackError::Rejected)?;
+        }
+
+        if let Some(streak) = limits.max_artist_streak {
+            //the track will play after everything already played and queued
+            let mut preceding: Vec<TrackInfo> = self
+                .store
+                .recently_played(self.session_id, streak)
+                .await?
+                .into_iter()
+                .rev()
+                .map(TrackInfo::from)
+                .collect();
+            preceding.extend(queue.into_iter().map(|entry| entry.track));
+            limits
+                .check_artist_streak(track, &preceding)
+                .map_err(AddTrackError::Rejected)?;
+        }
+        Ok(())
+    }
+
+    ///Serve the cached playback state, only going to the provider if nothing has been
+    /// fetched yet
+    async fn get_currently_playing(&mut self) -> Result<PlaybackSnapshot> {
+        if let None = self.playback {
+            self.refresh_playback().await?;
         }
+        Ok(self
+            .playback
+            .clone()
+            .unwrap_or_else(PlaybackSnapshot::empty))
+    }
+
+    async fn get_queued_tracks(&self) -> Result<Vec<QueueEntry>> {
+        let queue = self
+            .store
+            .get_track_queue(self.session_id, QUEUE_VIEW_LIMIT)
+            .await?;
+        return Ok(queue.into_iter().map(|entry| entry.into()).collect());
     }
 
-    fn get_queued_tracks(&self) -> Vec<TrackInfo> {
-        return self.queue.iter().map(|info| info.clone()).collect();
+    async fn vote(
+        &mut self,
+        entry_id: i32,
+        guest_id: Uuid,
+        vote: Option<Vote>,
+    ) -> Result<bool> {
+        let accepted = self
+            .store
+            .set_vote(self.session_id, entry_id, guest_id, vote)
+            .await?;
+        if accepted {
+            if let Err(e) = self.announce_votes(entry_id).await {
+                println!("failed to announce votes: {}", e);
+            }
+        }
+        Ok(accepted)
     }
+
+    async fn remove_entry(
+        &mut self,
+        entry_id: i32,
+        owner: Option<Uuid>,
+    ) -> Result<RemoveEntryOutcome> {
+        let entry = match self
+            .store
+            .get_queue_entry(self.session_id, entry_id)
+            .await?
+        {
+            Some(entry) => entry,
+            None => return Ok(RemoveEntryOutcome::NotFound),
+        };
+        if owner.is_some() && entry.added_by != owner {
+            return Ok(RemoveEntryOutcome::NotOwner);
+        }
+
+        if !self
+            .store
+            .remove_queue_entry(self.session_id, entry_id)
+            .await?
+        {
+            return Ok(RemoveEntryOutcome::NotFound);
+        }
+        self.announce_queue().await;
+        Ok(RemoveEntryOutcome::Removed)
+    }
+
+    async fn move_entry(&mut self, entry_id: i32, position: u32) -> Result<bool> {
+        let moved = self
+            .store
+            .move_queue_entry(self.session_id, entry_id, position)
+            .await?;
+        if moved {
+            self.announce_queue().await;
+        }
+        Ok(moved)
+    }
+
+    async fn clear_queue(&mut self) -> Result<u32> {
+        let removed = self.store.clear_queue(self.session_id).await?;
+        println!(
+            "cleared {} tracks from the queue of session {}",
+            removed, self.session_id
+        );
+        self.announce_queue().await;
+        Ok(removed)
+    }
+
+    ///Send an event to every subscriber. Having nobody listening is fine.
+    fn emit(&self, event: PlayerEvent) {
+        let _ = self.events.send(event);
+    }
+
+    ///Tell subscribers what the queue looks like now
+    async fn announce_queue(&mut self) {
+        match self.get_queued_tracks().await {
+            Ok(queue) => self.emit_queue(&queue),
+            Err(e) => println!("failed to read the queue: {}", e),
+        }
+    }
+
+    fn emit_queue(&mut self, queue: &[QueueEntry]) {
+        self.announced_order = queue.iter().map(|entry| entry.id).collect();
+        self.emit(PlayerEvent::QueueChanged {
+            queue: queue.iter().map(|entry| entry.into()).collect(),
+        });
+    }
+
+    ///Tell subscribers about the new totals on an entry, and about the queue too if the
+    /// vote moved it
+    async fn announce_votes(&mut self, entry_id: i32) -> Result<()> {
+        let queue = self.get_queued_tracks().await?;
+        if let Some(entry) = queue.iter().find(|entry| entry.id == entry_id) {
+            self.emit(PlayerEvent::VotesChanged {
+                entry_id: entry_id,
+                upvotes: entry.upvotes,
+                downvotes: entry.downvotes,
+            });
+        }
+
+        let reordered = queue
+            .iter()
+            .map(|entry| entry.id)
+            .ne(self.announced_order.iter().copied());
+        if reordered {
+            self.emit_queue(&queue);
+        }
+        Ok(())
+    }
+}
+
+pub fn start_player_thread(
+    provider: Provider,
+    store: Store,
+    session_id: Uuid,
+    selected_device: Option<String>,
+    fallback_playlist: Option<String>,
+    settings: PlayerSettings,
+    metrics: ManagedMetrics,
+) -> PlayerCommader {
+    let refresh_interval = settings.playback_refresh;
+    let (tx, rx) = tokio::sync::mpsc::channel(64); //TODO: consider unbounded channel here
+    let (events, _) = broadcast::channel(EVENT_BUFFER);
+    let (handle, commanders) = watch::channel(());
+    let (health, health_rx) = watch::channel(PlayerHealth {
+        status: PlayerStatus::Running,
+        restarts: 0,
+        last_crash: None,
+        failed_refreshes: 0,
+    });
+    let context = PlayerContext {
+        provider: provider,
+        store: store,
+        session_id: session_id,
+        settings: settings,
+        cmd_rx: Arc::new(Mutex::new(rx)),
+        cmd_tx: tx.clone(),
+        commanders: commanders,
+        wake_timer: WakeTimer::default(),
+        events: events,
+        health: Arc::new(health),
+        metrics: metrics,
+    };
+
+    let player = PlayerState::new(&context, selected_device, fallback_playlist);
+    tokio::task::spawn(supervise(context, player));
+    tokio::task::spawn(playback_refresher(tx.clone(), refresh_interval));
+    PlayerCommader::new(tx, health_rx, handle)
 }
 
-pub fn start_player_thread(auth_state: ManagedAuthState) -> PlayerCommader {
-    let (player, tx) = PlayerState::new(auth_state);
-    tokio::task::spawn(player_task(player));
-    PlayerCommader::new(tx)
+///Run the player, starting a fresh one from what the store knows about the session
+/// whenever it crashes. The wait before each restart doubles for every crash in a row,
+/// so a player which keeps crashing doesn't hammer Spotify.
+async fn supervise(context: PlayerContext, player: PlayerState) {
+    let session_id = context.session_id;
+    let mut next = Some(player);
+    let mut restarted = false;
+    let mut delay = MIN_RESTART_DELAY;
+    loop {
+        if let Some(player) = next.take() {
+            context.set_status(PlayerStatus::Running);
+            let started = Instant::now();
+            match tokio::task::spawn(player_task(player, restarted)).await {
+                Ok(()) => {
+                    context.set_status(PlayerStatus::Stopped);
+                    return;
+                }
+                Err(e) => {
+                    context.wake_timer.cancel();
+                    let crash = crash_message(e);
+                    println!("player for session {} crashed: {}", session_id, crash);
+                    if started.elapsed() >= STABLE_RUN {
+                        delay = MIN_RESTART_DELAY;
+                    }
+                    context.health.send_modify(|health| {
+                        health.status = PlayerStatus::Restarting;
+                        health.restarts += 1;
+                        health.last_crash = Some(crash);
+                    });
+                }
+            }
+        }
+
+        println!(
+            "restarting player for session {} in {} seconds",
+            session_id,
+            delay.as_secs()
+        );
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+        match context.restore().await {
+            Ok(Some(player)) => {
+                next = Some(player);
+                restarted = true;
+            }
+            Ok(None) => {
+                println!("session {} is gone, not restarting its player", session_id);
+                context.set_status(PlayerStatus::Stopped);
+                return;
+            }
+            Err(e) => {
+                println!("failed to restart player for session {}: {}", session_id, e)
+            }
+        }
+    }
 }
 
-async fn player_task(mut player: PlayerState) {
-    println!("starting player task");
+///What a player task died of
+fn crash_message(error: JoinError) -> String {
+    if !error.is_panic() {
+        return error.to_string();
+    }
+    let panic = error.into_panic();
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        return message.to_string();
+    }
+    match panic.downcast_ref::<String>() {
+        Some(message) => message.clone(),
+        None => "player panicked".to_owned(),
+    }
+}
 
+///Periodically ask the player to refresh its playback state, until it shuts down
+async fn playback_refresher(sender: PlayerCommandQueue, refresh_interval: Duration) {
+    let mut interval = tokio::time::interval(refresh_interval);
     loop {
-        let cmd = player.cmd_rx.recv().await.unwrap(); //it's pretty bad if the channel has been droppped
+        interval.tick().await;
+        if let Err(_) = sender.send(PlayerCommand::RefreshPlayback).await {
+            break;
+        }
+    }
+}
+
+fn log_control(action: &str, result: Result<()>) -> Result<()> {
+    if let Err(err) = &result {
+        println!("failed to {}: {}", action, err);
+    }
+    result
+}
+
+async fn player_task(mut player: PlayerState, restarted: bool) {
+    println!("starting player task for session {}", player.session_id);
+    match player.get_queued_tracks().await {
+        Ok(queue) => {
+            println!("resuming with {} queued tracks", queue.len());
+            player.announced_order = queue.iter().map(|entry| entry.id).collect();
+        }
+        Err(e) => println!("failed to load the queue: {}", e),
+    }
+    if restarted {
+        if let Err(e) = player.recover().await {
+            println!("failed to pick up playback after a crash: {}", e);
+        }
+    }
+
+    loop {
+        let cmd = match player.next_command().await {
+            Some(cmd) => cmd,
+            None => {
+                println!("every commander is gone, stopping player task");
+                player.cancel_wake();
+                break;
+            }
+        };
         match cmd {
             PlayerCommand::Wake => {
-                let result = player.advance_to_next_track().await;
+                let result = player.refresh_playback().await;
                 if let Err(err) = result {
                     println!("failed to advance track: {}", err);
                 }
+                if let Err(err) = player.preempt_autofill().await {
+                    println!("failed to preempt autofill: {}", err);
+                }
             }
-            PlayerCommand::AddTrack(track_id) => {
-                let result = player.add_track_to_queue(track_id).await;
-                if let Err(err) = result {
+            PlayerCommand::AddTrack(track_id, guest_id, response_channel) => {
+                let result = player.add_track_to_queue(track_id, guest_id).await;
+                if let Err(AddTrackError::Internal(err)) = &result {
                     println!("failed to add track to queue: {}", err);
                 }
+                player.metrics.record_queue_add(match &result {
+                    Ok(()) => QueueAddOutcome::Added,
+                    Err(AddTrackError::Rejected(rejection))
+                        if rejection.is_rate_limit() =>
+                    {
+                        QueueAddOutcome::RateLimited
+                    }
+                    Err(AddTrackError::Rejected(_)) => QueueAddOutcome::Rejected,
+                    Err(AddTrackError::UnknownTrack(_)) => QueueAddOutcome::UnknownTrack,
+                    Err(AddTrackError::Internal(_)) => QueueAddOutcome::Failed,
+                });
+                let added = result.is_ok();
+                let _ = response_channel.send(result);
+                if added {
+                    if let Err(err) = player.preempt_autofill().await {
+                        println!("failed to preempt autofill: {}", err);
+                    }
+                }
             }
 
+            PlayerCommand::RefreshPlayback => {
+                let result = player.refresh_playback().await;
+                if let Err(err) = &result {
+                    println!("failed to refresh playback state: {}", err);
+                }
+                player.record_refresh(result.is_ok());
+                if let Err(err) = player.preempt_autofill().await {
+                    println!("failed to preempt autofill: {}", err);
+                }
+            }
             PlayerCommand::GetCurrentTrack(response_channel) => {
                 let current_track = player.get_currently_playing().await;
-                match current_track {
-                    Ok(res) => {
-                        response_channel.send(res).unwrap();
-                    }
-                    Err(err) => {
-                        println!("failed to find current track: {}", err);
-                    }
+                if let Err(err) = &current_track {
+                    println!("failed to find current track: {}", err);
                 }
+                let _ = response_channel.send(current_track);
             }
             PlayerCommand::GetTrackQueue(response_channel) => {
-                let outvec = player.get_queued_tracks();
-                response_channel.send(outvec).unwrap();
+                let result = player.get_queued_tracks().await;
+                if let Err(err) = &result {
+                    println!("failed to read the queue: {}", err);
+                }
+                let _ = response_channel.send(result);
             }
-            PlayerCommand::Start => {
-                player.start().await;
+            PlayerCommand::Vote(entry_id, guest_id, vote, response_channel) => {
+                let result = player.vote(entry_id, guest_id, vote).await;
+                let _ = response_channel.send(result);
+            }
+            PlayerCommand::RemoveEntry(entry_id, owner, response_channel) => {
+                let result = player.remove_entry(entry_id, owner).await;
+                if let Err(err) = &result {
+                    println!("failed to remove queue entry: {}", err);
+                }
+                let _ = response_channel.send(result);
+            }
+            PlayerCommand::MoveEntry(entry_id, position, response_channel) => {
+                let result = player.move_entry(entry_id, position).await;
+                if let Err(err) = &result {
+                    println!("failed to move queue entry: {}", err);
+                }
+                let _ = response_channel.send(result);
+            }
+            PlayerCommand::ClearQueue(response_channel) => {
+                let result = player.clear_queue().await;
+                if let Err(err) = &result {
+                    println!("failed to clear the queue: {}", err);
+                }
+                let _ = response_channel.send(result);
+            }
+            PlayerCommand::Start(response_channel) => {
+                let result = player.start().await;
+                let _ = response_channel.send(log_control("start playback", result));
+            }
+            PlayerCommand::Pause(response_channel) => {
+                let _ = response_channel.send(log_control("pause", player.pause().await));
+            }
+            PlayerCommand::Resume(response_channel) => {
+                let _ =
+                    response_channel.send(log_control("resume", player.resume().await));
+            }
+            PlayerCommand::Skip(response_channel) => {
+                let _ = response_channel.send(log_control("skip", player.skip().await));
+            }
+            PlayerCommand::Previous(response_channel) => {
+                let result = player.previous().await;
+                let _ = response_channel.send(log_control("go back", result));
+            }
+            PlayerCommand::SetVolume(percent, response_channel) => {
+                let result = player.set_volume(percent).await;
+                let _ = response_channel.send(log_control("set volume", result));
+            }
+            PlayerCommand::Seek(position, response_channel) => {
+                let result = player.seek(position).await;
+                let _ = response_channel.send(log_control("seek", result));
+            }
+            PlayerCommand::Search(query, limit, response_channel) => {
+                let _ =
+                    response_channel.send(player.provider.search(&query, limit).await);
+            }
+            PlayerCommand::SetFallbackPlaylist(playlist_id, response_channel) => {
+                let result = player.set_fallback_playlist(playlist_id).await;
+                if let Err(err) = &result {
+                    println!("failed to set fallback playlist: {}", err);
+                }
+                let _ = response_channel.send(result);
+            }
+            PlayerCommand::GetDevices(response_channel) => {
+                let _ = response_channel.send(player.list_devices().await);
+            }
+            PlayerCommand::SelectDevice(device_id, response_channel) => {
+                let result = player.select_device(&device_id).await;
+                if let Err(err) = &result {
+                    println!("failed to switch device: {}", err);
+                }
+                let _ = response_channel.send(result);
+            }
+            PlayerCommand::Subscribe(response_channel) => {
+                let _ = response_channel.send(player.events.subscribe());
+            }
+            PlayerCommand::IsIdle(response_channel) => {
+                let idle = player
+                    .get_queued_tracks()
+                    .await
+                    .map(|queue| queue.is_empty());
+                let _ = response_channel.send(idle);
+            }
+            PlayerCommand::Shutdown => {
+                println!("stopping player task");
+                player.cancel_wake();
+                break;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        metrics::Metrics,
+        persistence::{
+            memory::InMemoryStore,
+            model::{SpotifyAlbum, SpotifyTrack},
+        },
+        provider::fake::FakeProvider,
+    };
+
+    fn track(id: &str, duration_secs: u64) -> TrackInfo {
+        TrackInfo::from(SpotifyTrack {
+            id: id.to_owned(),
+            name: id.to_owned(),
+            duration: Duration::from_secs(duration_secs),
+            album: SpotifyAlbum {
+                name: "album".to_owned(),
+                id: "album".to_owned(),
+                cover_image_url: None,
+            },
+            artists: vec![],
+        })
+    }
+
+    fn snapshot(
+        track_id: &str,
+        progress_secs: u64,
+        fetched_at: Instant,
+    ) -> PlaybackSnapshot {
+        PlaybackSnapshot {
+            track: Some(track(track_id, 180)),
+            is_playing: true,
+            progress: Some(Duration::from_secs(progress_secs)),
+            device: None,
+            fetched_at: fetched_at,
+            autofill: false,
+        }
+    }
+
+    #[test]
+    fn remaining_does_not_underflow() {
+        let now = Instant::now();
+        assert_eq!(
+            snapshot("a", 60, now).remaining(),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(snapshot("a", 185, now).remaining(), Some(Duration::ZERO));
+        assert_eq!(PlaybackSnapshot::empty().remaining(), None);
+    }
+
+    #[test]
+    fn track_change_near_the_end_is_not_a_skip() {
+        let start = Instant::now();
+        let previous = snapshot("a", 170, start);
+        let next = snapshot("b", 2, start + Duration::from_secs(9));
+        assert!(!previous.was_skipped(&next));
+    }
+
+    #[test]
+    fn early_track_change_is_a_skip() {
+        let start = Instant::now();
+        let previous = snapshot("a", 60, start);
+        let next = snapshot("b", 1, start + Duration::from_secs(5));
+        assert!(previous.was_skipped(&next));
+
+        //seeking within the same track isn't a skip
+        let seeked = snapshot("a", 10, start + Duration::from_secs(5));
+        assert!(!previous.was_skipped(&seeked));
+    }
+
+    #[rocket::async_test]
+    async fn cancelled_wake_never_arrives() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let timer = WakeTimer::default();
+        timer.set(tokio::task::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let _ = tx.send(()).await;
+        }));
+
+        //the supervisor cancels through its own copy of the timer
+        timer.clone().cancel();
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[rocket::async_test]
+    async fn player_stops_once_every_commander_is_dropped() {
+        let store = InMemoryStore::create();
+        let session = store.create_session("test").await.unwrap();
+        let settings = PlayerSettings {
+            limits: QueueLimits::default(),
+            playback_refresh: Duration::from_millis(20),
+            queue_lead_time: Duration::from_secs(10),
+            autofill: false,
+        };
+        let player = start_player_thread(
+            FakeProvider::new(vec![]),
+            store,
+            session.id,
+            None,
+            None,
+            settings,
+            Metrics::create(),
+        );
+        let mut health = player.health.clone();
+
+        let other = player.clone();
+        drop(player);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(other.health().status, PlayerStatus::Running);
+
+        drop(other);
+        let stopped = async {
+            while health.borrow().status != PlayerStatus::Stopped {
+                health.changed().await.unwrap();
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), stopped)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,337 @@
+//! In-process stand-in for a music service, so the player and routes can be driven
+//! without a Spotify account. It has a fixed catalog, a single device and a playback
+//! clock which only moves when a test calls `advance` (and not at all while paused).
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use super::{MusicProvider, Playback, PlaybackDevice};
+use crate::model::{Album, Artist, SpotifyItemId, TrackInfo};
+
+///Id of the device every FakeProvider starts out with
+pub const FAKE_DEVICE_ID: &str = "fake-speaker";
+
+pub struct FakeProvider {
+    state: Mutex<FakeState>,
+}
+
+struct FakeState {
+    catalog: Vec<TrackInfo>,
+    playlists: HashMap<String, Vec<TrackInfo>>,
+    devices: Vec<PlaybackDevice>,
+    current: Option<TrackInfo>,
+    progress: Duration,
+    paused: bool,
+    up_next: VecDeque<TrackInfo>,
+    ///Tracks which have been skipped or played through, most recent last
+    history: Vec<TrackInfo>,
+    ///Whether the next search panics, to crash whoever is calling
+    panic_on_search: bool,
+}
+
+///Catalog entry with a single artist and album named after the track
+pub fn track(id: &str, name: &str, duration_secs: u64) -> TrackInfo {
+    TrackInfo {
+        id: SpotifyItemId(id.to_owned()),
+        name: name.to_owned(),
+        duration: Duration::from_secs(duration_secs),
+        album: Album {
+            id: SpotifyItemId(format!("{}-album", id)),
+            name: format!("{} (Single)", name),
+            first_image_url: None,
+        },
+        artists: vec![Artist {
+            id: SpotifyItemId(format!("{}-artist", id)),
+            name: format!("{} Band", name),
+        }],
+    }
+}
+
+impl FakeProvider {
+    pub fn new(catalog: Vec<TrackInfo>) -> Arc<FakeProvider> {
+        Arc::new(FakeProvider {
+            state: Mutex::new(FakeState {
+                catalog: catalog,
+                playlists: HashMap::new(),
+                devices: vec![PlaybackDevice {
+                    id: FAKE_DEVICE_ID.to_owned(),
+                    name: "Fake Speaker".to_owned(),
+                    is_active: true,
+                    volume_percent: Some(50),
+                }],
+                current: None,
+                progress: Duration::ZERO,
+                paused: false,
+                up_next: VecDeque::new(),
+                history: Vec::new(),
+                panic_on_search: false,
+            }),
+        })
+    }
+
+    ///Make a playlist of catalog tracks available
+    pub fn add_playlist(&self, id: &str, track_ids: &[&str]) {
+        let mut state = self.state.lock().unwrap();
+        let tracks = track_ids
+            .iter()
+            .filter_map(|track_id| {
+                state
+                    .catalog
+                    .iter()
+                    .find(|track| &track.id.0 == track_id)
+                    .cloned()
+            })
+            .collect();
+        state.playlists.insert(id.to_owned(), tracks);
+    }
+
+    ///Make another device available, it starts out inactive
+    pub fn add_device(&self, id: &str, name: &str) {
+        self.state.lock().unwrap().devices.push(PlaybackDevice {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            is_active: false,
+            volume_percent: Some(50),
+        });
+    }
+
+    ///Move the playback clock forward, rolling on to queued tracks as each one ends
+    pub fn advance(&self, by: Duration) {
+        let mut state = self.state.lock().unwrap();
+        if state.paused {
+            return;
+        }
+
+        let mut left = by;
+        while let Some(current) = &state.current {
+            let remaining = current.duration.saturating_sub(state.progress);
+            if left < remaining {
+                state.progress += left;
+                return;
+            }
+            left -= remaining;
+            state.play_next();
+        }
+    }
+
+    ///Start playing a track as someone using the service's own app would, dropping
+    /// whatever was queued on the device
+    pub fn play_directly(&self, track_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        let track = state
+            .catalog
+            .iter()
+            .find(|track| track.id.0 == track_id)
+            .cloned();
+        if let Some(finished) = std::mem::replace(&mut state.current, track) {
+            state.history.push(finished);
+        }
+        state.up_next.clear();
+        state.progress = Duration::ZERO;
+    }
+
+    ///Make the next search panic
+    pub fn crash_next_search(&self) {
+        self.state.lock().unwrap().panic_on_search = true;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    ///How far into the current track playback is
+    pub fn progress(&self) -> Duration {
+        self.state.lock().unwrap().progress
+    }
+
+    pub fn volume(&self, device_id: &str) -> Option<u32> {
+        let state = self.state.lock().unwrap();
+        let device = state.devices.iter().find(|device| device.id == device_id)?;
+        device.volume_percent
+    }
+
+    ///Id of the track playing right now
+    pub fn now_playing(&self) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state.current.as_ref().map(|track| track.id.0.clone())
+    }
+
+    ///Ids of the tracks queued on the device, in the order they will play
+    pub fn up_next(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .up_next
+            .iter()
+            .map(|track| track.id.0.clone())
+            .collect()
+    }
+}
+
+impl FakeState {
+    fn play_next(&mut self) {
+        if let Some(finished) = self.current.take() {
+            self.history.push(finished);
+        }
+        self.current = self.up_next.pop_front();
+        self.progress = Duration::ZERO;
+    }
+
+    fn active_device(&self) -> Option<&PlaybackDevice> {
+        self.devices.iter().find(|device| device.is_active)
+    }
+
+    fn check_device(&self, device_id: &str) -> Result<()> {
+        match self.devices.iter().any(|device| device.id == device_id) {
+            true => Ok(()),
+            false => Err(anyhow::Error::msg(format!("no device {}", device_id))),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl MusicProvider for FakeProvider {
+    async fn search(&self, query: &str, limit: u32) -> Result<Vec<TrackInfo>> {
+        //the lock is released first so it isn't poisoned for everyone else
+        let panic = std::mem::take(&mut self.state.lock().unwrap().panic_on_search);
+        if panic {
+            panic!("search crashed on purpose");
+        }
+        let state = self.state.lock().unwrap();
+        let query = query.to_lowercase();
+        Ok(state
+            .catalog
+            .iter()
+            .filter(|track| track.name.to_lowercase().contains(&query))
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn track(&self, track_id: &str) -> Result<Option<TrackInfo>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .catalog
+            .iter()
+            .find(|track| track.id.0 == track_id)
+            .cloned())
+    }
+
+    ///Every catalog track which isn't a seed, in catalog order
+    async fn recommendations(
+        &self,
+        seed_track_ids: &[String],
+        limit: u32,
+    ) -> Result<Vec<TrackInfo>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .catalog
+            .iter()
+            .filter(|track| !seed_track_ids.contains(&track.id.0))
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn playlist_tracks(&self, playlist_id: &str) -> Result<Option<Vec<TrackInfo>>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.playlists.get(playlist_id).cloned())
+    }
+
+    async fn enqueue(&self, track_id: &str, device_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check_device(device_id)?;
+        let track = state
+            .catalog
+            .iter()
+            .find(|track| track.id.0 == track_id)
+            .cloned()
+            .ok_or_else(|| anyhow::Error::msg(format!("no track {}", track_id)))?;
+        state.up_next.push_back(track);
+        Ok(())
+    }
+
+    async fn skip_to_next(&self, device_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check_device(device_id)?;
+        state.play_next();
+        Ok(())
+    }
+
+    async fn skip_to_previous(&self, device_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check_device(device_id)?;
+        if let Some(previous) = state.history.pop() {
+            if let Some(current) = state.current.replace(previous) {
+                state.up_next.push_front(current);
+            }
+        }
+        state.progress = Duration::ZERO;
+        Ok(())
+    }
+
+    async fn pause(&self, device_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check_device(device_id)?;
+        state.paused = true;
+        Ok(())
+    }
+
+    async fn resume(&self, device_id: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check_device(device_id)?;
+        state.paused = false;
+        Ok(())
+    }
+
+    async fn set_volume(&self, device_id: &str, percent: u8) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check_device(device_id)?;
+        for device in state.devices.iter_mut().filter(|d| d.id == device_id) {
+            device.volume_percent = Some(percent as u32);
+        }
+        Ok(())
+    }
+
+    async fn seek(&self, device_id: &str, position: Duration) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check_device(device_id)?;
+        let duration = match &state.current {
+            Some(current) => current.duration,
+            None => return Err(anyhow::Error::msg("nothing is playing")),
+        };
+        state.progress = position.min(duration);
+        Ok(())
+    }
+
+    async fn current_playback(&self) -> Result<Option<Playback>> {
+        let state = self.state.lock().unwrap();
+        let device = match state.active_device() {
+            Some(device) => device.clone(),
+            None => return Ok(None),
+        };
+        Ok(Some(Playback {
+            track: state.current.clone(),
+            is_playing: state.current.is_some() && !state.paused,
+            progress: Some(state.progress),
+            device: Some(device),
+        }))
+    }
+
+    async fn devices(&self) -> Result<Vec<PlaybackDevice>> {
+        Ok(self.state.lock().unwrap().devices.clone())
+    }
+
+    async fn transfer_playback(&self, device_id: &str, _play: bool) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check_device(device_id)?;
+        for device in state.devices.iter_mut() {
+            device.is_active = device.id == device_id;
+        }
+        Ok(())
+    }
+}
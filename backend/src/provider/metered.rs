@@ -0,0 +1,116 @@
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use super::{MusicProvider, Playback, PlaybackDevice, Provider};
+use crate::{
+    metrics::{ManagedMetrics, ProviderCall},
+    model::TrackInfo,
+};
+
+///Passes every call on to another provider, timing it and counting its failures for the
+/// metrics
+pub struct MeteredProvider {
+    inner: Provider,
+    metrics: ManagedMetrics,
+}
+
+impl MeteredProvider {
+    pub fn wrap(inner: Provider, metrics: ManagedMetrics) -> Provider {
+        Arc::new(MeteredProvider {
+            inner: inner,
+            metrics: metrics,
+        })
+    }
+
+    async fn time<T, F>(&self, call: ProviderCall, request: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let started = Instant::now();
+        let result = request.await;
+        self.metrics
+            .record_provider_call(call, started.elapsed(), result.is_ok());
+        result
+    }
+}
+
+#[rocket::async_trait]
+impl MusicProvider for MeteredProvider {
+    async fn search(&self, query: &str, limit: u32) -> Result<Vec<TrackInfo>> {
+        self.time(ProviderCall::Search, self.inner.search(query, limit))
+            .await
+    }
+
+    async fn track(&self, track_id: &str) -> Result<Option<TrackInfo>> {
+        self.time(ProviderCall::Track, self.inner.track(track_id))
+            .await
+    }
+
+    async fn recommendations(
+        &self,
+        seed_track_ids: &[String],
+        limit: u32,
+    ) -> Result<Vec<TrackInfo>> {
+        let request = self.inner.recommendations(seed_track_ids, limit);
+        self.time(ProviderCall::Recommendations, request).await
+    }
+
+    async fn playlist_tracks(&self, playlist_id: &str) -> Result<Option<Vec<TrackInfo>>> {
+        let request = self.inner.playlist_tracks(playlist_id);
+        self.time(ProviderCall::PlaylistTracks, request).await
+    }
+
+    async fn enqueue(&self, track_id: &str, device_id: &str) -> Result<()> {
+        let request = self.inner.enqueue(track_id, device_id);
+        self.time(ProviderCall::Enqueue, request).await
+    }
+
+    async fn skip_to_next(&self, device_id: &str) -> Result<()> {
+        let request = self.inner.skip_to_next(device_id);
+        self.time(ProviderCall::SkipToNext, request).await
+    }
+
+    async fn skip_to_previous(&self, device_id: &str) -> Result<()> {
+        let request = self.inner.skip_to_previous(device_id);
+        self.time(ProviderCall::SkipToPrevious, request).await
+    }
+
+    async fn pause(&self, device_id: &str) -> Result<()> {
+        self.time(ProviderCall::Pause, self.inner.pause(device_id))
+            .await
+    }
+
+    async fn resume(&self, device_id: &str) -> Result<()> {
+        self.time(ProviderCall::Resume, self.inner.resume(device_id))
+            .await
+    }
+
+    async fn set_volume(&self, device_id: &str, percent: u8) -> Result<()> {
+        let request = self.inner.set_volume(device_id, percent);
+        self.time(ProviderCall::SetVolume, request).await
+    }
+
+    async fn seek(&self, device_id: &str, position: Duration) -> Result<()> {
+        let request = self.inner.seek(device_id, position);
+        self.time(ProviderCall::Seek, request).await
+    }
+
+    async fn current_playback(&self) -> Result<Option<Playback>> {
+        let request = self.inner.current_playback();
+        self.time(ProviderCall::CurrentPlayback, request).await
+    }
+
+    async fn devices(&self) -> Result<Vec<PlaybackDevice>> {
+        self.time(ProviderCall::Devices, self.inner.devices()).await
+    }
+
+    async fn transfer_playback(&self, device_id: &str, play: bool) -> Result<()> {
+        let request = self.inner.transfer_playback(device_id, play);
+        self.time(ProviderCall::TransferPlayback, request).await
+    }
+}
@@ -0,0 +1,69 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use rspotify::Token;
+
+use crate::model::TrackInfo;
+
+#[cfg(test)]
+pub mod fake;
+pub mod metered;
+pub mod spotify;
+
+///A device the music provider can play to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlaybackDevice {
+    pub id: String,
+    pub name: String,
+    pub is_active: bool,
+    pub volume_percent: Option<u32>,
+}
+
+///What the music provider is playing right now
+#[derive(Clone, Debug)]
+pub struct Playback {
+    ///The track playing, None if nothing is or it isn't a track (a podcast episode)
+    pub track: Option<TrackInfo>,
+    pub is_playing: bool,
+    pub progress: Option<Duration>,
+    pub device: Option<PlaybackDevice>,
+}
+
+///Trait abstracting the music service the player drives
+#[rocket::async_trait]
+pub trait MusicProvider {
+    async fn search(&self, query: &str, limit: u32) -> Result<Vec<TrackInfo>>;
+    ///Look up a track, None if the id isn't one the provider recognises
+    async fn track(&self, track_id: &str) -> Result<Option<TrackInfo>>;
+    ///Tracks similar to the seeds, at most five seeds are used
+    async fn recommendations(
+        &self,
+        seed_track_ids: &[String],
+        limit: u32,
+    ) -> Result<Vec<TrackInfo>>;
+    ///The tracks in a playlist in playlist order, None if the id isn't one the provider
+    /// recognises
+    async fn playlist_tracks(&self, playlist_id: &str) -> Result<Option<Vec<TrackInfo>>>;
+    ///Queue a track to play on a device after whatever is playing now
+    async fn enqueue(&self, track_id: &str, device_id: &str) -> Result<()>;
+    ///Skip straight to the next track in the device's queue
+    async fn skip_to_next(&self, device_id: &str) -> Result<()>;
+    async fn skip_to_previous(&self, device_id: &str) -> Result<()>;
+    async fn pause(&self, device_id: &str) -> Result<()>;
+    async fn resume(&self, device_id: &str) -> Result<()>;
+    ///Set the device's volume, from 0 to 100
+    async fn set_volume(&self, device_id: &str, percent: u8) -> Result<()>;
+    ///Jump to a position in the current track
+    async fn seek(&self, device_id: &str, position: Duration) -> Result<()>;
+    ///None when there is no active playback anywhere
+    async fn current_playback(&self) -> Result<Option<Playback>>;
+    async fn devices(&self) -> Result<Vec<PlaybackDevice>>;
+    ///Move playback to a device, starting it there if `play` is set
+    async fn transfer_playback(&self, device_id: &str, play: bool) -> Result<()>;
+}
+
+///Like Store, lets the music service be swapped at runtime
+pub type Provider = Arc<dyn MusicProvider + Send + Sync>;
+
+///Builds the provider a session's player uses from the session's stored credentials
+pub type ProviderFactory = Arc<dyn Fn(Token) -> Result<Provider> + Send + Sync>;
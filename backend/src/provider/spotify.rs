@@ -0,0 +1,311 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use rspotify::{
+    clients::{BaseClient, OAuthClient},
+    model::{
+        AdditionalType, ArtistId, CurrentPlaybackContext, Device, FullTrack, Id,
+        PlayableItem, PlaylistId, RecommendationsAttribute, SearchResult, SearchType,
+        SimplifiedAlbum, SimplifiedArtist, TrackId,
+    },
+    AuthCodeSpotify,
+};
+use tokio::sync::Mutex;
+
+use super::{MusicProvider, Playback, PlaybackDevice, Provider, ProviderFactory};
+use crate::{
+    authentication::{AuthenticationState, ManagedAuthState},
+    model::{Album, Artist, SpotifyItemId, TrackInfo},
+};
+
+///Most seeds the recommendations endpoint accepts
+const MAX_SEEDS: usize = 5;
+
+///Most tracks read from a playlist in one request
+const PLAYLIST_PAGE_SIZE: u32 = 100;
+
+///MusicProvider backed by the Spotify web API. Requests are made with whatever
+/// credentials are in the shared authentication state at the time.
+pub struct SpotifyProvider {
+    auth_state: ManagedAuthState,
+}
+
+impl SpotifyProvider {
+    pub fn new(auth_state: ManagedAuthState) -> SpotifyProvider {
+        return SpotifyProvider {
+            auth_state: auth_state,
+        };
+    }
+
+    ///ProviderFactory giving each session a SpotifyProvider with its own credentials
+    pub fn session_factory() -> ProviderFactory {
+        Arc::new(|token| {
+            let auth: ManagedAuthState =
+                Arc::new(Mutex::new(Some(AuthenticationState::from_token(token)?)));
+            let provider: Provider = Arc::new(SpotifyProvider::new(auth));
+            Ok(provider)
+        })
+    }
+
+    async fn client(&self) -> Result<AuthCodeSpotify> {
+        let mut auth_value = self.auth_state.lock().await;
+        match auth_value.as_mut() {
+            Some(auth) => auth.client().await,
+            None => Err(anyhow::Error::msg(
+                "no spotify credentials are available for this session",
+            )),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl MusicProvider for SpotifyProvider {
+    async fn search(&self, query: &str, limit: u32) -> Result<Vec<TrackInfo>> {
+        let result = self
+            .client()
+            .await?
+            .search(query, &SearchType::Track, None, None, Some(limit), None)
+            .await?;
+
+        match result {
+            SearchResult::Tracks(tracks) => {
+                Ok(tracks.items.into_iter().map(TrackInfo::from).collect())
+            }
+            _ => Err(anyhow::Error::msg(
+                "track search somehow returned non-track results",
+            )),
+        }
+    }
+
+    async fn track(&self, track_id: &str) -> Result<Option<TrackInfo>> {
+        let id = match TrackId::from_id(track_id) {
+            Ok(id) => id,
+            Err(_) => return Ok(None),
+        };
+        let track = self.client().await?.track(&id).await?;
+        Ok(Some(track.into()))
+    }
+
+    async fn recommendations(
+        &self,
+        seed_track_ids: &[String],
+        limit: u32,
+    ) -> Result<Vec<TrackInfo>> {
+        let seeds: Vec<TrackId> = seed_track_ids
+            .iter()
+            .take(MAX_SEEDS)
+            .filter_map(|id| TrackId::from_id(id).ok())
+            .collect();
+        if seeds.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let client = self.client().await?;
+        let recommended = client
+            .recommendations(
+                Vec::<RecommendationsAttribute>::new(),
+                None::<Vec<&ArtistId>>,
+                None::<Vec<&str>>,
+                Some(seeds.iter()),
+                None,
+                Some(limit),
+            )
+            .await?;
+
+        //recommendations come without albums, so look the full tracks up
+        let ids: Vec<TrackId> = recommended
+            .tracks
+            .into_iter()
+            .filter_map(|track| track.id)
+            .collect();
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let tracks = client.tracks(ids.iter(), None).await?;
+        Ok(tracks.into_iter().map(TrackInfo::from).collect())
+    }
+
+    async fn playlist_tracks(&self, playlist_id: &str) -> Result<Option<Vec<TrackInfo>>> {
+        let id = match PlaylistId::from_id_or_uri(playlist_id) {
+            Ok(id) => id,
+            Err(_) => return Ok(None),
+        };
+        let page = self
+            .client()
+            .await?
+            .playlist_items_manual(&id, None, None, Some(PLAYLIST_PAGE_SIZE), None)
+            .await?;
+
+        Ok(Some(
+            page.items
+                .into_iter()
+                .filter_map(|item| match item.track {
+                    //local files have no spotify id and can't be queued
+                    Some(PlayableItem::Track(track)) if track.id.is_some() => {
+                        Some(TrackInfo::from(track))
+                    }
+                    _ => None,
+                })
+                .collect(),
+        ))
+    }
+
+    async fn enqueue(&self, track_id: &str, device_id: &str) -> Result<()> {
+        let id = TrackId::from_id(track_id)?;
+        self.client()
+            .await?
+            .add_item_to_queue(&id, Some(device_id))
+            .await?;
+        Ok(())
+    }
+
+    async fn skip_to_next(&self, device_id: &str) -> Result<()> {
+        self.client().await?.next_track(Some(device_id)).await?;
+        Ok(())
+    }
+
+    async fn skip_to_previous(&self, device_id: &str) -> Result<()> {
+        self.client().await?.previous_track(Some(device_id)).await?;
+        Ok(())
+    }
+
+    async fn pause(&self, device_id: &str) -> Result<()> {
+        self.client().await?.pause_playback(Some(device_id)).await?;
+        Ok(())
+    }
+
+    async fn resume(&self, device_id: &str) -> Result<()> {
+        self.client()
+            .await?
+            .resume_playback(Some(device_id), None)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_volume(&self, device_id: &str, percent: u8) -> Result<()> {
+        self.client()
+            .await?
+            .volume(percent, Some(device_id))
+            .await?;
+        Ok(())
+    }
+
+    async fn seek(&self, device_id: &str, position: Duration) -> Result<()> {
+        self.client()
+            .await?
+            .seek_track(position.as_millis() as u32, Some(device_id))
+            .await?;
+        Ok(())
+    }
+
+    async fn current_playback(&self) -> Result<Option<Playback>> {
+        let playback = self
+            .client()
+            .await?
+            .current_playback(
+                None,
+                Some([&AdditionalType::Track, &AdditionalType::Episode]),
+            )
+            .await?;
+        Ok(playback.map(Playback::from))
+    }
+
+    async fn devices(&self) -> Result<Vec<PlaybackDevice>> {
+        let devices = self.client().await?.device().await?;
+        Ok(devices
+            .into_iter()
+            .filter_map(|device| device.try_into().ok())
+            .collect())
+    }
+
+    async fn transfer_playback(&self, device_id: &str, play: bool) -> Result<()> {
+        self.client()
+            .await?
+            .transfer_playback(device_id, Some(play))
+            .await?;
+        Ok(())
+    }
+}
+
+impl From<CurrentPlaybackContext> for Playback {
+    fn from(context: CurrentPlaybackContext) -> Self {
+        return Playback {
+            track: context.item.and_then(|item| match item {
+                PlayableItem::Track(full_track) => Some(TrackInfo::from(full_track)),
+                PlayableItem::Episode(_) => None,
+            }),
+            is_playing: context.is_playing,
+            progress: context.progress,
+            device: context.device.try_into().ok(),
+        };
+    }
+}
+
+impl TryFrom<Device> for PlaybackDevice {
+    type Error = anyhow::Error;
+
+    fn try_from(device: Device) -> Result<Self, Self::Error> {
+        match device.id {
+            Some(id) => Ok(PlaybackDevice {
+                id: id,
+                name: device.name,
+                is_active: device.is_active,
+                volume_percent: device.volume_percent,
+            }),
+            None => Err(anyhow::Error::msg(format!(
+                "device {} has no spotify id",
+                device.name
+            ))),
+        }
+    }
+}
+
+impl<T: Id> From<T> for SpotifyItemId {
+    fn from(id: T) -> Self {
+        let id_str = id.id().to_owned();
+        return Self(id_str);
+    }
+}
+
+impl From<FullTrack> for TrackInfo {
+    fn from(track: FullTrack) -> Self {
+        return TrackInfo {
+            id: track.id.unwrap().into(),
+            name: track.name,
+            duration: track.duration,
+            album: track.album.into(),
+            artists: track
+                .artists
+                .into_iter()
+                .filter_map(|artist| artist.try_into().ok())
+                .collect(),
+        };
+    }
+}
+
+impl TryFrom<SimplifiedArtist> for Artist {
+    type Error = anyhow::Error;
+
+    fn try_from(input: SimplifiedArtist) -> Result<Self, Self::Error> {
+        match input.id {
+            Some(id) => Ok(Self {
+                id: id.into(),
+                name: input.name,
+            }),
+            None => Err(anyhow::Error::msg(format!(
+                "artist {} has no spotify id",
+                input.name
+            ))),
+        }
+    }
+}
+
+impl From<SimplifiedAlbum> for Album {
+    fn from(input: SimplifiedAlbum) -> Self {
+        Self {
+            id: input.id.unwrap().into(),
+            name: input.name,
+            first_image_url: input.images.first().map(|image| image.url.clone()),
+        }
+    }
+}
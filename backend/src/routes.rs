@@ -1,184 +1,720 @@
-use std::{str::FromStr, time::Duration};
-
-use ddj_core::types::{CreateSessionResponse, PlayerState, Session, Track};
-use rocket::{http::Status, response::status::BadRequest, serde::json::Json, State};
-use rspotify::{
-    clients::{BaseClient, OAuthClient},
-    model::{Id, TrackId},
-    AuthCodeSpotify, Credentials, OAuth,
+use std::{collections::HashMap, future::Future, time::Duration};
+
+use ddj_core::types::{
+    ClientConfig, CreateSessionResponse, Device, GuestIdentity, PlayHistory, PlayerEvent,
+    PlayerHealth, PlayerState, PlayerStatus, QueuedTrack, Readiness, ReadinessCheck,
+    Session, Track,
+};
+use rocket::{
+    http::{Cookie, CookieJar, Status},
+    response::stream::{Event, EventStream},
+    serde::json::Json,
+    tokio::{select, sync::broadcast::error::RecvError},
+    Shutdown, State,
 };
+use rspotify::{clients::OAuthClient, AuthCodeSpotify, Credentials, OAuth};
+use uuid::Uuid;
 
 use crate::{
-    authentication::{self, AuthenticationState, ManagedAuthState, SpotifyClient},
-    model::TrackInfo,
-    persistence::{
-        model::{SpotifyAlbum, SpotifyTrack},
-        Store,
-    },
-    player::PlayerCommader,
+    authentication::{self, Guest, Member, Permission, GUEST_COOKIE},
+    config::{Config, SpotifyConfig},
+    error::ApiError,
+    metrics::ManagedMetrics,
+    model::QueueEntry,
+    persistence::{migrations::SCHEMA_VERSION, model::Vote, Store},
+    player::{PlayerCommader, RemoveEntryOutcome},
+    sessions::{ManagedSessionRegistry, SessionRegistry},
 };
 
-#[post("/search", data = "<query>")]
-pub async fn search(client: SpotifyClient, query: String) -> Option<Json<Vec<Track>>> {
-    let search = client
-        .spotify
-        .search(
-            &query,
-            &rspotify::model::SearchType::Track,
-            None,
-            None,
-            Some(5),
-            None,
-        )
-        .await;
-    if let Err(err) = search {
-        println!("SEARCH ERROR: {}", err);
-        return None;
+///Number of results returned by a track search
+const SEARCH_LIMIT: u32 = 5;
+
+///Plays in a page of history unless the client asks for fewer
+const HISTORY_PAGE_SIZE: u32 = 50;
+
+async fn session_player(
+    sessions: &SessionRegistry,
+    session_id: Uuid,
+) -> Result<PlayerCommader, ApiError> {
+    Ok(sessions.player(session_id).await?)
+}
+
+///Fail with 404 unless the session exists
+async fn require_session(store: &Store, session_id: Uuid) -> Result<(), ApiError> {
+    match store.get_session(session_id).await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(ApiError::NotFound(format!(
+            "no session with id {}",
+            session_id
+        ))),
+        Err(e) => Err(ApiError::Store(e)),
     }
-    let real_search = search.unwrap();
+}
 
-    return if let rspotify::model::SearchResult::Tracks(tracks) = real_search {
-        let items = tracks.items;
-        let final_result: Vec<Track> = items.iter().map(|ft| ft.into()).collect();
-        Some(Json(final_result))
-    } else {
-        panic!("track search somehow returned non-track results")
-    };
+///Search for tracks with the session's own Spotify credentials
+#[post("/session/<session_id>/search", data = "<query>")]
+pub async fn search(
+    session_id: Uuid,
+    sessions: &State<ManagedSessionRegistry>,
+    query: String,
+) -> Result<Json<Vec<Track>>, ApiError> {
+    let player = session_player(sessions, session_id).await?;
+    let tracks = player.search(query, SEARCH_LIMIT).await?;
+
+    let final_result: Vec<Track> = tracks.iter().map(|t| t.into()).collect();
+    return Ok(Json(final_result));
+}
+
+#[post("/session/<_session_id>/next_track")]
+pub async fn play_track(
+    _session_id: Uuid,
+    member: Member,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<(), ApiError> {
+    control(
+        sessions,
+        member,
+        |player| async move { player.start().await },
+    )
+    .await
 }
 
-#[post("/next_track")]
-pub async fn play_track(state: &State<PlayerCommader>) {
-    state.start().await.unwrap();
+///Run a playback control on a session's player, if the member making the request is
+/// allowed to control playback
+async fn control<F, Fut>(
+    sessions: &SessionRegistry,
+    member: Member,
+    control: F,
+) -> Result<(), ApiError>
+where
+    F: FnOnce(PlayerCommader) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    member.require(Permission::ControlPlayback)?;
+    let player = session_player(sessions, member.session_id()).await?;
+    Ok(control(player).await?)
 }
 
-#[post("/queue/<track_id>")]
+#[post("/session/<_session_id>/pause")]
+pub async fn pause(
+    _session_id: Uuid,
+    member: Member,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<(), ApiError> {
+    control(
+        sessions,
+        member,
+        |player| async move { player.pause().await },
+    )
+    .await
+}
+
+#[post("/session/<_session_id>/resume")]
+pub async fn resume(
+    _session_id: Uuid,
+    member: Member,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<(), ApiError> {
+    control(
+        sessions,
+        member,
+        |player| async move { player.resume().await },
+    )
+    .await
+}
+
+///Skip the current track, playing the next one in the session's queue
+#[post("/session/<_session_id>/skip")]
+pub async fn skip(
+    _session_id: Uuid,
+    member: Member,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<(), ApiError> {
+    control(
+        sessions,
+        member,
+        |player| async move { player.skip().await },
+    )
+    .await
+}
+
+#[post("/session/<_session_id>/previous")]
+pub async fn previous(
+    _session_id: Uuid,
+    member: Member,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<(), ApiError> {
+    control(
+        sessions,
+        member,
+        |player| async move { player.previous().await },
+    )
+    .await
+}
+
+#[post("/session/<_session_id>/volume/<percent>")]
+pub async fn set_volume(
+    _session_id: Uuid,
+    percent: u8,
+    member: Member,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<(), ApiError> {
+    if percent > 100 {
+        return Err(ApiError::Validation(format!(
+            "volume must be between 0 and 100, not {}",
+            percent
+        )));
+    }
+    control(sessions, member, |player| async move {
+        player.set_volume(percent).await
+    })
+    .await
+}
+
+#[post("/session/<_session_id>/seek/<position_ms>")]
+pub async fn seek(
+    _session_id: Uuid,
+    position_ms: u64,
+    member: Member,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<(), ApiError> {
+    let position = Duration::from_millis(position_ms);
+    control(sessions, member, |player| async move {
+        player.seek(position).await
+    })
+    .await
+}
+
+///Queue a track for the guest. Tracks turned away by a rate limit are answered with 429
+/// and by the session's rules with 409, either way the ErrorBody says why.
+#[post("/session/<session_id>/queue/<track_id>")]
 pub async fn add_track_to_queue(
-    player_cmd: &State<PlayerCommader>,
-    store: &State<Store>,
+    session_id: Uuid,
+    guest: Guest,
+    sessions: &State<ManagedSessionRegistry>,
     track_id: String,
-) -> Result<(), BadRequest<()>> {
-    let id = TrackId::from_id(&track_id);
-    match id {
-        Err(_) => Err(BadRequest(None)),
-        Ok(unwrapped_id) => {
-            player_cmd.add_track_to_queue(unwrapped_id).await.unwrap();
-            store
-                .add_track_to_queue(SpotifyTrack {
-                    id: track_id,
-                    name: "n/a".to_owned(),
-                    duration: Duration::from_secs(0),
-                    album: SpotifyAlbum {
-                        name: "n/a".to_owned(),
-                        id: "n/a".to_owned(),
-                        cover_image_url: "n/a".to_owned(),
-                    },
-                })
-                .await
-                .unwrap();
-            Ok(())
-        }
+) -> Result<(), ApiError> {
+    let player_cmd = session_player(sessions, session_id).await?;
+    player_cmd
+        .add_track_to_queue(track_id, Some(guest.id))
+        .await?;
+    Ok(())
+}
+
+#[get("/session/<session_id>/queue")]
+pub async fn get_queued_tracks(
+    session_id: Uuid,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<Json<Vec<QueueEntry>>, ApiError> {
+    let player = session_player(sessions, session_id).await?;
+    let data = player.get_queued_tracks().await?;
+    return Ok(Json(data));
+}
+
+///Take an entry out of the queue. The host can remove anything, guests only the tracks
+/// they queued themselves.
+#[delete("/session/<_session_id>/queue/<entry_id>")]
+pub async fn remove_queue_entry(
+    _session_id: Uuid,
+    entry_id: i32,
+    member: Member,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<(), ApiError> {
+    let owner = if member.role().allows(Permission::RemoveAnyEntry) {
+        None
+    } else {
+        member.require(Permission::RemoveOwnEntries)?;
+        member.guest_id()
+    };
+
+    let player = session_player(sessions, member.session_id()).await?;
+    match player.remove_entry(entry_id, owner).await? {
+        RemoveEntryOutcome::Removed => Ok(()),
+        RemoveEntryOutcome::NotFound => Err(no_entry(entry_id)),
+        RemoveEntryOutcome::NotOwner => Err(ApiError::Forbidden(
+            "you can only remove tracks you queued".to_owned(),
+        )),
     }
 }
 
-#[get("/queue")]
-pub async fn get_queued_tracks(state: &State<PlayerCommader>) -> Json<Vec<TrackInfo>> {
-    let data = state.get_queued_tracks().await.unwrap();
-    return Json(data);
+///Move an entry to a place in the queue, 0 being the next to play. The entry keeps its
+/// place, and so does everything ahead of it, however the votes change afterwards.
+#[post("/session/<_session_id>/queue/<entry_id>/position/<position>")]
+pub async fn move_queue_entry(
+    _session_id: Uuid,
+    entry_id: i32,
+    position: u32,
+    member: Member,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<(), ApiError> {
+    member.require(Permission::ArrangeQueue)?;
+    let player = session_player(sessions, member.session_id()).await?;
+    match player.move_entry(entry_id, position).await? {
+        true => Ok(()),
+        false => Err(no_entry(entry_id)),
+    }
 }
 
-#[get("/current_state")]
-pub async fn get_current_state(
-    player: &State<PlayerCommader>,
-) -> Json<ddj_core::types::PlayerState> {
-    let current_track = player.get_currently_playing_track().await.unwrap();
-    let unwrapped: Option<Track> = current_track.map(|track: TrackInfo| (&track).into());
-
-    let queue = player.get_queued_tracks().await.unwrap();
-    let transformed_queue: Vec<Track> = queue.iter().map(|info| info.into()).collect();
-    println!("found {} in queue", transformed_queue.len());
-    return Json(PlayerState {
+fn no_entry(entry_id: i32) -> ApiError {
+    ApiError::NotFound(format!("no queue entry with id {}", entry_id))
+}
+
+#[delete("/session/<_session_id>/queue")]
+pub async fn clear_queue(
+    _session_id: Uuid,
+    member: Member,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<(), ApiError> {
+    member.require(Permission::RemoveAnyEntry)?;
+    let player = session_player(sessions, member.session_id()).await?;
+    player.clear_queue().await?;
+    Ok(())
+}
+
+#[get("/session/<session_id>/devices")]
+pub async fn get_devices(
+    session_id: Uuid,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<Json<Vec<Device>>, ApiError> {
+    let player = session_player(sessions, session_id).await?;
+    let devices = player.devices().await?;
+    Ok(Json(devices))
+}
+
+///Choose the device the session plays on. Playback moves to it straight away and the
+/// choice is remembered for the next time the session's player starts.
+#[post("/session/<session_id>/devices/<device_id>")]
+pub async fn select_device(
+    session_id: Uuid,
+    device_id: String,
+    member: Member,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<(), ApiError> {
+    member.require(Permission::ChangeSettings)?;
+    let player = session_player(sessions, session_id).await?;
+    match player.select_device(device_id.clone()).await? {
+        true => Ok(()),
+        false => Err(ApiError::NotFound(format!(
+            "no device with id {}",
+            device_id
+        ))),
+    }
+}
+
+///Choose a playlist for the session to fill in from once its queue runs dry, instead of
+/// recommendations based on what has played
+#[post("/session/<session_id>/fallback_playlist/<playlist_id>")]
+pub async fn set_fallback_playlist(
+    session_id: Uuid,
+    playlist_id: String,
+    member: Member,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<(), ApiError> {
+    member.require(Permission::ChangeSettings)?;
+    let player = session_player(sessions, session_id).await?;
+    match player
+        .set_fallback_playlist(Some(playlist_id.clone()))
+        .await?
+    {
+        true => Ok(()),
+        false => Err(ApiError::NotFound(format!(
+            "no playlist with id {}",
+            playlist_id
+        ))),
+    }
+}
+
+#[delete("/session/<session_id>/fallback_playlist")]
+pub async fn clear_fallback_playlist(
+    session_id: Uuid,
+    member: Member,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<(), ApiError> {
+    member.require(Permission::ChangeSettings)?;
+    let player = session_player(sessions, session_id).await?;
+    player.set_fallback_playlist(None).await?;
+    Ok(())
+}
+
+async fn player_state(player: &PlayerCommader) -> anyhow::Result<PlayerState> {
+    let playback = player.get_currently_playing_track().await?;
+    let unwrapped: Option<Track> = playback.track.as_ref().map(|track| track.into());
+
+    let queue = player.get_queued_tracks().await?;
+    let transformed_queue: Vec<QueuedTrack> =
+        queue.iter().map(|entry| entry.into()).collect();
+    return Ok(PlayerState {
         current_track: unwrapped,
         queue: transformed_queue,
+        playback_age_ms: playback.age().as_millis() as u64,
+        autofill: playback.autofill,
     });
 }
 
-#[options("/<_..>")]
-pub async fn handle_options<'a>() -> () {
-    ()
+///How the session's player is doing: whether it is running, how often it has crashed
+/// and been restarted, and whether it can reach Spotify
+#[get("/session/<session_id>/health")]
+pub async fn get_player_health(
+    session_id: Uuid,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<Json<PlayerHealth>, ApiError> {
+    let player = session_player(sessions, session_id).await?;
+    Ok(Json(player.health()))
+}
+
+#[get("/session/<session_id>/current_state")]
+pub async fn get_current_state(
+    session_id: Uuid,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<Json<ddj_core::types::PlayerState>, ApiError> {
+    let player = session_player(sessions, session_id).await?;
+    Ok(Json(player_state(&player).await?))
 }
 
-#[post("/start_auth_flow")]
-pub async fn start_auth_flow() -> (Status, Option<String>) {
-    let creds = Credentials::from_env();
-    if creds.is_none() {
-        println!("No credentials found in the enviornment, crashing!");
-        return (Status::InternalServerError, None);
+///Stream of PlayerEvents for a session as server-sent events. Starts with a snapshot of
+/// the current state and sends another whenever the client falls too far behind.
+#[get("/session/<session_id>/events")]
+pub async fn player_events(
+    session_id: Uuid,
+    sessions: &State<ManagedSessionRegistry>,
+    metrics: &State<ManagedMetrics>,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], ApiError> {
+    let player = session_player(sessions, session_id).await?;
+    let mut events = player.subscribe().await?;
+    let connection = metrics.client_connected();
+
+    Ok(EventStream! {
+        //counted as connected until the client goes away and the stream is dropped
+        let _connection = connection;
+        let mut send_snapshot = true;
+        loop {
+            if send_snapshot {
+                match player_state(&player).await {
+                    Ok(state) => yield Event::json(&PlayerEvent::Snapshot(state)),
+                    Err(e) => println!("failed to read player state: {}", e),
+                }
+                send_snapshot = false;
+            }
+
+            let event = select! {
+                received = events.recv() => match received {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => {
+                        send_snapshot = true;
+                        continue;
+                    }
+                    //the player shut down, the client will reconnect to a new one
+                    Err(RecvError::Closed) => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            yield Event::json(&event);
+        }
+    })
+}
+
+async fn cast_vote(
+    sessions: &SessionRegistry,
+    session_id: Uuid,
+    entry_id: i32,
+    guest: Guest,
+    vote: Option<Vote>,
+) -> Result<(), ApiError> {
+    let player = session_player(sessions, session_id).await?;
+    match player.vote(entry_id, guest.id, vote).await? {
+        true => Ok(()),
+        false => Err(no_entry(entry_id)),
+    }
+}
+
+#[post("/session/<session_id>/queue/<entry_id>/upvote")]
+pub async fn upvote(
+    session_id: Uuid,
+    entry_id: i32,
+    guest: Guest,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<(), ApiError> {
+    cast_vote(sessions, session_id, entry_id, guest, Some(Vote::Up)).await
+}
+
+#[post("/session/<session_id>/queue/<entry_id>/downvote")]
+pub async fn downvote(
+    session_id: Uuid,
+    entry_id: i32,
+    guest: Guest,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<(), ApiError> {
+    cast_vote(sessions, session_id, entry_id, guest, Some(Vote::Down)).await
+}
+
+#[delete("/session/<session_id>/queue/<entry_id>/vote")]
+pub async fn clear_vote(
+    session_id: Uuid,
+    entry_id: i32,
+    guest: Guest,
+    sessions: &State<ManagedSessionRegistry>,
+) -> Result<(), ApiError> {
+    cast_vote(sessions, session_id, entry_id, guest, None).await
+}
+
+///Hand out a fresh guest id for a session. Clients keep it and send it back in the
+/// X-DDJ-Guest header, it is also set as a cookie for clients which can't.
+#[post("/session/<session_id>/guest")]
+pub async fn new_guest(
+    session_id: Uuid,
+    store: &State<Store>,
+    cookies: &CookieJar<'_>,
+) -> Result<Json<GuestIdentity>, ApiError> {
+    require_session(store, session_id).await?;
+
+    let guest = store
+        .create_guest(session_id)
+        .await
+        .map_err(ApiError::Store)?;
+    cookies.add(Cookie::new(GUEST_COOKIE, guest.id.to_string()));
+    Ok(Json(GuestIdentity {
+        guest_id: guest.id,
+        session_id: guest.session_id,
+    }))
+}
+
+///What a session has played, latest first, a page at a time. The page after this one
+/// is fetched by passing its `next_before` as `before`.
+#[get("/session/<session_id>/history?<before>&<limit>")]
+pub async fn get_history(
+    session_id: Uuid,
+    before: Option<i32>,
+    limit: Option<u32>,
+    store: &State<Store>,
+) -> Result<Json<PlayHistory>, ApiError> {
+    require_session(store, session_id).await?;
+
+    let limit = limit
+        .unwrap_or(HISTORY_PAGE_SIZE)
+        .clamp(1, HISTORY_PAGE_SIZE);
+    let plays = store
+        .play_history(session_id, before, limit)
+        .await
+        .map_err(ApiError::Store)?;
+    let next_before = if plays.len() as u32 == limit {
+        plays.last().map(|played| played.id)
+    } else {
+        None
+    };
+    Ok(Json(PlayHistory {
+        plays: plays.into_iter().map(|played| played.into()).collect(),
+        next_before: next_before,
+    }))
+}
+
+///Where the frontend should send its requests, for deployments serving it from the
+/// backend's origin
+#[get("/config.json")]
+pub fn client_config(config: &State<Config>) -> Json<ClientConfig> {
+    Json(ClientConfig {
+        api_base: config.server.public_url.clone(),
+    })
+}
+
+///The process is up and answering requests
+#[get("/healthz")]
+pub fn healthz() -> &'static str {
+    "ok"
+}
+
+///Whether the backend can serve sessions: its database answers and is on this build's
+/// schema, and none of its players are down. Pass a session to also check it is logged
+/// in to Spotify and its player is up. Answers 503 unless every check passes.
+#[get("/readyz?<session>")]
+pub async fn readyz(
+    session: Option<Uuid>,
+    store: &State<Store>,
+    sessions: &State<ManagedSessionRegistry>,
+) -> (Status, Json<Readiness>) {
+    let players = sessions.health().await;
+    let mut checks = vec![database_check(store).await, players_check(&players)];
+    if let Some(session_id) = session {
+        checks.push(session_check(store, &players, session_id).await);
     }
-    let mut oauth_info = OAuth::from_env(authentication::scopes()).unwrap();
-    oauth_info.redirect_uri = "http://192.168.0.22:8080#login".to_owned();
 
-    let client = AuthCodeSpotify::new(creds.unwrap(), oauth_info);
-    let authorize_url = client.get_authorize_url(true).unwrap();
+    let ready = checks.iter().all(|check| check.ok);
+    let status = match ready {
+        true => Status::Ok,
+        false => Status::ServiceUnavailable,
+    };
+    (
+        status,
+        Json(Readiness {
+            ready: ready,
+            checks: checks,
+        }),
+    )
+}
+
+fn check(name: &str, ok: bool, detail: String) -> ReadinessCheck {
+    ReadinessCheck {
+        name: name.to_owned(),
+        ok: ok,
+        detail: detail,
+    }
+}
+
+async fn database_check(store: &Store) -> ReadinessCheck {
+    match store.schema_version().await {
+        Ok(version) if version == SCHEMA_VERSION => check(
+            "database",
+            true,
+            format!("schema is at version {}", version),
+        ),
+        Ok(version) => check(
+            "database",
+            false,
+            format!(
+                "schema is at version {}, this build expects version {}",
+                version, SCHEMA_VERSION
+            ),
+        ),
+        Err(e) => check("database", false, format!("unreachable: {}", e)),
+    }
+}
 
-    (Status::Ok, Some(authorize_url))
+fn players_check(players: &HashMap<Uuid, PlayerHealth>) -> ReadinessCheck {
+    let down: Vec<String> = players
+        .iter()
+        .filter(|(_, health)| health.status != PlayerStatus::Running)
+        .map(|(session_id, _)| session_id.to_string())
+        .collect();
+    match down.is_empty() {
+        true => check("players", true, format!("{} running", players.len())),
+        false => check(
+            "players",
+            false,
+            format!("players are down for sessions {}", down.join(", ")),
+        ),
+    }
 }
 
-#[post("/finish_auth_flow/<code>")]
-pub async fn finish_auth_flow(code: String, auth: &State<ManagedAuthState>) {
-    let creds = Credentials::from_env();
-    if creds.is_none() {
-        panic!("No credentials found in the enviornment, crashing!");
+async fn session_check(
+    store: &Store,
+    players: &HashMap<Uuid, PlayerHealth>,
+    session_id: Uuid,
+) -> ReadinessCheck {
+    let session = match store.get_session(session_id).await {
+        Ok(Some(session)) => session,
+        Ok(None) => return check("session", false, format!("no session {}", session_id)),
+        Err(e) => return check("session", false, format!("failed to look it up: {}", e)),
+    };
+    if session.token.is_none() {
+        return check("session", false, "not logged in to spotify".to_owned());
     }
-    let mut oauth_info = OAuth::from_env(authentication::scopes()).unwrap();
-    oauth_info.redirect_uri = "http://192.168.0.22:8080#login".to_owned();
-    let mut client = AuthCodeSpotify::new(creds.unwrap(), oauth_info);
+    match players.get(&session_id) {
+        Some(health) if health.status != PlayerStatus::Running => check(
+            "session",
+            false,
+            format!("player is {:?}", health.status).to_lowercase(),
+        ),
+        Some(_) => check("session", true, "logged in, player running".to_owned()),
+        None => check("session", true, "logged in, no player running".to_owned()),
+    }
+}
+
+///Counters for Prometheus to scrape
+#[get("/metrics")]
+pub fn get_metrics(metrics: &State<ManagedMetrics>) -> String {
+    metrics.render()
+}
 
-    client.request_token(&code).await.unwrap();
+#[options("/<_..>")]
+pub async fn handle_options<'a>() -> () {
+    ()
+}
 
-    let auth_state = AuthenticationState::new(client).await;
-    *auth.lock().await = Some(auth_state);
-    println!("successfully authenticated client")
+///Spotify client for logging a session in, built from the app credentials in the
+/// environment
+fn login_client(config: &SpotifyConfig) -> Result<AuthCodeSpotify, ApiError> {
+    let creds = Credentials::from_env().ok_or_else(|| {
+        ApiError::Internal(anyhow::Error::msg(
+            "no spotify app credentials found in the environment",
+        ))
+    })?;
+    let mut oauth_info = OAuth::from_env(authentication::scopes()).ok_or_else(|| {
+        ApiError::Internal(anyhow::Error::msg(
+            "no spotify oauth settings found in the environment",
+        ))
+    })?;
+    oauth_info.redirect_uri = config.redirect_uri.clone();
+    Ok(AuthCodeSpotify::new(creds, oauth_info))
 }
 
 #[post("/new_session/<name>")]
 pub async fn create_session(
     name: &str,
     store: &State<Store>,
-) -> Result<Json<CreateSessionResponse>, Status> {
-    let creds = Credentials::from_env().unwrap();
-    let mut oauth_info = OAuth::from_env(authentication::scopes()).unwrap();
-    oauth_info.redirect_uri = "http://192.168.0.22:8080#login".to_owned();
-    let client = AuthCodeSpotify::new(creds, oauth_info);
-    let authorize_url = client.get_authorize_url(true).unwrap();
-
-    let res = store.create_session(name).await;
-    match res {
-        Ok(session) => Ok(Json(CreateSessionResponse {
-            session: Session {
-                id: session.id,
-                name: name.to_owned(),
-            },
-            auth_link: authorize_url,
-        })),
-        Err(e) => {
-            println!("failed to create session: {}", e);
-            Err(Status::InternalServerError)
-        }
-    }
+    config: &State<Config>,
+) -> Result<Json<CreateSessionResponse>, ApiError> {
+    let client = login_client(&config.spotify)?;
+    let authorize_url = client
+        .get_authorize_url(true)
+        .map_err(|e| ApiError::Internal(e.into()))?;
+
+    let session = store.create_session(name).await.map_err(ApiError::Store)?;
+    Ok(Json(CreateSessionResponse {
+        session: Session {
+            id: session.id,
+            name: name.to_owned(),
+        },
+        auth_link: authorize_url,
+        host_token: session.host_token.unwrap_or_default(),
+    }))
 }
 
-#[post("/authenticate_session/<id>", data = "<code>")]
-pub async fn authenticate_session(id: &str, code: &str, store: &State<Store>) {
-    let creds = Credentials::from_env().unwrap();
-    let mut oauth_info = OAuth::from_env(authentication::scopes()).unwrap();
-    oauth_info.redirect_uri = "http://192.168.0.22:8080#login".to_owned();
-    let mut client = AuthCodeSpotify::new(creds, oauth_info);
-    client.request_token(&code).await.unwrap();
-    let session_id = uuid::Uuid::from_str(id).unwrap();
+///Store the Spotify credentials for a session. Only its host may do this, once for
+/// the first login and again whenever the session needs to be re-authenticated.
+#[post("/authenticate_session/<session_id>", data = "<code>")]
+pub async fn authenticate_session(
+    session_id: Uuid,
+    code: &str,
+    member: Member,
+    store: &State<Store>,
+    sessions: &State<ManagedSessionRegistry>,
+    config: &State<Config>,
+) -> Result<(), ApiError> {
+    member.require(Permission::Reauthenticate)?;
+    let mut client = login_client(&config.spotify)?;
+    client.request_token(&code).await.map_err(|e| {
+        ApiError::NotAuthenticated(format!(
+            "spotify did not accept the authorization code: {}",
+            e
+        ))
+    })?;
 
-    let mut session = store.get_session(session_id).await.unwrap().unwrap();
+    let mut session = match store.get_session(session_id).await {
+        Ok(Some(session)) => session,
+        Ok(None) => {
+            return Err(ApiError::NotFound(format!(
+                "no session with id {}",
+                session_id
+            )))
+        }
+        Err(e) => return Err(ApiError::Store(e)),
+    };
+
+    session.token = match client.token.lock().await {
+        Ok(token) => token.clone(),
+        Err(_) => {
+            return Err(ApiError::Internal(anyhow::Error::msg(
+                "failed to read the new spotify token",
+            )))
+        }
+    };
 
-    session.token = client.token.lock().await.unwrap().clone();
+    store
+        .update_session(&session)
+        .await
+        .map_err(ApiError::Store)?;
 
-    store.update_session(&session).await.unwrap();
+    //a running player still holds the old credentials
+    sessions.evict(session_id).await;
+    Ok(())
 }
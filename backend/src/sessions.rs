@@ -0,0 +1,217 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ddj_core::types::{PlayerHealth, PlayerStatus};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    metrics::ManagedMetrics,
+    persistence::Store,
+    player::{self, PlayerCommader, PlayerSettings},
+    provider::{metered::MeteredProvider, ProviderFactory},
+};
+
+///How long a player may go without requests before it is considered for shutdown
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+///How often the registry looks for idle players
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+pub type ManagedSessionRegistry = Arc<SessionRegistry>;
+
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error("no session exists with id {0}")]
+    NotFound(Uuid),
+
+    #[error("session {0} has not been authenticated with spotify")]
+    NotAuthenticated(Uuid),
+
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+struct SessionPlayer {
+    commander: PlayerCommader,
+    last_used: Instant,
+}
+
+///Owns one player actor per active PlaySession. Players are spawned the first time
+/// a session is used and shut down again once they have been idle for a while.
+pub struct SessionRegistry {
+    store: Store,
+    players: Mutex<HashMap<Uuid, SessionPlayer>>,
+    idle_timeout: Duration,
+    settings: PlayerSettings,
+    providers: ProviderFactory,
+    metrics: ManagedMetrics,
+}
+
+impl SessionRegistry {
+    pub fn new(
+        store: Store,
+        settings: PlayerSettings,
+        providers: ProviderFactory,
+        metrics: ManagedMetrics,
+    ) -> ManagedSessionRegistry {
+        Arc::new(SessionRegistry {
+            store: store,
+            players: Mutex::new(HashMap::new()),
+            idle_timeout: IDLE_TIMEOUT,
+            settings: settings,
+            providers: providers,
+            metrics: metrics,
+        })
+    }
+
+    ///Health of every player which is running, by session
+    pub async fn health(&self) -> HashMap<Uuid, PlayerHealth> {
+        let players = self.players.lock().await;
+        players
+            .iter()
+            .map(|(session_id, player)| (*session_id, player.commander.health()))
+            .collect()
+    }
+
+    ///Find the player for a session, starting one if it isn't already running. The
+    /// session and its credentials are looked up without holding the registry's lock,
+    /// so a slow store or Spotify doesn't hold up requests for every other session.
+    pub async fn player(&self, session_id: Uuid) -> Result<PlayerCommader, SessionError> {
+        let running = running_player(&mut *self.players.lock().await, session_id);
+        if let Some(running) = running {
+            return Ok(running);
+        }
+
+        let session = self
+            .store
+            .get_session(session_id)
+            .await?
+            .ok_or(SessionError::NotFound(session_id))?;
+        let token = session
+            .token
+            .ok_or(SessionError::NotAuthenticated(session_id))?;
+        let provider =
+            MeteredProvider::wrap((self.providers)(token)?, self.metrics.clone());
+
+        let mut players = self.players.lock().await;
+        //another request may have started one in the meantime
+        if let Some(running) = running_player(&mut players, session_id) {
+            return Ok(running);
+        }
+        let commander = player::start_player_thread(
+            provider,
+            self.store.clone(),
+            session_id,
+            session.device_id,
+            session.fallback_playlist,
+            self.settings.clone(),
+            self.metrics.clone(),
+        );
+        println!("started player for session {}", session_id);
+
+        players.insert(
+            session_id,
+            SessionPlayer {
+                commander: commander.clone(),
+                last_used: Instant::now(),
+            },
+        );
+
+        Ok(commander)
+    }
+
+    ///Stop the player for a session (if any) so the next request starts a fresh one.
+    /// Used when the session's credentials change.
+    pub async fn evict(&self, session_id: Uuid) {
+        let removed = self.players.lock().await.remove(&session_id);
+        if let Some(player) = removed {
+            if let Err(e) = player.commander.shutdown().await {
+                println!(
+                    "failed to shut down player for session {}: {}",
+                    session_id, e
+                );
+            }
+        }
+    }
+
+    ///Shut down players which have gone unused for a while and have nothing to play.
+    /// The players are asked whether they are idle without holding the registry's lock,
+    /// so a slow one doesn't hold up requests for every other session.
+    async fn shutdown_idle_players(&self) {
+        let candidates: Vec<(Uuid, PlayerCommader, Instant)> = {
+            let players = self.players.lock().await;
+            players
+                .iter()
+                .filter(|(_, player)| player.last_used.elapsed() >= self.idle_timeout)
+                .map(|(session_id, player)| {
+                    (*session_id, player.commander.clone(), player.last_used)
+                })
+                .collect()
+        };
+
+        let mut idle = Vec::new();
+        for (session_id, commander, last_used) in candidates {
+            match commander.is_idle().await {
+                Ok(true) => idle.push((session_id, last_used)),
+                Ok(false) => (),
+                Err(e) => {
+                    println!("player for session {} is unresponsive: {}", session_id, e);
+                    idle.push((session_id, last_used));
+                }
+            }
+        }
+
+        let mut stopping = Vec::new();
+        {
+            let mut players = self.players.lock().await;
+            for (session_id, last_used) in idle {
+                //a request may have come in, or the player been replaced, since
+                let unused = players
+                    .get(&session_id)
+                    .map_or(false, |player| player.last_used == last_used);
+                if unused {
+                    if let Some(player) = players.remove(&session_id) {
+                        stopping.push((session_id, player));
+                    }
+                }
+            }
+        }
+
+        for (session_id, player) in stopping {
+            println!("shutting down idle player for session {}", session_id);
+            let _ = player.commander.shutdown().await;
+        }
+    }
+}
+
+///The session's player if it is still running, marking it as used
+fn running_player(
+    players: &mut HashMap<Uuid, SessionPlayer>,
+    session_id: Uuid,
+) -> Option<PlayerCommader> {
+    let running = players.get_mut(&session_id)?;
+    if running.commander.health().status == PlayerStatus::Stopped {
+        println!(
+            "player for session {} has stopped, replacing it",
+            session_id
+        );
+        return None;
+    }
+    running.last_used = Instant::now();
+    Some(running.commander.clone())
+}
+
+pub fn start_idle_reaper(registry: ManagedSessionRegistry) {
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            registry.shutdown_idle_players().await;
+        }
+    });
+}
@@ -4,18 +4,60 @@ use std::{
 };
 
 use anyhow::Result;
-use reqwest::blocking::Client;
+use ddj_core::types::{
+    CreateSessionResponse, ErrorBody, GuestIdentity, PlayHistory, PlayedTrack,
+};
+use reqwest::{
+    blocking::{Client, Response},
+    StatusCode,
+};
 use thiserror::Error;
 
+///Error response from the backend, with its description of what went wrong
+#[derive(Debug, Error)]
+#[error("{body} ({status})")]
+pub struct ServerError {
+    pub status: StatusCode,
+    pub body: ErrorBody,
+}
+
+///Pass successful responses through, turning any other into an error. Errors the
+/// backend described become a ServerError.
+fn check(res: Response) -> Result<Response> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    match res.json::<ErrorBody>() {
+        Ok(body) => Err(ServerError {
+            status: status,
+            body: body,
+        }
+        .into()),
+        Err(_) => Err(anyhow::Error::msg(format!(
+            "server responded with {}",
+            status
+        ))),
+    }
+}
+
 pub struct DialecticDjClient {
     address: String,
     client: Client,
 }
 
 impl DialecticDjClient {
+    ///Client for the server at `address`, a base url such as `https://ddj.example.com`.
+    /// Plain `host:port` addresses are taken to be http.
     pub fn new(address: &str) -> DialecticDjClient {
+        let address = address.trim_end_matches('/');
+        let address = if address.contains("://") {
+            address.to_owned()
+        } else {
+            format!("http://{}", address)
+        };
         return DialecticDjClient {
-            address: address.to_owned(),
+            address: address,
             client: Client::new(),
         };
     }
@@ -23,7 +65,7 @@ impl DialecticDjClient {
     // pub fn search(&self, query: &str) -> Result<Vec<Track>> {
     //     let res = self
     //         .client
-    //         .post(format!("http://{}/search", self.address))
+    //         .post(format!("{}/search", self.address))
     //         .body(query.to_owned())
     //         .send()?;
 
@@ -32,19 +74,87 @@ impl DialecticDjClient {
     //     return Ok(json);
     // }
 
-    pub fn play_track(&self) -> Result<()> {
+    pub fn new_session(&self, name: &str) -> Result<CreateSessionResponse> {
         let res = self
             .client
-            .post(format!("http://{}/next_track", self.address))
+            .post(format!("{}/new_session/{}", self.address, name))
             .send()?;
+        let res = check(res)?;
+        return Ok(res.json()?);
+    }
+
+    ///Send a host-only playback control, `action` is the route under the session
+    pub fn control(&self, session: &str, host_token: &str, action: &str) -> Result<()> {
+        let res = self
+            .client
+            .post(format!("{}/session/{}/{}", self.address, session, action))
+            .header("X-DDJ-Host", host_token)
+            .send()?;
+        check(res)?;
         return Ok(());
     }
 
-    pub fn add_track_to_queue(&self, track: &str) -> Result<()> {
+    ///Delete something under a session as its host
+    pub fn remove(&self, session: &str, host_token: &str, path: &str) -> Result<()> {
         let res = self
             .client
-            .post(format!("http://{}/queue/{}", self.address, track))
+            .delete(format!("{}/session/{}/{}", self.address, session, path))
+            .header("X-DDJ-Host", host_token)
             .send()?;
+        check(res)?;
         return Ok(());
     }
+
+    pub fn new_guest(&self, session: &str) -> Result<GuestIdentity> {
+        let res = self
+            .client
+            .post(format!("{}/session/{}/guest", self.address, session))
+            .send()?;
+        let res = check(res)?;
+        return Ok(res.json()?);
+    }
+
+    pub fn add_track_to_queue(
+        &self,
+        session: &str,
+        guest: &str,
+        track: &str,
+    ) -> Result<()> {
+        let res = self
+            .client
+            .post(format!(
+                "{}/session/{}/queue/{}",
+                self.address, session, track
+            ))
+            .header("X-DDJ-Guest", guest)
+            .send()?;
+        check(res)?;
+        return Ok(());
+    }
+
+    ///One page of a session's history, latest first
+    pub fn history(&self, session: &str, before: Option<i32>) -> Result<PlayHistory> {
+        let mut url = format!("{}/session/{}/history", self.address, session);
+        if let Some(before) = before {
+            url.push_str(&format!("?before={}", before));
+        }
+        let res = check(self.client.get(url).send()?)?;
+        return Ok(res.json()?);
+    }
+
+    ///Everything a session has played, in the order it played
+    pub fn setlist(&self, session: &str) -> Result<Vec<PlayedTrack>> {
+        let mut plays = Vec::new();
+        let mut before = None;
+        loop {
+            let page = self.history(session, before)?;
+            plays.extend(page.plays);
+            match page.next_before {
+                Some(next) => before = Some(next),
+                None => break,
+            }
+        }
+        plays.reverse();
+        return Ok(plays);
+    }
 }
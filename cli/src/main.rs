@@ -1,16 +1,39 @@
 use clap::clap_derive::*;
 use clap::Parser;
 use client::DialecticDjClient;
+use ddj_core::types::Requester;
 
 mod client;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct TopLevel {
+    #[clap(
+        long,
+        env = "DDJ_SERVER",
+        default_value = "http://localhost:8090",
+        value_parser,
+        global = true
+    )]
+    /// address of the ddj server, the scheme may be left out
+    server: String,
+
     #[clap(subcommand)]
     command: Subcommands,
 }
 
+///Identifies the session being controlled and proves the caller is its host
+#[derive(Args, Debug)]
+struct HostArgs {
+    #[clap(short, long, value_parser)]
+    /// id of the session to control
+    session: String,
+
+    #[clap(long, value_parser)]
+    /// host token printed when the session was created
+    host_token: String,
+}
+
 #[derive(Subcommand, Debug)]
 enum Subcommands {
     Search {
@@ -19,19 +42,130 @@ enum Subcommands {
         query: String,
     },
 
-    Play,
+    /// Start playing the session's queue
+    Play {
+        #[clap(flatten)]
+        host: HostArgs,
+    },
 
     Add {
+        #[clap(short, long, value_parser)]
+        /// id of the session to add to
+        session: String,
+
         #[clap(short, long, value_parser)]
         /// add a track to the DJ queue
         track: String,
+
+        #[clap(short, long, value_parser)]
+        /// guest id to add the track as, a new guest is created when omitted
+        guest: Option<String>,
+    },
+
+    /// Create a session and print its host token
+    NewSession {
+        #[clap(short, long, value_parser)]
+        /// name of the new session
+        name: String,
+    },
+
+    /// Pause playback
+    Pause {
+        #[clap(flatten)]
+        host: HostArgs,
+    },
+
+    /// Resume playback
+    Resume {
+        #[clap(flatten)]
+        host: HostArgs,
+    },
+
+    /// Skip to the next track in the queue
+    Skip {
+        #[clap(flatten)]
+        host: HostArgs,
+    },
+
+    /// Go back to the previous track
+    Previous {
+        #[clap(flatten)]
+        host: HostArgs,
+    },
+
+    /// Set the playback volume
+    Volume {
+        #[clap(flatten)]
+        host: HostArgs,
+
+        #[clap(short, long, value_parser = clap::value_parser!(u8).range(0..=100))]
+        /// volume from 0 to 100
+        percent: u8,
+    },
+
+    /// Jump to a position in the current track
+    Seek {
+        #[clap(flatten)]
+        host: HostArgs,
+
+        #[clap(long, value_parser)]
+        /// position in seconds from the start of the track
+        position: u64,
+    },
+
+    /// Take an entry out of the queue
+    Remove {
+        #[clap(flatten)]
+        host: HostArgs,
+
+        #[clap(short, long, value_parser)]
+        /// id of the queue entry
+        entry: i32,
+    },
+
+    /// Move an entry to a position in the queue, 0 plays next
+    Move {
+        #[clap(flatten)]
+        host: HostArgs,
+
+        #[clap(short, long, value_parser)]
+        /// id of the queue entry
+        entry: i32,
+
+        #[clap(short, long, value_parser)]
+        /// position to move the entry to
+        position: u32,
+    },
+
+    /// Remove every track from the queue
+    Clear {
+        #[clap(flatten)]
+        host: HostArgs,
+    },
+
+    /// Print every track a session has played, in order
+    Setlist {
+        #[clap(short, long, value_parser)]
+        /// id of the session
+        session: String,
+    },
+
+    /// Fill in from a playlist when the queue runs dry, or from recommendations when
+    /// no playlist is given
+    Fallback {
+        #[clap(flatten)]
+        host: HostArgs,
+
+        #[clap(short, long, value_parser)]
+        /// id of the Spotify playlist to fall back on
+        playlist: Option<String>,
     },
 }
 
 fn main() {
     let args = TopLevel::parse();
 
-    let client = DialecticDjClient::new("localhost:8090");
+    let client = DialecticDjClient::new(&args.server);
 
     match args.command {
         Subcommands::Search { query } => {
@@ -65,11 +199,112 @@ fn main() {
             //     }
             // }
         }
-        Subcommands::Play => {
-            client.play_track().unwrap();
+        Subcommands::Play { host } => control(&client, &host, "next_track"),
+        Subcommands::Add {
+            session,
+            track,
+            guest,
+        } => {
+            let guest = match guest {
+                Some(guest) => guest,
+                None => match client.new_guest(&session) {
+                    Ok(identity) => {
+                        println!("adding as guest {}", identity.guest_id);
+                        identity.guest_id.to_string()
+                    }
+                    Err(err) => {
+                        println!("failed to join session: {}", err);
+                        return;
+                    }
+                },
+            };
+            if let Err(err) = client.add_track_to_queue(&session, &guest, &track) {
+                println!("failed to add track: {}", err);
+            }
         }
-        Subcommands::Add { track } => {
-            client.add_track_to_queue(&track).unwrap();
+        Subcommands::NewSession { name } => match client.new_session(&name) {
+            Ok(created) => {
+                println!("Session:    {}", created.session.id);
+                println!("Host token: {}", created.host_token);
+                println!("Log in at:  {}", created.auth_link);
+            }
+            Err(err) => println!("failed to create session: {}", err),
+        },
+        Subcommands::Pause { host } => control(&client, &host, "pause"),
+        Subcommands::Resume { host } => control(&client, &host, "resume"),
+        Subcommands::Skip { host } => control(&client, &host, "skip"),
+        Subcommands::Previous { host } => control(&client, &host, "previous"),
+        Subcommands::Volume { host, percent } => {
+            control(&client, &host, &format!("volume/{}", percent))
         }
+        Subcommands::Seek { host, position } => {
+            control(&client, &host, &format!("seek/{}", position * 1000))
+        }
+        Subcommands::Remove { host, entry } => {
+            let path = format!("queue/{}", entry);
+            if let Err(err) = client.remove(&host.session, &host.host_token, &path) {
+                println!("failed to remove entry {}: {}", entry, err);
+            }
+        }
+        Subcommands::Move {
+            host,
+            entry,
+            position,
+        } => control(
+            &client,
+            &host,
+            &format!("queue/{}/position/{}", entry, position),
+        ),
+        Subcommands::Clear { host } => {
+            if let Err(err) = client.remove(&host.session, &host.host_token, "queue") {
+                println!("failed to clear the queue: {}", err);
+            }
+        }
+        Subcommands::Setlist { session } => match client.setlist(&session) {
+            Ok(plays) => {
+                for (number, played) in plays.iter().enumerate() {
+                    let artists: Vec<&str> = played
+                        .track
+                        .artists
+                        .iter()
+                        .map(|artist| &artist.name[..])
+                        .collect();
+                    let autofill = match played.requester {
+                        Requester::Autofill => " (autofill)",
+                        _ => "",
+                    };
+                    let skipped = match played.skipped {
+                        true => " (skipped)",
+                        false => "",
+                    };
+                    println!(
+                        "{:>3}. {} - {}{}{}",
+                        number + 1,
+                        played.track.name,
+                        artists.join(", "),
+                        autofill,
+                        skipped
+                    );
+                }
+            }
+            Err(err) => println!("failed to fetch the setlist: {}", err),
+        },
+        Subcommands::Fallback { host, playlist } => match playlist {
+            Some(playlist) => {
+                control(&client, &host, &format!("fallback_playlist/{}", playlist))
+            }
+            None => {
+                let path = "fallback_playlist";
+                if let Err(err) = client.remove(&host.session, &host.host_token, path) {
+                    println!("failed to clear the fallback playlist: {}", err);
+                }
+            }
+        },
+    }
+}
+
+fn control(client: &DialecticDjClient, host: &HostArgs, action: &str) {
+    if let Err(err) = client.control(&host.session, &host.host_token, action) {
+        println!("failed to {}: {}", action, err);
     }
 }
@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{fmt, time::Duration};
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -17,10 +17,127 @@ pub struct Track {
     pub album_art_link: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+///A track waiting in a session's queue along with the votes it has collected
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct QueuedTrack {
+    pub entry_id: i32,
+    pub track: Track,
+    pub upvotes: u32,
+    pub downvotes: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct PlayerState {
     pub current_track: Option<Track>,
-    pub queue: Vec<Track>,
+    pub queue: Vec<QueuedTrack>,
+    ///How long ago `current_track` was read from Spotify
+    pub playback_age_ms: u64,
+    ///Whether `current_track` was picked by the player because the queue ran dry, it
+    /// gives way to the first track anyone queues
+    pub autofill: bool,
+}
+
+///Whether a session's player is up
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayerStatus {
+    Running,
+    ///The player crashed and is waiting to be started again
+    Restarting,
+    ///The player was shut down and won't come back until the session is used again
+    Stopped,
+}
+
+///How a session's player has been doing since it was started
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct PlayerHealth {
+    pub status: PlayerStatus,
+    ///How many times the player has crashed and been started again
+    pub restarts: u32,
+    ///What made the player crash the last time it did
+    pub last_crash: Option<String>,
+    ///How many playback refreshes in a row have failed, Spotify being unreachable shows
+    /// up here
+    pub failed_refreshes: u32,
+}
+
+///One of the things the backend needs to serve sessions
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ReadinessCheck {
+    pub name: String,
+    pub ok: bool,
+    pub detail: String,
+}
+
+///Answer to /readyz, the backend is ready when every check passes
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<ReadinessCheck>,
+}
+
+///Answer to /config.json, where the frontend should send its requests
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Default)]
+pub struct ClientConfig {
+    ///Base url of the API, the origin config.json was fetched from when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_base: Option<String>,
+}
+
+///Who a track in a session's history was played for
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Requester {
+    Guest {
+        guest_id: Uuid,
+    },
+    Host,
+    ///The player picked the track because the queue ran dry
+    Autofill,
+}
+
+///A track a session played
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PlayedTrack {
+    pub play_id: i32,
+    pub track: Track,
+    ///When the track was handed to Spotify, in milliseconds since the Unix epoch
+    pub played_at_ms: i64,
+    pub requester: Requester,
+    ///Whether it was left well before its end
+    pub skipped: bool,
+}
+
+///A page of a session's history, latest first
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PlayHistory {
+    pub plays: Vec<PlayedTrack>,
+    ///Pass as `before` to get the page after this one, None on the last page
+    pub next_before: Option<i32>,
+}
+
+///Change to a session's player, pushed to subscribed clients as it happens
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PlayerEvent {
+    ///The full state, sent when a client subscribes and whenever it may have missed events
+    Snapshot(PlayerState),
+
+    ///A different track is now playing
+    TrackChanged {
+        current_track: Option<Track>,
+        autofill: bool,
+    },
+
+    ///Tracks were added to or removed from the queue, or its order changed
+    QueueChanged { queue: Vec<QueuedTrack> },
+
+    ///The votes on a single queue entry changed
+    VotesChanged {
+        entry_id: i32,
+        upvotes: u32,
+        downvotes: u32,
+    },
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -33,6 +150,159 @@ pub struct Session {
 pub struct CreateSessionResponse {
     pub session: Session,
     pub auth_link: String,
+    ///Secret identifying the session's host, sent back in the X-DDJ-Host header to
+    /// control playback
+    pub host_token: String,
+}
+
+///Device a session can play on
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    ///Whether the device is the one currently playing for the account
+    pub is_active: bool,
+    ///Whether the host picked this device for the session
+    pub is_selected: bool,
+    pub volume_percent: Option<u32>,
+}
+
+///Guest id issued for a session. Clients send it back in the X-DDJ-Guest header.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct GuestIdentity {
+    pub guest_id: Uuid,
+    pub session_id: Uuid,
+}
+
+///Why a track was not added to the queue. Sent in the ErrorBody of a 429 response when
+/// a guest hit a rate limit, or a 409 response when the track broke one of the session's
+/// rules about what can be queued.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum QueueRejection {
+    ///The guest already has as many tracks waiting as they are allowed
+    TooManyQueued { limit: u32 },
+
+    ///The guest has added too many tracks recently
+    TooManyAdds {
+        limit: u32,
+        window_secs: u64,
+        retry_after_secs: u64,
+    },
+
+    ///The track is already waiting in the queue
+    AlreadyQueued,
+
+    ///The track played too recently to be queued again
+    PlayedRecently {
+        cooldown_secs: u64,
+        retry_after_secs: u64,
+    },
+
+    ///Queueing the track would put too many tracks by one artist in a row
+    ArtistStreak { artist: String, limit: u32 },
+}
+
+impl QueueRejection {
+    ///Whether the rejection comes from a rate limit, which goes away with time, rather
+    /// than from one of the rules about what can be queued
+    pub fn is_rate_limit(&self) -> bool {
+        match self {
+            QueueRejection::TooManyQueued { .. } | QueueRejection::TooManyAdds { .. } => {
+                true
+            }
+            QueueRejection::AlreadyQueued
+            | QueueRejection::PlayedRecently { .. }
+            | QueueRejection::ArtistStreak { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for QueueRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueRejection::TooManyQueued { limit } => {
+                write!(f, "you already have {} tracks in the queue", limit)
+            }
+            QueueRejection::TooManyAdds {
+                limit,
+                window_secs,
+                retry_after_secs,
+            } => write!(
+                f,
+                "you can only add {} tracks every {} minutes, try again in {} seconds",
+                limit,
+                window_secs / 60,
+                retry_after_secs
+            ),
+            QueueRejection::AlreadyQueued => write!(f, "that track is already queued"),
+            QueueRejection::PlayedRecently {
+                cooldown_secs,
+                retry_after_secs,
+            } => write!(
+                f,
+                "that track played in the last {} minutes, it can be queued again in {} \
+                 minutes",
+                cooldown_secs / 60,
+                (retry_after_secs + 59) / 60
+            ),
+            QueueRejection::ArtistStreak { artist, limit } => write!(
+                f,
+                "there can't be more than {} tracks by {} in a row",
+                limit, artist
+            ),
+        }
+    }
+}
+
+///What went wrong with a request, so clients can react without parsing the message
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    ///The request did not identify a guest or host, or the session has not been logged
+    /// in to Spotify
+    NotAuthenticated,
+
+    ///Whoever made the request is not allowed to do what they asked
+    Forbidden,
+
+    ///The session, queue entry or whatever else the request refers to doesn't exist
+    NotFound,
+
+    ///The request itself doesn't make sense, such as an out of range volume
+    Validation,
+
+    ///A track was turned away by one of the session's rules about what can be queued
+    Rejected,
+
+    ///The guest hit a rate limit and has to wait before trying again
+    RateLimited,
+
+    ///Spotify failed or refused the request
+    Provider,
+
+    ///The backend failed to read or write its data
+    Store,
+
+    ///Anything else that went wrong on the backend
+    Internal,
+}
+
+///Body of every error response from the backend
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ErrorBody {
+    pub kind: ErrorKind,
+    ///Description of the error which can be shown to the user
+    pub message: String,
+    ///Why a track was not added to the queue, for rejected and rate_limited errors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rejection: Option<QueueRejection>,
+}
+
+impl fmt::Display for ErrorBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
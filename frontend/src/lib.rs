@@ -3,81 +3,169 @@
 // but some rules are too "annoying" or are not applicable for your case.)
 #![allow(clippy::wildcard_imports)]
 
-use std::str::FromStr;
+use std::cell::RefCell;
 
 use ddj_core::types::{
-    AuthenticateClientMessage, CreateSessionResponse, PlayerState, Session, Track,
+    AuthenticateClientMessage, ClientConfig, CreateSessionResponse, Device, ErrorBody,
+    GuestIdentity, PlayHistory, PlayedTrack, PlayerEvent, PlayerState, QueuedTrack,
+    Session, Track,
 };
 use seed::{prelude::*, *};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
+use web_sys::{EventSource, MessageEvent};
 
 const SEARCH: &str = "search";
 const DEVICE_SELECTION: &str = "device_selection";
 const LOGIN: &str = "login";
 
+const SESSION_STORAGE_KEY: &str = "ddj-session";
+const GUEST_STORAGE_KEY: &str = "ddj-guest";
+const GUEST_HEADER: &str = "X-DDJ-Guest";
+const HOST_STORAGE_KEY: &str = "ddj-host-token";
+const HOST_HEADER: &str = "X-DDJ-Host";
+
+///How many of the latest plays are shown under the queue
+const RECENTLY_PLAYED_COUNT: u32 = 5;
+
+///`<meta>` tag index.html can name the backend's API with, see `find_api_base`
+const API_BASE_META: &str = "ddj-api-base";
+
+const MIN_RECONNECT_DELAY_MS: u32 = 1000;
+const MAX_RECONNECT_DELAY_MS: u32 = 30000;
+
 // ------ ------
 //     Init
 // ------ ------
 
 // `init` describes what should happen when your app started.
 fn init(url: Url, orders: &mut impl Orders<Msg>) -> Model {
-    orders.stream(streams::interval(10000, || Msg::UpdateState));
-
-    let page = match url.hash_path().get(0) {
-        Some(path) => {
-            if path == LOGIN {
-                log!("login page init");
-                match url.search().get("code") {
-                    Some(values) => {
-                        let first = values.first();
-                        log!(first);
-                        match first {
-                            Some(code) => {
-                                let cloned = code.clone();
-                                orders.perform_cmd(async move {
-                                    
-
-                                    send_code(&cloned).await;
-                                });
-                                Page::Landing
-                            }
-                            None => Page::Landing,
-                        }
-                    }
-                    None => {
-                        orders.perform_cmd(async {
-                            Msg::AuthUrlAvailable(request_login_url().await)
-                        });
+    let page = page_for(&url);
 
-                        Page::Login(None)
-                    }
-                }
-            } else {
-                Page::Landing
-            }
-        }
-        None => Page::Landing,
-    };
+    let session = stored_session();
+    //guest ids only work for the session they were issued for
+    let guest = stored_guest().filter(|guest| {
+        session
+            .as_ref()
+            .map(|session| session.id == guest.session_id)
+            .unwrap_or(false)
+    });
 
-    if page == Page::Landing {
-        update_state(orders);
-        Url::from_str("http://192.168.0.22:8080/")
-            .unwrap()
-            .go_and_replace();
-    }
+    //nothing can be fetched until we know where the backend is, `load_page` picks up
+    // from there
+    orders.perform_cmd(async move { Msg::ApiBaseFound(url, find_api_base().await) });
 
     Model {
         page: page,
         loaded: LoadingState::Loading,
         currently_playing: None,
+        autofill: false,
         queue: Vec::new(),
+        recently_played: Vec::new(),
         search_model: SearchModel {
             results: Vec::new(),
             in_progress: false,
             error: None,
         },
-        session: None,
+        session: session,
+        guest: guest,
+        devices: Vec::new(),
+        notice: None,
+        events: None,
+        reconnect_delay_ms: MIN_RECONNECT_DELAY_MS,
+    }
+}
+
+///Page to open for a url. Hosts come back from logging in to Spotify on the login page
+/// with a code, and go on to the queue from there.
+fn page_for(url: &Url) -> Page {
+    match url.hash_path().get(0) {
+        Some(path) if path == LOGIN => match url.search().get("code") {
+            Some(_) => Page::Landing,
+            None => Page::Login(None),
+        },
+        Some(path) if path == DEVICE_SELECTION => Page::DeviceSelection,
+        _ => Page::Landing,
+    }
+}
+
+///Code Spotify sent the host back to the login page with, if it did
+fn login_code(url: &Url) -> Option<String> {
+    if url.hash_path().get(0)? != LOGIN {
+        return None;
+    }
+    url.search().get("code")?.first().cloned()
+}
+
+///Load whatever the page needs once the backend has been found
+fn load_page(url: Url, model: &mut Model, orders: &mut impl Orders<Msg>) {
+    if let Some(code) = login_code(&url) {
+        forget_login_code(&url);
+        match &model.session {
+            Some(session) => {
+                let session_id = session.id;
+                orders.perform_cmd(async move {
+                    if let Err(err) = send_code(&code, session_id).await {
+                        log!("failed to log in: {:?}", err);
+                    }
+                });
+            }
+            None => log!("got an auth code without a session to use it for"),
+        }
+    }
+    if model.page == Page::Login(None) {
+        orders.perform_cmd(async { Msg::AuthUrlAvailable(request_login_url().await) });
+    }
+
+    if let (Some(session), None) = (&model.session, &model.guest) {
+        let session_id = session.id;
+        orders.perform_cmd(async move {
+            Msg::GuestAvailable(request_guest(session_id).await)
+        });
+    }
+
+    if let (Page::DeviceSelection, Some(session)) = (&model.page, &model.session) {
+        request_devices(session.id, orders);
+    }
+
+    if model.page == Page::Landing {
+        if let Some(session) = &model.session {
+            update_state(session.id, orders);
+            model.events = subscribe(session.id, orders);
+        }
+    }
+}
+
+///Take the code out of the address bar so reloading doesn't try to log in with it
+/// again, without reloading the page
+fn forget_login_code(url: &Url) {
+    let path = format!("/{}", url.path().join("/"));
+    let replaced = window().history().and_then(|history| {
+        history.replace_state_with_url(&JsValue::NULL, "", Some(&path))
+    });
+    if let Err(err) = replaced {
+        log!("failed to clean up the login url: {:?}", err);
+    }
+}
+
+fn stored_session() -> Option<Session> {
+    LocalStorage::get(SESSION_STORAGE_KEY).ok()
+}
+
+fn stored_guest() -> Option<GuestIdentity> {
+    LocalStorage::get(GUEST_STORAGE_KEY).ok()
+}
+
+///Token proving this browser created the session, only the host has one
+fn stored_host_token() -> Option<String> {
+    LocalStorage::get(HOST_STORAGE_KEY).ok()
+}
+
+///Send the host token along with a request, when this browser has one
+fn as_host(request: Request) -> Request {
+    match stored_host_token() {
+        Some(token) => request.header(Header::custom(HOST_HEADER, token)),
+        None => request,
     }
 }
 
@@ -110,9 +198,31 @@ struct Model {
     page: Page,
     loaded: LoadingState,
     currently_playing: Option<Track>,
-    queue: Vec<Track>,
+    ///Whether the current track was picked by the player to fill in for an empty queue
+    autofill: bool,
+    queue: Vec<QueuedTrack>,
+    recently_played: Vec<PlayedTrack>,
     search_model: SearchModel,
     session: Option<Session>,
+    guest: Option<GuestIdentity>,
+    devices: Vec<Device>,
+    notice: Option<String>,
+    events: Option<EventSubscription>,
+    reconnect_delay_ms: u32,
+}
+
+///Open connection to a session's event stream. The connection is closed when this
+/// is dropped.
+struct EventSubscription {
+    source: EventSource,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_error: Closure<dyn FnMut(JsValue)>,
+}
+
+impl Drop for EventSubscription {
+    fn drop(&mut self) {
+        self.source.close();
+    }
 }
 
 struct SearchModel {
@@ -128,35 +238,60 @@ struct SearchModel {
 // (Remove the line below once any of your `Msg` variants doesn't implement `Copy`.)
 // `Msg` describes the different events you can modify state with.
 enum Msg {
-    NewStateAvailable(fetch::Result<PlayerState>),
+    ApiBaseFound(Url, String),
+    NewStateAvailable(RequestResult<PlayerState>),
+    HistoryAvailable(RequestResult<PlayHistory>),
     EnterSearchMode,
     SearchInputChanged(String),
-    SearchResultAvailable(fetch::Result<Vec<Track>>),
+    SearchResultAvailable(RequestResult<Vec<Track>>),
     TrackClicked(Track),
-    UpdateState,
-    AuthUrlAvailable(fetch::Result<CreateSessionResponse>),
+    TrackAdded(RequestResult<()>),
+    PlayerEventReceived(PlayerEvent),
+    EventStreamFailed,
+    Reconnect,
+    AuthUrlAvailable(RequestResult<CreateSessionResponse>),
+    GuestAvailable(RequestResult<GuestIdentity>),
+    Upvote(i32),
+    Downvote(i32),
+    EnterDeviceSelection,
+    LeaveDeviceSelection,
+    DevicesAvailable(RequestResult<Vec<Device>>),
+    DeviceClicked(String),
+    DeviceSelected(RequestResult<()>),
 }
 
 // `update` describes how to handle each `Msg`.
 fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
     match msg {
+        Msg::ApiBaseFound(url, api_base) => {
+            log!("using the API at", api_base);
+            API_BASE.with(|base| *base.borrow_mut() = api_base);
+            load_page(url, model, orders);
+        }
         Msg::NewStateAvailable(fetch_result) => match fetch_result {
             Ok(player_state) => {
                 model.loaded = LoadingState::Done;
                 model.currently_playing = player_state.current_track;
+                model.autofill = player_state.autofill;
                 model.queue = player_state.queue;
             }
-            Err(_) => {
-                model.loaded = LoadingState::Error("player state fetch failed".to_owned())
+            Err(err) => {
+                model.loaded =
+                    LoadingState::Error(err.describe("player state fetch failed"))
             }
         },
+        Msg::HistoryAvailable(fetch_result) => match fetch_result {
+            Ok(history) => model.recently_played = history.plays,
+            Err(err) => log!("failed to fetch history: {:?}", err),
+        },
         Msg::EnterSearchMode => {
             model.page = Page::Search(false);
         }
         Msg::SearchInputChanged(new_input) => {
-            if model.page == Page::Search(false) {
+            if let (Page::Search(false), Some(session)) = (&model.page, &model.session) {
+                let session_id = session.id;
                 orders.perform_cmd(async move {
-                    Msg::SearchResultAvailable(search(&new_input).await)
+                    Msg::SearchResultAvailable(search(session_id, &new_input).await)
                 });
                 model.search_model.in_progress = true;
             }
@@ -166,98 +301,478 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
                 model.search_model.in_progress = false;
                 model.search_model.results = search_result;
             }
-            Err(_) => {
-                model.search_model.error = Some("search query failed".to_owned());
+            Err(err) => {
+                model.search_model.error = Some(err.describe("search query failed"));
             }
         },
-        Msg::TrackClicked(track) => match model.page {
-            Page::Search(_) => {
+        Msg::TrackClicked(track) => match (&model.page, &model.session, &model.guest) {
+            (Page::Search(_), Some(session), Some(guest)) => {
+                let session_id = session.id;
+                let guest_id = guest.guest_id;
                 orders.perform_cmd(async move {
-                    add_track_to_queue(&track).await;
+                    Msg::TrackAdded(
+                        add_track_to_queue(session_id, guest_id, &track).await,
+                    )
                 });
                 model.page = Page::Landing;
-                update_state(orders);
             }
             _ => {}
         },
-        Msg::UpdateState => {
-            orders.skip();
-            match model.page {
-                Page::Landing => {
-                    update_state(orders);
+        Msg::TrackAdded(result) => {
+            model.notice = match result {
+                Ok(()) => None,
+                Err(err) => {
+                    log!("failed to add track: {:?}", err);
+                    Some(err.describe("failed to add track"))
                 }
-                _ => (),
+            };
+        }
+        Msg::PlayerEventReceived(event) => {
+            model.reconnect_delay_ms = MIN_RECONNECT_DELAY_MS;
+            if let (PlayerEvent::TrackChanged { .. }, Some(session)) =
+                (&event, &model.session)
+            {
+                update_history(session.id, orders);
+            }
+            apply_event(model, event);
+        }
+        Msg::EventStreamFailed => {
+            model.events = None;
+            let delay = model.reconnect_delay_ms;
+            model.reconnect_delay_ms = (delay * 2).min(MAX_RECONNECT_DELAY_MS);
+            log!("lost the event stream, reconnecting in {}ms", delay);
+            orders.perform_cmd(cmds::timeout(delay, || Msg::Reconnect));
+
+            //keep the page roughly current while disconnected
+            if let Some(session) = &model.session {
+                update_state(session.id, orders);
+            }
+        }
+        Msg::Reconnect => {
+            orders.skip();
+            if let (Page::Landing, Some(session), None) =
+                (&model.page, &model.session, &model.events)
+            {
+                model.events = subscribe(session.id, orders);
             }
         }
         Msg::AuthUrlAvailable(url) => match url {
             Ok(session) => {
                 log!("got auth url");
+                if let Err(err) =
+                    LocalStorage::insert(SESSION_STORAGE_KEY, &session.session)
+                {
+                    log!("failed to store session: {:?}", err);
+                }
+                if let Err(err) =
+                    LocalStorage::insert(HOST_STORAGE_KEY, &session.host_token)
+                {
+                    log!("failed to store host token: {:?}", err);
+                }
+                let session_id = session.session.id;
+                orders.perform_cmd(async move {
+                    Msg::GuestAvailable(request_guest(session_id).await)
+                });
                 model.page = Page::Login(Some(session.auth_link));
                 model.session = Some(session.session);
             }
             Err(err) => {
-                log!("failed to recieve redirect URL: {}",);
+                log!("failed to recieve redirect URL: {:?}", err);
+                model.notice = Some(err.describe("failed to create a session"));
             }
         },
+        Msg::GuestAvailable(identity) => match identity {
+            Ok(identity) => {
+                if let Err(err) = LocalStorage::insert(GUEST_STORAGE_KEY, &identity) {
+                    log!("failed to store guest id: {:?}", err);
+                }
+                model.guest = Some(identity);
+            }
+            Err(err) => {
+                log!("failed to get a guest id: {:?}", err);
+            }
+        },
+        Msg::Upvote(entry_id) => cast_vote(model, orders, entry_id, "upvote"),
+        Msg::Downvote(entry_id) => cast_vote(model, orders, entry_id, "downvote"),
+        Msg::EnterDeviceSelection => {
+            model.page = Page::DeviceSelection;
+            if let Some(session) = &model.session {
+                request_devices(session.id, orders);
+            }
+        }
+        Msg::LeaveDeviceSelection => show_landing(model, orders),
+        Msg::DevicesAvailable(devices) => match devices {
+            Ok(devices) => model.devices = devices,
+            Err(err) => {
+                log!("failed to list devices: {:?}", err);
+                model.notice = Some(err.describe("couldn't find any devices"));
+            }
+        },
+        Msg::DeviceClicked(device_id) => {
+            if let Some(session) = &model.session {
+                let session_id = session.id;
+                orders.perform_cmd(async move {
+                    Msg::DeviceSelected(select_device(session_id, &device_id).await)
+                });
+            }
+        }
+        Msg::DeviceSelected(result) => match result {
+            Ok(()) => {
+                model.notice = None;
+                show_landing(model, orders);
+            }
+            Err(err) => {
+                log!("failed to select device: {:?}", err);
+                model.notice = Some(err.describe("failed to switch device"));
+            }
+        },
+    }
+}
+
+///Go back to the queue, reconnecting to the session's events if the connection was
+/// dropped while on another page
+fn show_landing(model: &mut Model, orders: &mut impl Orders<Msg>) {
+    model.page = Page::Landing;
+    if let Some(session) = &model.session {
+        update_state(session.id, orders);
+        if model.events.is_none() {
+            model.events = subscribe(session.id, orders);
+        }
     }
 }
 
-fn update_state(orders: &mut impl Orders<Msg>) {
-    orders.perform_cmd(async { Msg::NewStateAvailable(request_new_state().await) });
+fn cast_vote(
+    model: &Model,
+    orders: &mut impl Orders<Msg>,
+    entry_id: i32,
+    direction: &'static str,
+) {
+    match (&model.session, &model.guest) {
+        (Some(session), Some(guest)) => {
+            let session_id = session.id;
+            let guest = guest.guest_id;
+            orders.perform_cmd(async move {
+                if let Err(err) = vote(session_id, guest, entry_id, direction).await {
+                    log!("failed to vote: {:?}", err);
+                }
+            });
+        }
+        _ => log!("can't vote without a session and guest id"),
+    }
+}
+
+fn apply_event(model: &mut Model, event: PlayerEvent) {
+    match event {
+        PlayerEvent::Snapshot(state) => {
+            model.loaded = LoadingState::Done;
+            model.currently_playing = state.current_track;
+            model.autofill = state.autofill;
+            model.queue = state.queue;
+        }
+        PlayerEvent::TrackChanged {
+            current_track,
+            autofill,
+        } => {
+            model.currently_playing = current_track;
+            model.autofill = autofill;
+        }
+        PlayerEvent::QueueChanged { queue } => {
+            model.queue = queue;
+        }
+        PlayerEvent::VotesChanged {
+            entry_id,
+            upvotes,
+            downvotes,
+        } => {
+            if let Some(entry) = model.queue.iter_mut().find(|e| e.entry_id == entry_id) {
+                entry.upvotes = upvotes;
+                entry.downvotes = downvotes;
+            }
+        }
+    }
 }
 
-const BASE_URL: &str = "http://192.168.0.22:8090";
+///Connect to the session's event stream. The browser retries dropped connections by
+/// itself, EventStreamFailed is only sent once it gives up.
+fn subscribe(
+    session_id: Uuid,
+    orders: &mut impl Orders<Msg>,
+) -> Option<EventSubscription> {
+    let url = format!("{}/session/{}/events", api_base(), session_id);
+    let source = match EventSource::new(&url) {
+        Ok(source) => source,
+        Err(err) => {
+            log!("failed to open event stream: {:?}", err);
+            return None;
+        }
+    };
 
-async fn request_new_state() -> fetch::Result<PlayerState> {
-    let request = Request::new(format!("{}/current_state", BASE_URL)).method(Method::Get);
+    let sender = orders.msg_sender();
+    let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+        let parsed = event
+            .data()
+            .as_string()
+            .map(|data| serde_json::from_str::<PlayerEvent>(&data));
+        match parsed {
+            Some(Ok(event)) => sender(Some(Msg::PlayerEventReceived(event))),
+            Some(Err(err)) => log!("failed to parse player event: {}", err),
+            None => log!("player event had no text"),
+        }
+    }) as Box<dyn FnMut(MessageEvent)>);
+    source.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+    let sender = orders.msg_sender();
+    let errored_source = source.clone();
+    let on_error = Closure::wrap(Box::new(move |_: JsValue| {
+        if errored_source.ready_state() == EventSource::CLOSED {
+            sender(Some(Msg::EventStreamFailed));
+        }
+    }) as Box<dyn FnMut(JsValue)>);
+    source.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+
+    Some(EventSubscription {
+        source: source,
+        _on_message: on_message,
+        _on_error: on_error,
+    })
+}
 
+fn update_state(session_id: Uuid, orders: &mut impl Orders<Msg>) {
+    orders.perform_cmd(async move {
+        Msg::NewStateAvailable(request_new_state(session_id).await)
+    });
+    update_history(session_id, orders);
+}
+
+fn update_history(session_id: Uuid, orders: &mut impl Orders<Msg>) {
+    orders.perform_cmd(async move {
+        Msg::HistoryAvailable(request_history(session_id).await)
+    });
+}
+
+thread_local! {
+    ///Base url of the backend's API, set once `find_api_base` has found it
+    static API_BASE: RefCell<String> = RefCell::new(String::new());
+}
+
+fn api_base() -> String {
+    API_BASE.with(|base| base.borrow().clone())
+}
+
+///Where the backend's API is: the `ddj-api-base` meta tag in index.html if it names one,
+/// otherwise what /config.json says, otherwise the page's own origin. Lets the same
+/// build be deployed anywhere.
+async fn find_api_base() -> String {
+    let api_base = match meta_api_base() {
+        Some(api_base) => api_base,
+        None => {
+            let origin = window().location().origin().unwrap_or_default();
+            match fetch_client_config().await {
+                Ok(config) => config.api_base.unwrap_or(origin),
+                Err(err) => {
+                    log!("no usable /config.json, using the page's origin: {:?}", err);
+                    origin
+                }
+            }
+        }
+    };
+    api_base.trim_end_matches('/').to_owned()
+}
+
+fn meta_api_base() -> Option<String> {
+    let selector = format!("meta[name=\"{}\"]", API_BASE_META);
+    document()
+        .query_selector(&selector)
+        .ok()
+        .flatten()
+        .and_then(|meta| meta.get_attribute("content"))
+        .filter(|content| !content.trim().is_empty())
+}
+
+async fn fetch_client_config() -> RequestResult<ClientConfig> {
+    let request = Request::new("/config.json").method(Method::Get);
+    let response = send(request).await?;
+    let payload = response.json().await?;
+    Ok(payload)
+}
+
+///Why a request to the backend failed
+#[derive(Debug)]
+enum RequestError {
+    ///The backend answered with its description of the error
+    Api(ErrorBody),
+    ///The request never got an answer, or one which couldn't be read
+    Fetch(fetch::FetchError),
+}
+
+impl RequestError {
+    ///Message to show the user, `fallback` when the backend didn't give one
+    fn describe(&self, fallback: &str) -> String {
+        match self {
+            RequestError::Api(body) => body.message.clone(),
+            RequestError::Fetch(_) => fallback.to_owned(),
+        }
+    }
+}
+
+impl From<fetch::FetchError> for RequestError {
+    fn from(err: fetch::FetchError) -> Self {
+        RequestError::Fetch(err)
+    }
+}
+
+type RequestResult<T> = Result<T, RequestError>;
+
+///Fetch, turning error responses into the ErrorBody the backend sent with them
+async fn send(request: Request<'_>) -> RequestResult<Response> {
     let response = fetch(request).await?;
+    let status = response.status();
+    if status.is_ok() {
+        return Ok(response);
+    }
+    match response.json::<ErrorBody>().await {
+        Ok(body) => Err(RequestError::Api(body)),
+        Err(_) => Err(RequestError::Fetch(fetch::FetchError::StatusError(status))),
+    }
+}
+
+async fn request_new_state(session_id: Uuid) -> RequestResult<PlayerState> {
+    let request = Request::new(format!(
+        "{}/session/{}/current_state",
+        api_base(),
+        session_id
+    ))
+    .method(Method::Get);
+
+    let response = send(request).await?;
+    let payload = response.json().await?;
+
+    Ok(payload)
+}
+
+async fn request_history(session_id: Uuid) -> RequestResult<PlayHistory> {
+    let request = Request::new(format!(
+        "{}/session/{}/history?limit={}",
+        api_base(),
+        session_id,
+        RECENTLY_PLAYED_COUNT
+    ))
+    .method(Method::Get);
+
+    let response = send(request).await?;
     let payload = response.json().await?;
 
     Ok(payload)
 }
 
-async fn search(query: &str) -> fetch::Result<Vec<Track>> {
-    let request = Request::new(format!("{}/search", BASE_URL))
+async fn search(session_id: Uuid, query: &str) -> RequestResult<Vec<Track>> {
+    let request = Request::new(format!("{}/session/{}/search", api_base(), session_id))
         .method(Method::Post)
         .json(query)?;
 
-    let response = fetch(request).await?;
+    let response = send(request).await?;
     let payload = response.json().await?;
 
     Ok(payload)
 }
 
-async fn add_track_to_queue(track: &Track) -> fetch::Result<()> {
-    let request =
-        Request::new(format!("{}/queue/{}", BASE_URL, &track.id)).method(Method::Post);
-    fetch(request).await?;
+///Fails with the reason the track was turned away, if it was
+async fn add_track_to_queue(
+    session_id: Uuid,
+    guest: Uuid,
+    track: &Track,
+) -> RequestResult<()> {
+    let request = Request::new(format!(
+        "{}/session/{}/queue/{}",
+        api_base(),
+        session_id,
+        &track.id
+    ))
+    .method(Method::Post)
+    .header(Header::custom(GUEST_HEADER, guest.to_string()));
+    send(request).await?;
+
+    Ok(())
+}
+
+async fn vote(
+    session_id: Uuid,
+    guest: Uuid,
+    entry_id: i32,
+    direction: &str,
+) -> RequestResult<()> {
+    let request = Request::new(format!(
+        "{}/session/{}/queue/{}/{}",
+        api_base(),
+        session_id,
+        entry_id,
+        direction
+    ))
+    .method(Method::Post)
+    .header(Header::custom(GUEST_HEADER, guest.to_string()));
+    send(request).await?;
+
+    Ok(())
+}
+
+fn request_devices(session_id: Uuid, orders: &mut impl Orders<Msg>) {
+    orders.perform_cmd(
+        async move { Msg::DevicesAvailable(fetch_devices(session_id).await) },
+    );
+}
+
+async fn fetch_devices(session_id: Uuid) -> RequestResult<Vec<Device>> {
+    let request = Request::new(format!("{}/session/{}/devices", api_base(), session_id))
+        .method(Method::Get);
+    let response = send(request).await?;
+    let payload = response.json().await?;
+    Ok(payload)
+}
+
+async fn select_device(session_id: Uuid, device_id: &str) -> RequestResult<()> {
+    let request = as_host(Request::new(format!(
+        "{}/session/{}/devices/{}",
+        api_base(),
+        session_id,
+        device_id
+    )))
+    .method(Method::Post);
+    send(request).await?;
 
     Ok(())
 }
 
-async fn request_login_url() -> fetch::Result<CreateSessionResponse> {
-    let request = Request::new(format!("{}/new_session/{}", BASE_URL, "test-session"))
+async fn request_guest(session_id: Uuid) -> RequestResult<GuestIdentity> {
+    let request = Request::new(format!("{}/session/{}/guest", api_base(), session_id))
         .method(Method::Post);
-    let response = fetch(request).await?;
+    let response = send(request).await?;
     let payload = response.json().await?;
     Ok(payload)
 }
 
-async fn send_code(code: &str, session_id: Uuid) {
+async fn request_login_url() -> RequestResult<CreateSessionResponse> {
+    let request = Request::new(format!("{}/new_session/{}", api_base(), "test-session"))
+        .method(Method::Post);
+    let response = send(request).await?;
+    let payload = response.json().await?;
+    Ok(payload)
+}
+
+async fn send_code(code: &str, session_id: Uuid) -> RequestResult<()> {
     let message = AuthenticateClientMessage {
         session_id: session_id,
         auth_code: code.to_owned(),
     };
-    let request = Request::new(format!(
+    let request = as_host(Request::new(format!(
         "{}/authenticate_session/{}",
-        BASE_URL,
+        api_base(),
         session_id.to_string()
-    ))
+    )))
     .method(Method::Post)
-    .json(&message)
-    .unwrap();
-    let response = fetch(request).await.unwrap();
+    .json(&message)?;
+    send(request).await?;
+
+    Ok(())
 }
 
 // ------ ------
@@ -269,7 +784,7 @@ fn view(model: &Model) -> Node<Msg> {
     match &model.page {
         Page::Landing => view_normal_mode(model),
         Page::Search(_) => view_search_mode(model),
-        Page::DeviceSelection => todo!(),
+        Page::DeviceSelection => view_device_selection(model),
         Page::Login(url) => div![
             "redirecting...",
             match url {
@@ -296,6 +811,44 @@ fn view_search_mode(model: &Model) -> Node<Msg> {
     ]
 }
 
+fn view_device_selection(model: &Model) -> Node<Msg> {
+    div![
+        C!["content"],
+        div![C!["app-title"], "Play On"],
+        model
+            .notice
+            .as_ref()
+            .map(|notice| div![C!["notice"], notice]),
+        if model.devices.is_empty() {
+            div!["no devices found, open Spotify somewhere and try again"]
+        } else {
+            div![model.devices.iter().map(|device| view_device(device))]
+        },
+        div![
+            C!["device-back"],
+            "back",
+            ev(Ev::Click, |_| Msg::LeaveDeviceSelection)
+        ]
+    ]
+}
+
+fn view_device(device: &Device) -> Node<Msg> {
+    let device_id = device.id.clone();
+    let status = if device.is_selected {
+        "selected"
+    } else if device.is_active {
+        "playing"
+    } else {
+        ""
+    };
+    div![
+        C!["device", IF!(device.is_selected => "selected-device")],
+        div![&device.name],
+        div![C!["device-status"], status],
+        ev(Ev::Click, move |_| Msg::DeviceClicked(device_id))
+    ]
+}
+
 fn view_search_results(model: &SearchModel) -> Node<Msg> {
     if let Some(err) = &model.error {
         div![format!("ERROR: {}", err)]
@@ -311,9 +864,17 @@ fn view_normal_mode(model: &Model) -> Node<Msg> {
         div![match &model.loaded {
             LoadingState::Done => {
                 div![
-                    div![view_currently_playing(&model.currently_playing)],
+                    model
+                        .notice
+                        .as_ref()
+                        .map(|notice| div![C!["notice"], notice]),
+                    div![view_currently_playing(
+                        &model.currently_playing,
+                        model.autofill
+                    )],
                     div![format!("{} Songs in Queue:", model.queue.len())],
-                    view_queue(&model.queue)
+                    view_voting_queue(&model.queue),
+                    view_recently_played(&model.recently_played)
                 ]
             }
             LoadingState::Error(msg) => {
@@ -323,6 +884,7 @@ fn view_normal_mode(model: &Model) -> Node<Msg> {
                 div!["loading....."]
             }
         }],
+        view_device_button(),
         view_plus_button()
     ]
 }
@@ -331,9 +893,60 @@ fn view_queue(queue: &Vec<Track>) -> Node<Msg> {
     div![queue.iter().map(|track| view_track(track))]
 }
 
-fn view_currently_playing(track: &Option<Track>) -> Node<Msg> {
+fn view_voting_queue(queue: &Vec<QueuedTrack>) -> Node<Msg> {
+    div![queue.iter().map(|entry| view_queue_entry(entry))]
+}
+
+fn view_queue_entry(entry: &QueuedTrack) -> Node<Msg> {
+    let entry_id = entry.entry_id;
+    div![
+        C!["queue-entry"],
+        view_track(&entry.track),
+        div![
+            C!["vote-buttons"],
+            div![
+                format!("▲ {}", entry.upvotes),
+                ev(Ev::Click, move |_| Msg::Upvote(entry_id))
+            ],
+            div![
+                format!("▼ {}", entry.downvotes),
+                ev(Ev::Click, move |_| Msg::Downvote(entry_id))
+            ]
+        ]
+    ]
+}
+
+fn view_recently_played(history: &Vec<PlayedTrack>) -> Node<Msg> {
+    if history.is_empty() {
+        return empty![];
+    }
+    div![
+        C!["recently-played"],
+        div!["Recently played:"],
+        history.iter().map(|played| {
+            let artists = played
+                .track
+                .artists
+                .iter()
+                .map(|artist| &artist.name[..])
+                .collect::<Vec<&str>>()
+                .join(", ");
+            div![
+                C!["played-track"],
+                format!("{} - {}", played.track.name, artists)
+            ]
+        })
+    ]
+}
+
+fn view_currently_playing(track: &Option<Track>, autofill: bool) -> Node<Msg> {
     if let Some(inner_track) = track {
-        div![div!["Currently playing:"], view_track(&inner_track)]
+        let heading = if autofill {
+            "Filling in until something is queued:"
+        } else {
+            "Currently playing:"
+        };
+        div![div![heading], view_track(&inner_track)]
     } else {
         div!["Nothing Playing"]
     }
@@ -344,15 +957,34 @@ fn view_track(track: &Track) -> Node<Msg> {
     let duration_mins = track.duration.as_secs() / 60;
     let duration_secs = track.duration.as_secs() % 60;
     let duration_str = format!("{}:{}", duration_mins, duration_secs);
+    let artists_str = track
+        .artists
+        .iter()
+        .map(|artist| &artist.name[..])
+        .collect::<Vec<&str>>()
+        .join(", ");
     let cloned_track = track.clone();
     div![
         C!["track"],
         img!(attrs! {At::Src => album_art}),
-        div![C!["track-info"], div![&track.name], div![duration_str]],
+        div![
+            C!["track-info"],
+            div![&track.name],
+            div![C!["track-artists"], artists_str],
+            div![duration_str]
+        ],
         ev(Ev::Click, move |_| { Msg::TrackClicked(cloned_track) })
     ]
 }
 
+fn view_device_button() -> Node<Msg> {
+    div![
+        C!["device-button"],
+        "devices",
+        ev(Ev::Click, |_| Msg::EnterDeviceSelection)
+    ]
+}
+
 fn view_plus_button() -> Node<Msg> {
     div![
         C!["plus-button"],